@@ -1,5 +1,5 @@
-extern crate prost_build;
-
 fn main() {
-    prost_build::compile_protos(&["src/message.proto"], &["backend/src", "src/"]).unwrap();
+    tonic_build::configure()
+        .compile_protos(&["src/message.proto"], &["backend/src", "src/"])
+        .unwrap();
 }
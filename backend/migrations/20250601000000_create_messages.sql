@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS messages (
+    user_id TEXT NOT NULL,
+    hostname TEXT NOT NULL,
+    title TEXT NOT NULL,
+    body TEXT NOT NULL,
+    timestamp BIGINT,
+    received_at BIGINT NOT NULL,
+    payload TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS messages_user_id_hostname_idx
+    ON messages (user_id, hostname, received_at);
@@ -0,0 +1,2 @@
+-- messages belong to organizations, the personal ones share the ids of their users
+ALTER TABLE messages RENAME COLUMN user_id TO org_id;
@@ -0,0 +1,656 @@
+use crate::api::authorization::AdminUser;
+use crate::api::password::send_password_reset;
+use crate::api::AppState;
+use crate::errors::APIError;
+use crate::model::organization::{OrgID, Role};
+use crate::model::token::{TokenID, TokenInfo};
+use crate::model::user::{User, UserID, UserRole};
+use crate::persistence::token::TokenStore;
+use crate::persistence::{
+    MessageKey, MessageQuery, PersistMessage, PersistOrganization, PersistPendingUser,
+    PersistSession, PersistUser, Persistence,
+};
+use crate::service::authentication::hash_password;
+use crate::service::token::{random_alphanumeric_string, tokens_of_org};
+use actix_web::{delete, get, patch, post, web, HttpResponse, Responder, Scope};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+/// Length of the random password replacing the one of a forced reset.
+const RESET_PASSWORD_LENGTH: u32 = 64;
+
+/// Searches and pages users ordered by email. `cursor` is the `next_cursor` of
+/// the previous [`UserPage`].
+#[derive(Debug, Default, Deserialize)]
+pub struct UserQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    /// Part of the email or user id, case-insensitive.
+    search: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateUserRequest {
+    role: Option<UserRole>,
+    suspended: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct UserSummary {
+    user_id: UserID,
+    email: String,
+    role: UserRole,
+    suspended: bool,
+}
+
+impl From<User> for UserSummary {
+    fn from(user: User) -> Self {
+        UserSummary {
+            user_id: user.user_id,
+            email: user.email,
+            role: user.role,
+            suspended: user.suspended,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct UserPage {
+    users: Vec<UserSummary>,
+    next_cursor: Option<String>,
+    total: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Membership {
+    org_id: OrgID,
+    role: Role,
+}
+
+/// Messages stored in the personal organization of a user.
+#[derive(Serialize, Debug, Default)]
+struct MessageCounts {
+    total: usize,
+    hostnames: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct UserDetails {
+    #[serde(flatten)]
+    user: UserSummary,
+    organizations: Vec<Membership>,
+    messages: MessageCounts,
+}
+
+/// Without the nonce, which would give away the confirmation link.
+#[derive(Serialize, Debug)]
+struct PendingUserResponse {
+    user_id: UserID,
+    email: String,
+}
+
+#[derive(Serialize, Debug)]
+struct AdminTokenResponse {
+    org_id: OrgID,
+    #[serde(flatten)]
+    info: TokenInfo,
+}
+
+fn internal_error(e: anyhow::Error) -> APIError {
+    error!("{e}");
+    APIError::InternalServerError
+}
+
+async fn get_user(persist: &mut Persistence, user_id: &UserID) -> Result<User, APIError> {
+    persist.get_user_by_id(user_id).await.map_err(|e| {
+        info!("{e}");
+        APIError::NotFound
+    })
+}
+
+async fn message_counts(
+    persist: &mut Persistence,
+    org_id: &OrgID,
+) -> Result<MessageCounts, APIError> {
+    let mut counts = MessageCounts::default();
+    let query = MessageQuery {
+        limit: Some(1),
+        ..MessageQuery::default()
+    };
+    for hostname in persist
+        .get_hostnames(org_id)
+        .await
+        .map_err(internal_error)?
+    {
+        let key = MessageKey {
+            org_id: org_id.clone(),
+            hostname,
+        };
+        let total = persist
+            .query_messages(&key, &query)
+            .await
+            .map_err(internal_error)?
+            .total;
+        counts.total += total;
+        counts.hostnames.insert(key.hostname, total);
+    }
+    Ok(counts)
+}
+
+/// The tokens of all organizations of the user.
+async fn tokens_of_user(
+    persist: &mut Persistence,
+    user_id: &UserID,
+) -> Result<Vec<TokenInfo>, APIError> {
+    let mut tokens = Vec::new();
+    for (org_id, _) in persist
+        .get_organizations_of_user(user_id)
+        .await
+        .map_err(internal_error)?
+    {
+        tokens.extend(
+            tokens_of_org(persist, &org_id)
+                .await
+                .map_err(internal_error)?,
+        );
+    }
+    Ok(tokens)
+}
+
+#[get("/users")]
+pub(crate) async fn get_users(
+    _admin: AdminUser,
+    query: web::Query<UserQuery>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let query = query.into_inner();
+    let offset: usize = match &query.cursor {
+        None => 0,
+        Some(cursor) => cursor
+            .parse()
+            .map_err(|_| APIError::BadRequest(format!("invalid cursor: {cursor}")))?,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let search = query.search.map(|search| search.to_lowercase());
+
+    let mut index = state
+        .persist
+        .lock()
+        .await
+        .get_user_index()
+        .await
+        .map_err(internal_error)?;
+    if let Some(search) = &search {
+        index.retain(|(email, user_id)| {
+            email.to_lowercase().contains(search) || user_id.0.contains(search)
+        });
+    }
+    index.sort();
+    let total = index.len();
+    let mut persist = state.persist.lock().await;
+    let mut users = Vec::new();
+    for (_, user_id) in index.iter().skip(offset).take(limit) {
+        match persist.get_user_by_id(user_id).await {
+            Ok(user) => users.push(UserSummary::from(user)),
+            // deleted since loading the index
+            Err(e) => info!("{e}"),
+        }
+    }
+    drop(persist);
+    let next = offset + limit;
+    Ok(web::Json(UserPage {
+        users,
+        next_cursor: (next < total).then(|| next.to_string()),
+        total,
+    }))
+}
+
+/// A user with the organizations and the messages of the personal one.
+#[get("/users/{user_id}")]
+pub(crate) async fn get_user_details(
+    _admin: AdminUser,
+    path: web::Path<UserID>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let mut persist = state.persist.lock().await;
+    let user = get_user(&mut persist, &path).await?;
+    let organizations = persist
+        .get_organizations_of_user(&user.user_id)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|(org_id, role)| Membership { org_id, role })
+        .collect();
+    let messages = message_counts(&mut persist, &OrgID::personal(&user.user_id)).await?;
+    Ok(web::Json(UserDetails {
+        user: UserSummary::from(user),
+        organizations,
+        messages,
+    }))
+}
+
+/// Changes the role or suspends a user. Suspended users are logged out and
+/// can't log in until the suspension is lifted.
+#[patch("/users/{user_id}")]
+pub(crate) async fn update_user(
+    admin: AdminUser,
+    path: web::Path<UserID>,
+    request: web::Json<UpdateUserRequest>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    if admin.user_id.as_ref() == Some(&path) {
+        return Err(APIError::BadRequest(
+            "admins can't change their own account".to_string(),
+        ));
+    }
+    let mut persist = state.persist.lock().await;
+    let mut user = get_user(&mut persist, &path).await?;
+    if let Some(role) = request.role {
+        user.role = role;
+    }
+    if let Some(suspended) = request.suspended {
+        user.suspended = suspended;
+    }
+    persist.update_user(&user).await.map_err(internal_error)?;
+    if user.suspended {
+        persist
+            .delete_sessions(&user.user_id, None)
+            .await
+            .map_err(internal_error)?;
+    }
+    info!("{admin} updated {}: {request:?}", user.user_id);
+    Ok(web::Json(UserSummary::from(user)))
+}
+
+/// Replaces the password with a random one, logs out all sessions and mails a
+/// reset link to the user.
+#[post("/users/{user_id}/password_reset")]
+pub(crate) async fn force_password_reset(
+    admin: AdminUser,
+    path: web::Path<UserID>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let mut persist = state.persist.lock().await;
+    let mut user = get_user(&mut persist, &path).await?;
+    user.password_hash = hash_password(&random_alphanumeric_string(RESET_PASSWORD_LENGTH));
+    persist.update_user(&user).await.map_err(internal_error)?;
+    persist
+        .delete_sessions(&user.user_id, None)
+        .await
+        .map_err(internal_error)?;
+    send_password_reset(&mut persist, &state.frontend_url, &user).await?;
+    info!("{admin} forced a password reset of {}", user.user_id);
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// The tokens of all organizations the user is a member of.
+#[get("/users/{user_id}/tokens")]
+pub(crate) async fn get_user_tokens(
+    _admin: AdminUser,
+    path: web::Path<UserID>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let mut persist = state.persist.lock().await;
+    let user = get_user(&mut persist, &path).await?;
+    let mut tokens: Vec<AdminTokenResponse> = tokens_of_user(&mut persist, &user.user_id)
+        .await?
+        .into_iter()
+        .map(|info| AdminTokenResponse {
+            org_id: info.org_id.clone(),
+            info,
+        })
+        .collect();
+    tokens.sort_by_key(|token| token.info.metadata.created_at);
+    Ok(web::Json(tokens))
+}
+
+/// Revokes a token of an organization the user is a member of.
+#[delete("/users/{user_id}/tokens/{token_id}")]
+pub(crate) async fn delete_user_token(
+    admin: AdminUser,
+    path: web::Path<(UserID, TokenID)>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let (user_id, token_id) = path.into_inner();
+    let mut persist = state.persist.lock().await;
+    let info = tokens_of_user(&mut persist, &user_id)
+        .await?
+        .into_iter()
+        .find(|info| info.token_id == token_id)
+        .ok_or(APIError::NotFound)?;
+    persist
+        .delete_token(&info.hash)
+        .await
+        .map_err(internal_error)?;
+    info!(
+        "{admin} revoked token {token_id} of {} on behalf of {user_id}",
+        info.org_id
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Registrations waiting for the confirmation of their email.
+#[get("/pending_users")]
+pub(crate) async fn get_pending_users(
+    _admin: AdminUser,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let mut pending: Vec<PendingUserResponse> = state
+        .persist
+        .lock()
+        .await
+        .get_users_pending()
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|user| PendingUserResponse {
+            user_id: user.user_id,
+            email: user.email,
+        })
+        .collect();
+    pending.sort_by(|a, b| a.email.cmp(&b.email));
+    Ok(web::Json(pending))
+}
+
+/// Activates a pending registration as if its confirmation link was opened.
+#[post("/pending_users/{user_id}")]
+pub(crate) async fn activate_pending_user(
+    admin: AdminUser,
+    path: web::Path<UserID>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let mut persist = state.persist.lock().await;
+    let nonce = persist
+        .get_nonce_of_user_pending(&path)
+        .await
+        .map_err(internal_error)?
+        .ok_or(APIError::NotFound)?;
+    let user = persist.get_user_pending(&nonce).await.map_err(|e| {
+        info!("{e}");
+        APIError::NotFound
+    })?;
+    let existing = persist
+        .get_user_by_email(&user.email)
+        .await
+        .map_err(internal_error)?;
+    if existing.is_some() {
+        return Err(APIError::BadRequest("email already in use".to_string()));
+    }
+    persist
+        .confirm_user_pending(&nonce)
+        .await
+        .map_err(internal_error)?;
+    info!("{admin} activated {}", user.user_id);
+    Ok(HttpResponse::Created().json(UserSummary::from(user)))
+}
+
+pub(crate) fn get_admin_services() -> Scope {
+    web::scope("/admin")
+        .service(get_users)
+        .service(get_user_details)
+        .service(update_user)
+        .service(force_password_reset)
+        .service(get_user_tokens)
+        .service(delete_user_token)
+        .service(get_pending_users)
+        .service(activate_pending_user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::authentication::login;
+    use crate::api::login_cookie;
+    use crate::model::message::MessageBackend;
+    use crate::model::token::TokenMetadata;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::Key;
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    const ADMIN_TOKEN: &str = "admin-token";
+
+    fn app_state() -> web::Data<AppState> {
+        web::Data::new(AppState {
+            admin_token: Some(ADMIN_TOKEN.to_string()),
+            ..AppState::in_memory()
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_admin_authorization() {
+        let state = app_state();
+        let mut admin = User::new("admin@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&admin).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(get_admin_services()),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/admin/users").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let request = test::TestRequest::get()
+            .uri("/admin/users")
+            .insert_header((AUTHORIZATION, "Bearer wrong"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let cookie = login_cookie(&app, "admin@x.x", "asdfasdfasdf").await;
+        let request = test::TestRequest::get()
+            .uri("/admin/users")
+            .cookie(cookie.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        admin.role = UserRole::Admin;
+        state.persist.lock().await.add_user(&admin).await.unwrap();
+        let request = test::TestRequest::get()
+            .uri("/admin/users")
+            .cookie(cookie.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = test::TestRequest::patch()
+            .uri(&format!("/admin/users/{}", admin.user_id))
+            .cookie(cookie)
+            .set_json(json!({"role": "user"}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_manage_users() {
+        let state = app_state();
+        let users: Vec<User> = (0..3)
+            .map(|i| User::new(format!("user{i}@x.x"), "asdfasdfasdf".to_string()))
+            .collect();
+        let user_id = &users[1].user_id;
+        let org_id = OrgID::personal(user_id);
+        {
+            let mut persist = state.persist.lock().await;
+            for user in &users {
+                persist.add_user(user).await.unwrap();
+            }
+            for hostname in ["a", "a", "b"] {
+                let key = MessageKey {
+                    org_id: org_id.clone(),
+                    hostname: hostname.to_string(),
+                };
+                persist
+                    .add_message(&key, &MessageBackend::default())
+                    .await
+                    .unwrap();
+            }
+            persist
+                .create_token(&org_id, &TokenMetadata::default())
+                .await
+                .unwrap();
+        }
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(get_admin_services()),
+        )
+        .await;
+        let request = |request: test::TestRequest| {
+            request
+                .insert_header((AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}")))
+                .to_request()
+        };
+
+        let page: Value = test::call_and_read_body_json(
+            &app,
+            request(test::TestRequest::get().uri("/admin/users?limit=2")),
+        )
+        .await;
+        assert_eq!(page["total"], 3);
+        assert_eq!(page["users"][0]["email"], "user0@x.x");
+        assert_eq!(page["next_cursor"], "2");
+        let page: Value = test::call_and_read_body_json(
+            &app,
+            request(test::TestRequest::get().uri("/admin/users?search=USER2")),
+        )
+        .await;
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["users"][0]["email"], "user2@x.x");
+
+        let details: Value = test::call_and_read_body_json(
+            &app,
+            request(test::TestRequest::get().uri(&format!("/admin/users/{user_id}"))),
+        )
+        .await;
+        assert_eq!(details["messages"]["total"], 3);
+        assert_eq!(details["messages"]["hostnames"]["a"], 2);
+        assert_eq!(details["organizations"][0]["role"], "owner");
+
+        let tokens: Vec<Value> = test::call_and_read_body_json(
+            &app,
+            request(test::TestRequest::get().uri(&format!("/admin/users/{user_id}/tokens"))),
+        )
+        .await;
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0]["org_id"], org_id.to_string());
+        let token_id = tokens[0]["token_id"].as_str().unwrap();
+        let uri = format!("/admin/users/{}/tokens/{token_id}", users[0].user_id);
+        let response =
+            test::call_service(&app, request(test::TestRequest::delete().uri(&uri))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let uri = format!("/admin/users/{user_id}/tokens/{token_id}");
+        let response =
+            test::call_service(&app, request(test::TestRequest::delete().uri(&uri))).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let mut persist = state.persist.lock().await;
+        assert!(persist.get_tokens_of_org(&org_id).await.unwrap().is_empty());
+        drop(persist);
+
+        let cookie = login_cookie(&app, "user1@x.x", "asdfasdfasdf").await;
+        let response = test::call_service(
+            &app,
+            request(
+                test::TestRequest::patch()
+                    .uri(&format!("/admin/users/{user_id}"))
+                    .set_json(json!({"suspended": true})),
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut persist = state.persist.lock().await;
+        assert!(persist.get_sessions(user_id).await.unwrap().is_empty());
+        drop(persist);
+        let login_request = test::TestRequest::post()
+            .uri("/login")
+            .cookie(cookie)
+            .set_json(json!({"email": "user1@x.x", "password": "asdfasdfasdf"}))
+            .to_request();
+        let response = test::call_service(&app, login_request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let uri = format!("/admin/users/{}/password_reset", users[2].user_id);
+        let response = test::call_service(&app, request(test::TestRequest::post().uri(&uri))).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let login_request = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({"email": "user2@x.x", "password": "asdfasdfasdf"}))
+            .to_request();
+        let response = test::call_service(&app, login_request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_activate_pending_user() {
+        let state = app_state();
+        let user = User::new("pending@x.x".to_string(), "asdfasdfasdf".to_string());
+        let nonce = "nonce".to_string();
+        state
+            .persist
+            .lock()
+            .await
+            .add_user_pending(&user, &nonce)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(get_admin_services()),
+        )
+        .await;
+        let request = |request: test::TestRequest| {
+            request
+                .insert_header((AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}")))
+                .to_request()
+        };
+
+        let pending: Vec<Value> = test::call_and_read_body_json(
+            &app,
+            request(test::TestRequest::get().uri("/admin/pending_users")),
+        )
+        .await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0]["user_id"], user.user_id.to_string());
+        assert!(!pending[0].to_string().contains("nonce"));
+        let activate = || {
+            request(
+                test::TestRequest::post().uri(&format!("/admin/pending_users/{}", user.user_id)),
+            )
+        };
+        let response = test::call_service(&app, activate()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = test::call_service(&app, activate()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        login_cookie(&app, "pending@x.x", "asdfasdfasdf").await;
+    }
+}
@@ -1,19 +1,62 @@
+use crate::api::client_ip;
+use crate::api::rate_limit::{
+    check_blocked, check_rate_limit, record_failure, reset_failures, Backoff,
+};
+use crate::api::two_factor::verify_second_factor;
 use crate::AppState;
 use actix_identity::Identity;
 
 use crate::service::authentication::valid_hash;
 use validator::Validate;
 
+use actix_session::{Session, SessionExt};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{ContentType, USER_AGENT};
 use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
 use actix_web::web::Data;
-use actix_web::{error, get, post, web, HttpMessage, Responder};
+use actix_web::{error, get, post, web, HttpMessage, HttpResponse, Responder};
 
 use actix_web::web::Redirect;
 
 use crate::errors::APIError;
-use log::{debug, info};
+use crate::model::user::{SessionID, SessionInfo, User, UserID};
+use crate::persistence::{PersistSession, PersistTwoFactor, PersistUser, Persistence};
+use crate::service::email::{generate_account_locked_mail, send_mail};
+use crate::service::token::random_alphanumeric_string;
+use chrono::{DateTime, Duration, Utc};
+use lettre::message::Mailbox;
+use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 
+const SESSION_ID_KEY: &str = "snitch.session_id";
+const SESSION_ID_LENGTH: u32 = 32;
+const PENDING_LOGIN_KEY: &str = "snitch.pending_login";
+const PENDING_LOGIN_TTL: i64 = 5 * 60;
+const MAX_TWO_FACTOR_ATTEMPTS: u64 = 5;
+
+/// Failed logins per account, counted by email so that unknown emails behave the same.
+const ACCOUNT_BACKOFF: Backoff = Backoff {
+    free_failures: 3,
+    lockout_failures: 10,
+    lockout_seconds: 30 * 60,
+    window_seconds: 24 * 60 * 60,
+};
+
+/// Failed logins per client IP, across accounts.
+const IP_BACKOFF: Backoff = Backoff {
+    free_failures: 10,
+    lockout_failures: 50,
+    lockout_seconds: 60 * 60,
+    window_seconds: 24 * 60 * 60,
+};
+
+/// The id under which the session of a logged-in user is stored, see [`PersistSession`].
+pub(crate) fn session_id(session: &Session) -> Option<SessionID> {
+    session.get(SESSION_ID_KEY).ok().flatten()
+}
+
 #[derive(Deserialize, Debug, Validate)]
 pub struct LoginRequest {
     #[validate(email)]
@@ -23,6 +66,23 @@ pub struct LoginRequest {
     password: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct TwoFactorLoginRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct TwoFactorRequiredResponse {
+    two_factor_required: bool,
+}
+
+/// A login with the correct password that still needs the second factor.
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    user_id: UserID,
+    expires_at: DateTime<Utc>,
+}
+
 #[derive(Serialize)]
 pub struct LoginResponse {
     access_token: String,
@@ -43,14 +103,181 @@ pub async fn login(
     let mut users = state.persist.lock().await;
     let email = &login_request.email;
     debug!("login request for {}", email);
-    if let Some(user) = users.get_user_by_email(email).await {
-        if valid_hash(&user.password_hash, &login_request.password) {
-            Identity::login(&req.extensions(), user.user_id.to_string()).unwrap();
-            return Ok(user.email);
+    let ip_key = format!("login_ip:{}", client_ip(&req));
+    let account_key = format!("login_account:{}", email.to_lowercase());
+    check_blocked(&mut users, &ip_key).await?;
+    check_blocked(&mut users, &account_key).await?;
+
+    let user = users.get_user_by_email(email).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    let user = match user {
+        Some(user) if valid_hash(&user.password_hash, &login_request.password) => user,
+        user => {
+            record_failure(&mut users, &ip_key, &IP_BACKOFF).await?;
+            if record_failure(&mut users, &account_key, &ACCOUNT_BACKOFF).await? {
+                info!("locked logins of {email}");
+                if let Some(user) = user {
+                    notify_lockout(&user);
+                }
+            }
+            return Err(APIError::Unauthorized);
         }
+    };
+    reset_failures(&mut users, &account_key).await?;
+    if user.suspended {
+        info!("login of suspended {}", user.user_id);
+        return Err(APIError::Forbidden("account suspended".to_string()));
+    }
+
+    let two_factor = users.get_two_factor(&user.user_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    if two_factor.is_some_and(|two_factor| two_factor.enabled) {
+        let pending = PendingLogin {
+            user_id: user.user_id.clone(),
+            expires_at: Utc::now() + Duration::seconds(PENDING_LOGIN_TTL),
+        };
+        req.get_session()
+            .insert(PENDING_LOGIN_KEY, pending)
+            .map_err(|e| {
+                error!("{e}");
+                APIError::InternalServerError
+            })?;
+        info!("login of {} waits for the second factor", user.user_id);
+        return Ok(HttpResponse::Accepted().json(TwoFactorRequiredResponse {
+            two_factor_required: true,
+        }));
     }
+    start_session(&req, &mut users, &user.user_id).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(user.email))
+}
 
-    Err(APIError::Unauthorized)
+/// Tells the owner that their account is locked, in the background so that the
+/// response time doesn't reveal whether the account exists.
+fn notify_lockout(user: &User) {
+    let receiver: Mailbox = match user.email.parse() {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            error!("{e}");
+            return;
+        }
+    };
+    let mail = generate_account_locked_mail(ACCOUNT_BACKOFF.lockout_seconds / 60);
+    tokio::task::spawn(async move {
+        if let Err(e) = send_mail(mail, receiver).await {
+            error!("failed sending lockout mail: {e}");
+        }
+    });
+}
+
+/// Completes a login that is waiting for the second factor, with a TOTP code or
+/// one of the recovery codes.
+#[post("/login/2fa")]
+pub async fn login_two_factor(
+    req: actix_web::HttpRequest,
+    request: web::Json<TwoFactorLoginRequest>,
+    state: Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let session = req.get_session();
+    let pending: PendingLogin = session
+        .get(PENDING_LOGIN_KEY)
+        .ok()
+        .flatten()
+        .filter(|pending: &PendingLogin| pending.expires_at > Utc::now())
+        .ok_or(APIError::Unauthorized)?;
+    let user_id = pending.user_id;
+
+    let mut users = state.persist.lock().await;
+    check_rate_limit(
+        &mut users,
+        &format!("login_2fa:{user_id}"),
+        MAX_TWO_FACTOR_ATTEMPTS,
+        PENDING_LOGIN_TTL,
+    )
+    .await?;
+    if !verify_second_factor(&mut users, &user_id, &request.code).await? {
+        return Err(APIError::Unauthorized);
+    }
+    session.remove(PENDING_LOGIN_KEY);
+    start_session(&req, &mut users, &user_id).await?;
+    let user = users.get_user_by_id(&user_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(user.email))
+}
+
+/// Logs in the user and registers the session, see [`PersistSession`].
+async fn start_session(
+    req: &actix_web::HttpRequest,
+    users: &mut Persistence,
+    user_id: &UserID,
+) -> Result<(), APIError> {
+    Identity::login(&req.extensions(), user_id.to_string()).map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    let session = SessionInfo {
+        session_id: random_alphanumeric_string(SESSION_ID_LENGTH),
+        created_at: Utc::now(),
+        ip: Some(client_ip(req)),
+        user_agent: req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(str::to_string),
+    };
+    users.add_session(user_id, &session).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    req.get_session()
+        .insert(SESSION_ID_KEY, session.session_id)
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })
+}
+
+/// Logs out sessions that were deleted, e.g. by a password change on another
+/// device. Runs inside the session middleware.
+pub(crate) async fn validate_session(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if let (Ok(identity), Some(state)) = (
+        req.extract::<Identity>().await,
+        req.app_data::<Data<AppState>>().cloned(),
+    ) {
+        if let Ok(user_id) = identity.id() {
+            let user_id: UserID = user_id.into();
+            let valid = match session_id(&req.get_session()) {
+                Some(session_id) => state
+                    .persist
+                    .lock()
+                    .await
+                    .has_session(&user_id, &session_id)
+                    .await
+                    .map_err(|e| {
+                        error!("{e}");
+                        error::ErrorInternalServerError("Internal Server Error")
+                    })?,
+                None => false,
+            };
+            if !valid {
+                info!("logging out deleted session of {user_id}");
+                identity.logout();
+            }
+        }
+    }
+    next.call(req).await
 }
 
 #[get("/")]
@@ -65,8 +292,128 @@ pub async fn index(identity: Option<Identity>) -> actix_web::Result<impl Respond
 }
 
 #[post("/logout")]
-pub async fn logout(id: Identity) -> impl Responder {
+pub async fn logout(id: Identity, session: Session, state: Data<AppState>) -> impl Responder {
     info!("logging out {:?}", id.id());
+    if let (Ok(user_id), Some(session_id)) = (id.id(), session_id(&session)) {
+        if let Err(e) = state
+            .persist
+            .lock()
+            .await
+            .delete_session(&user_id.into(), &session_id)
+            .await
+        {
+            error!("{e}");
+        }
+    }
     id.logout();
     Redirect::to("/").using_status_code(StatusCode::FOUND)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::users::get_user_by_id;
+    use crate::persistence::PersistRateLimit;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::Key;
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_login_in_memory() {
+        let state = Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&user).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(get_user_by_id),
+        )
+        .await;
+
+        let request = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({"email": "x.x@x.x", "password": "wrong-password"}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({"email": "x.x@x.x", "password": "asdfasdfasdf"}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert!(response.status().is_success());
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        let cookie = response
+            .response()
+            .cookies()
+            .next()
+            .expect("no session cookie")
+            .into_owned();
+
+        let request = test::TestRequest::get()
+            .uri("/user")
+            .cookie(cookie)
+            .to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["email"], "x.x@x.x");
+    }
+
+    #[actix_web::test]
+    async fn test_login_backoff() {
+        let state = Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&user).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login),
+        )
+        .await;
+        let login_with = |password: &str| {
+            test::TestRequest::post()
+                .uri("/login")
+                .set_json(json!({"email": "x.x@x.x", "password": password}))
+                .to_request()
+        };
+
+        for _ in 0..=ACCOUNT_BACKOFF.free_failures {
+            let response = test::call_service(&app, login_with("wrong-password")).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        // even the correct password is rejected during the backoff
+        let response = test::call_service(&app, login_with("asdfasdfasdf")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("retry-after"));
+
+        let account_key = "login_account:x.x@x.x";
+        state
+            .persist
+            .lock()
+            .await
+            .reset_attempts(account_key)
+            .await
+            .unwrap();
+        let response = test::call_service(&app, login_with("asdfasdfasdf")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::api::organizations::ORG_HEADER;
+use crate::api::AppState;
+use crate::errors::APIError;
+use crate::model::organization::{OrgID, Role};
+use crate::model::user::{UserID, UserRole};
+use crate::persistence::{PersistOrganization, PersistUser, Persistence};
+use crate::service::authentication::constant_time_eq;
+use actix_identity::Identity;
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use log::{error, info};
+use std::fmt::{Display, Formatter};
+
+/// The logged-in user of a request. Handlers take it instead of [`Identity`],
+/// requests without a valid session are rejected with 401.
+#[derive(Debug, Clone)]
+pub(crate) struct AuthenticatedUser {
+    pub(crate) user_id: UserID,
+    pub(crate) role: UserRole,
+}
+
+impl AuthenticatedUser {
+    /// Fails for read-only users.
+    pub(crate) fn require_write(&self) -> Result<(), APIError> {
+        if self.role == UserRole::ReadOnly {
+            info!("{} is read-only", self.user_id);
+            return Err(APIError::Forbidden("read-only user".to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn app_state(request: &HttpRequest) -> Result<web::Data<AppState>, APIError> {
+    request
+        .app_data::<web::Data<AppState>>()
+        .cloned()
+        .ok_or_else(|| {
+            error!("app state missing");
+            APIError::InternalServerError
+        })
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = APIError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let request = request.clone();
+        Box::pin(async move {
+            let identity = Identity::extract(&request)
+                .await
+                .map_err(|_| APIError::Unauthorized)?;
+            let user_id: UserID = identity.id().map_err(|_| APIError::Unauthorized)?.into();
+            let state = app_state(&request)?;
+            let user = state
+                .persist
+                .lock()
+                .await
+                .get_user_by_id(&user_id)
+                .await
+                .map_err(|e| {
+                    info!("no user of session: {e}");
+                    APIError::Unauthorized
+                })?;
+            if user.suspended {
+                info!("session of suspended {user_id}");
+                return Err(APIError::Forbidden("account suspended".to_string()));
+            }
+            Ok(AuthenticatedUser {
+                user_id,
+                role: user.role,
+            })
+        })
+    }
+}
+
+/// A logged-in user with the [`UserRole::Admin`] role, or a request with the
+/// admin token of the [`AppState`] as bearer token. Other users are rejected
+/// with 403, a wrong admin token with 401.
+#[derive(Debug, Clone)]
+pub(crate) struct AdminUser {
+    /// Absent for the admin token.
+    pub(crate) user_id: Option<UserID>,
+}
+
+impl Display for AdminUser {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.user_id {
+            Some(user_id) => write!(f, "admin {user_id}"),
+            None => write!(f, "admin token"),
+        }
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = APIError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let user = AuthenticatedUser::from_request(request, payload);
+        let request = request.clone();
+        Box::pin(async move {
+            if let Some(token) = token {
+                return match &app_state(&request)?.admin_token {
+                    Some(admin_token)
+                        if constant_time_eq(admin_token.as_bytes(), token.as_bytes()) =>
+                    {
+                        Ok(AdminUser { user_id: None })
+                    }
+                    _ => {
+                        info!("wrong admin token");
+                        Err(APIError::Unauthorized)
+                    }
+                };
+            }
+            let user = user.await?;
+            if user.role != UserRole::Admin {
+                info!("{} is not an admin", user.user_id);
+                return Err(APIError::Forbidden("requires the admin role".to_string()));
+            }
+            Ok(AdminUser {
+                user_id: Some(user.user_id),
+            })
+        })
+    }
+}
+
+/// A logged-in user with the role in an organization. Extracted for the
+/// organization selected with the [`ORG_HEADER`], the personal one if absent.
+/// Users that aren't members are rejected with 403.
+#[derive(Debug, Clone)]
+pub(crate) struct OrgMember {
+    pub(crate) user_id: UserID,
+    pub(crate) org_id: OrgID,
+    pub(crate) role: Role,
+}
+
+impl OrgMember {
+    /// The membership of the user in the organization. Read-only users are
+    /// viewers regardless of their role.
+    pub(crate) async fn of(
+        persist: &mut Persistence,
+        org_id: &OrgID,
+        user: &AuthenticatedUser,
+    ) -> Result<Self, APIError> {
+        let user_id = &user.user_id;
+        let role = persist
+            .get_role(org_id, user_id)
+            .await
+            .map_err(|e| {
+                error!("{e}");
+                APIError::InternalServerError
+            })?
+            .ok_or_else(|| {
+                info!("{user_id} is not a member of {org_id}");
+                APIError::Forbidden("not a member".to_string())
+            })?;
+        let role = match user.role {
+            UserRole::ReadOnly => role.min(Role::Viewer),
+            UserRole::User | UserRole::Admin => role,
+        };
+        Ok(OrgMember {
+            user_id: user_id.clone(),
+            org_id: org_id.clone(),
+            role,
+        })
+    }
+
+    /// Fails unless the member has at least `role`.
+    pub(crate) fn require(&self, role: Role) -> Result<&Self, APIError> {
+        if self.role < role {
+            info!(
+                "{} is {} of {}, {role} required",
+                self.user_id, self.role, self.org_id
+            );
+            return Err(APIError::Forbidden(format!("requires the {role} role")));
+        }
+        Ok(self)
+    }
+}
+
+impl FromRequest for OrgMember {
+    type Error = APIError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = AuthenticatedUser::from_request(request, payload);
+        let request = request.clone();
+        Box::pin(async move {
+            let user = user.await?;
+            let org_id = match request.headers().get(ORG_HEADER) {
+                Some(header) => header
+                    .to_str()
+                    .map(|org_id| OrgID(org_id.to_string()))
+                    .map_err(|_| APIError::BadRequest(format!("invalid {ORG_HEADER} header")))?,
+                None => OrgID::personal(&user.user_id),
+            };
+            let state = app_state(&request)?;
+            let mut persist = state.persist.lock().await;
+            OrgMember::of(&mut persist, &org_id, &user).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::authentication::login;
+    use crate::api::login_cookie;
+    use crate::model::user::User;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::storage::CookieSessionStore;
+    use actix_session::SessionMiddleware;
+    use actix_web::cookie::Key;
+    use actix_web::http::StatusCode;
+    use actix_web::{get, test, App, HttpResponse, Responder};
+
+    #[get("/authenticated")]
+    async fn authenticated(user: AuthenticatedUser) -> impl Responder {
+        HttpResponse::Ok().body(user.role.to_string())
+    }
+
+    #[get("/admin")]
+    async fn admin(_admin: AdminUser) -> impl Responder {
+        HttpResponse::Ok().finish()
+    }
+
+    #[get("/member")]
+    async fn member(member: OrgMember) -> Result<impl Responder, APIError> {
+        member.require(Role::Admin)?;
+        Ok(HttpResponse::Ok().body(member.role.to_string()))
+    }
+
+    #[actix_web::test]
+    async fn test_extractors() {
+        let state = web::Data::new(AppState::in_memory());
+        let mut user = User::new("a@b.c".to_string(), "asdfasdfasdf".to_string());
+        let mut reader = User::new("d@e.f".to_string(), "asdfasdfasdf".to_string());
+        reader.role = UserRole::ReadOnly;
+        {
+            let mut persist = state.persist.lock().await;
+            persist.add_user(&user).await.unwrap();
+            persist.add_user(&reader).await.unwrap();
+        }
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(authenticated)
+                .service(admin)
+                .service(member),
+        )
+        .await;
+
+        for uri in ["/authenticated", "/admin", "/member"] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{uri}");
+        }
+
+        let cookie = login_cookie(&app, "a@b.c", "asdfasdfasdf").await;
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .cookie(cookie.clone())
+                .to_request()
+        };
+        let response = test::call_service(&app, get("/authenticated")).await;
+        assert_eq!(test::read_body(response).await, "user");
+        let response = test::call_service(&app, get("/admin")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&app, get("/member")).await;
+        assert_eq!(test::read_body(response).await, "owner");
+        let request = test::TestRequest::get()
+            .uri("/member")
+            .cookie(cookie.clone())
+            .insert_header((ORG_HEADER, reader.user_id.to_string()))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        user.role = UserRole::Admin;
+        state.persist.lock().await.add_user(&user).await.unwrap();
+        let response = test::call_service(&app, get("/admin")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let cookie = login_cookie(&app, "d@e.f", "asdfasdfasdf").await;
+        let request = test::TestRequest::get()
+            .uri("/member")
+            .cookie(cookie)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        state
+            .persist
+            .lock()
+            .await
+            .delete_user(&user.user_id)
+            .await
+            .unwrap();
+        let response = test::call_service(&app, get("/authenticated")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::api::messages::MAX_BATCH_SIZE;
+use crate::api::AppState;
+use crate::model::message::proto::ingestion_server::{Ingestion, IngestionServer};
+use crate::model::message::proto::{SendMessageResponse, SendMessagesResponse};
+use crate::model::message::{MessageBackend, MessageToken, ProtoMessageBackend};
+use crate::model::organization::OrgID;
+use crate::model::token::{TokenInfo, TokenScope};
+use crate::service::ingestion::{self, IngestMessages};
+use crate::service::token::{authorize_token, check_hostname, record_token_hosts, TokenError};
+use crate::TokenState;
+use actix_web::web::Data;
+use log::{error, info};
+use std::net::SocketAddr;
+use tonic::metadata::MetadataMap;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+/// gRPC counterpart of `POST /messages` and `POST /messages/batch`.
+pub(crate) struct IngestionService {
+    state: Data<AppState>,
+    token_state: Data<TokenState>,
+    ingestion: Data<ingestion::Ingestion>,
+}
+
+impl IngestionService {
+    pub(crate) fn new(
+        state: Data<AppState>,
+        token_state: Data<TokenState>,
+        ingestion: Data<ingestion::Ingestion>,
+    ) -> Self {
+        Self {
+            state,
+            token_state,
+            ingestion,
+        }
+    }
+
+    /// Shares the limit of the HTTP routes, counting messages.
+    fn rate_limit(&self, org_id: &OrgID, count: usize) -> Result<(), Status> {
+        self.state
+            .messages_rate_limiter
+            .check(&org_id.to_string(), count as u64)
+            .map_err(|_| Status::resource_exhausted("too many messages"))
+    }
+
+    /// Waits for the acknowledgement, gRPC clients have no other way to learn
+    /// about lost messages.
+    async fn ingest(&self, org_id: OrgID, messages: Vec<MessageBackend>) -> Result<(), Status> {
+        self.ingestion
+            .ingest(org_id, messages, true)
+            .await
+            .map_err(|e| {
+                error!("{e}");
+                Status::unavailable(e.to_string())
+            })
+    }
+
+    async fn authenticate(&self, token: &MessageToken) -> Result<TokenInfo, Status> {
+        authorize_token(
+            &mut *self.token_state.token.lock().await,
+            token,
+            TokenScope::Ingest,
+        )
+        .await
+        .map_err(token_status)
+    }
+
+    async fn record_hosts(&self, token: &TokenInfo, messages: &[MessageBackend]) {
+        record_token_hosts(
+            &mut *self.token_state.token.lock().await,
+            token,
+            messages.iter().map(|message| message.hostname.as_str()),
+        )
+        .await;
+    }
+}
+
+fn token_status(e: TokenError) -> Status {
+    match e {
+        TokenError::Unknown | TokenError::Expired => {
+            info!("{e}");
+            Status::unauthenticated(e.to_string())
+        }
+        TokenError::MissingScope(_) | TokenError::Hostname(_) => {
+            info!("{e}");
+            Status::permission_denied(e.to_string())
+        }
+        TokenError::Persistence(e) => {
+            error!("{e}");
+            Status::internal("Internal Server Error")
+        }
+    }
+}
+
+/// Reads the token before any await, streaming requests are not `Sync`.
+fn bearer_token(metadata: &MetadataMap) -> Result<MessageToken, Status> {
+    metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+        .ok_or_else(|| Status::unauthenticated("missing bearer token"))
+}
+
+fn validate(message: ProtoMessageBackend) -> Result<MessageBackend, Status> {
+    MessageBackend::from(message)
+        .validated()
+        .map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+#[tonic::async_trait]
+impl Ingestion for IngestionService {
+    async fn send_message(
+        &self,
+        request: Request<ProtoMessageBackend>,
+    ) -> Result<Response<SendMessageResponse>, Status> {
+        let token = self
+            .authenticate(&bearer_token(request.metadata())?)
+            .await?;
+        let message = validate(request.into_inner())?;
+        check_hostname(&token, &message.hostname).map_err(token_status)?;
+        self.rate_limit(&token.org_id, 1)?;
+        let messages = vec![message];
+        self.record_hosts(&token, &messages).await;
+        self.ingest(token.org_id, messages).await?;
+        Ok(Response::new(SendMessageResponse {}))
+    }
+
+    /// Ingests the stream in batches of [`MAX_BATCH_SIZE`]. An invalid message,
+    /// one for a host the token isn't allowed for or a batch over the rate limit
+    /// aborts the stream; batches ingested before stay accepted.
+    async fn send_messages(
+        &self,
+        request: Request<Streaming<ProtoMessageBackend>>,
+    ) -> Result<Response<SendMessagesResponse>, Status> {
+        let token = self
+            .authenticate(&bearer_token(request.metadata())?)
+            .await?;
+        let mut stream = request.into_inner();
+        let mut batch = Vec::new();
+        let mut accepted = 0;
+        while let Some(message) = stream.message().await? {
+            let message = validate(message)?;
+            check_hostname(&token, &message.hostname).map_err(token_status)?;
+            batch.push(message);
+            if batch.len() == MAX_BATCH_SIZE {
+                self.rate_limit(&token.org_id, batch.len())?;
+                accepted += batch.len();
+                self.record_hosts(&token, &batch).await;
+                self.ingest(token.org_id.clone(), std::mem::take(&mut batch))
+                    .await?;
+            }
+        }
+        if !batch.is_empty() {
+            self.rate_limit(&token.org_id, batch.len())?;
+            accepted += batch.len();
+            self.record_hosts(&token, &batch).await;
+            self.ingest(token.org_id, batch).await?;
+        }
+        Ok(Response::new(SendMessagesResponse {
+            accepted: accepted as u32,
+        }))
+    }
+}
+
+pub(crate) async fn serve(
+    address: SocketAddr,
+    service: IngestionService,
+) -> Result<(), tonic::transport::Error> {
+    info!("gRPC ingestion listening on {address}");
+    Server::builder()
+        .add_service(IngestionServer::new(service))
+        .serve(address)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::rate_limit::MESSAGES_RATE_LIMIT;
+    use crate::model::user::User;
+    use crate::persistence::token::TokenStore;
+    use crate::persistence::{MessageKey, PersistMessage};
+    use crate::service::ingestion::tests::notification_addr;
+    use crate::service::ingestion::DirectIngestion;
+    use std::time::Duration;
+    use tonic::Code;
+
+    fn request(token: Option<&str>, hostname: &str) -> Request<ProtoMessageBackend> {
+        let mut request = Request::new(ProtoMessageBackend {
+            hostname: hostname.to_string(),
+            title: "title".to_string(),
+            ..Default::default()
+        });
+        if let Some(token) = token {
+            request
+                .metadata_mut()
+                .insert("authorization", format!("Bearer {token}").parse().unwrap());
+        }
+        request
+    }
+
+    #[actix_web::test]
+    async fn test_send_message() {
+        let state = Data::new(AppState::in_memory());
+        let persistence = state.persist.lock().await.clone();
+        let token_state = Data::new(TokenState::new(persistence));
+        let user = User::example();
+        let token = token_state
+            .token
+            .lock()
+            .await
+            .create_token_for_org(&OrgID::personal(&user.user_id))
+            .await
+            .unwrap();
+        let ingestion = Data::new(ingestion::Ingestion::Direct(DirectIngestion::new(
+            state.clone(),
+            notification_addr(),
+        )));
+        let service = IngestionService::new(state.clone(), token_state, ingestion);
+        let mut events = state.message_events.subscribe();
+
+        let status = service
+            .send_message(request(None, "host"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        let status = service
+            .send_message(request(Some("unknown"), "host"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        let status = service
+            .send_message(request(Some(&token), ""))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(service
+            .send_message(request(Some(&token), "host"))
+            .await
+            .is_ok());
+
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.org_id, OrgID::personal(&user.user_id));
+        let key = MessageKey {
+            org_id: OrgID::personal(&user.user_id),
+            hostname: "host".to_string(),
+        };
+        let stored = state
+            .persist
+            .lock()
+            .await
+            .find_messages(&key)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert!(stored[0].timestamp.is_some());
+
+        let org_id = OrgID::personal(&user.user_id).to_string();
+        let _ = state
+            .messages_rate_limiter
+            .check(&org_id, MESSAGES_RATE_LIMIT.max_requests);
+        let status = service
+            .send_message(request(Some(&token), "host"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+}
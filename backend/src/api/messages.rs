@@ -1,18 +1,23 @@
 use crate::api::AppState;
-use crate::model::message::{MessageBackend, MessageToken, ProtoMessageBackend};
-use crate::persistence::{MessageKey, PersistMessage};
-use actix::Addr;
-use actix_identity::Identity;
-use actix_web::{get, post, web, Responder};
+use crate::model::message::{MessageBackend, MessageToken};
+use crate::persistence::{MessageKey, MessagePage, MessageQuery, PersistMessage};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::web::Bytes;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use futures_util::StreamExt;
+use tokio_stream::wrappers::BroadcastStream;
 
 use crate::errors::APIError;
 
-use crate::model::user::UserID;
+use crate::api::authorization::OrgMember;
+use crate::api::token::token_error;
+use crate::model::organization::OrgID;
+use crate::model::token::{TokenInfo, TokenScope};
+use crate::service::token::{authorize_token, check_hostname, record_token_hosts};
 use crate::TokenState;
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 
-use crate::service::kafka::{KafkaActor, TryNotify as KafkaNotify};
-use crate::service::notification_dispatcher::{NotificationActor, TryNotify};
+use crate::service::ingestion::{IngestError, IngestMessages, Ingestion, RETRY_AFTER};
 use log::{error, info};
 use serde::Deserialize;
 use serde::Serialize;
@@ -27,60 +32,531 @@ struct MessageResponse {
     messages: Vec<MessageBackend>,
 }
 
+pub(crate) const MAX_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BatchItemResult {
+    index: usize,
+    accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BatchResponse {
+    accepted: usize,
+    rejected: usize,
+    results: Vec<BatchItemResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IngestOptions {
+    /// Respond only after the messages are stored or acknowledged by Kafka.
+    #[serde(default)]
+    wait_for_ack: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamRequest {
+    hostname: Option<String>,
+}
+
+/// A full ingestion queue or a failed delivery is reported as 503, the client
+/// should retry with the same idempotency key.
+fn ingest_error(e: IngestError) -> APIError {
+    error!("{e}");
+    APIError::ServiceUnavailable(RETRY_AFTER)
+}
+
 #[post("/messages")]
 pub(crate) async fn add_message(
     auth: BearerAuth,
     message: web::Json<MessageBackend>,
+    options: web::Query<IngestOptions>,
+    state: web::Data<AppState>,
     token_state: web::Data<TokenState>,
-    kafka_add: web::Data<Addr<KafkaActor>>,
+    ingestion: web::Data<Ingestion>,
 ) -> Result<impl Responder, APIError> {
-    let mut token_store = token_state.token.lock().await;
     let token: MessageToken = auth.token().trim().to_string();
-    let message: ProtoMessageBackend = message.into_inner().into();
-    match token_store.get_user_id_of_token(&token).await {
-        None => {
-            info!("no user id of token {token}");
-            return Err(APIError::Unauthorized);
-        }
-        Some(user_id) => {
-            kafka_add.do_send(KafkaNotify(user_id, message));
+    let message = message
+        .into_inner()
+        .validated()
+        .map_err(|e| APIError::BadRequest(e.to_string()))?;
+    // the token store is only locked to resolve the token, not while ingesting
+    let info = {
+        let mut token_store = token_state.token.lock().await;
+        let info = authorize_token(&mut token_store, &token, TokenScope::Ingest)
+            .await
+            .map_err(token_error)?;
+        check_hostname(&info, &message.hostname).map_err(token_error)?;
+        state
+            .messages_rate_limiter
+            .check(&info.org_id.to_string(), 1)?;
+        record_token_hosts(&mut token_store, &info, [message.hostname.as_str()]).await;
+        info
+    };
+    ingestion
+        .ingest(info.org_id, vec![message], options.wait_for_ack)
+        .await
+        .map_err(ingest_error)?;
+
+    Ok("success".to_string())
+}
+
+/// Splits a batch body into its items. Items are parsed one by one so that a
+/// single invalid message, or one for a host the token isn't allowed for, only
+/// rejects itself. An NDJSON line that isn't JSON at all rejects the batch.
+fn parse_batch(
+    ndjson: bool,
+    body: &[u8],
+    token: &TokenInfo,
+) -> Result<Vec<Result<MessageBackend, String>>, APIError> {
+    let items: Vec<serde_json::Value> = if ndjson {
+        body.split(|byte| *byte == b'\n')
+            .enumerate()
+            .filter(|(_, line)| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|(index, line)| {
+                serde_json::from_slice(line).map_err(|e| {
+                    APIError::BadRequest(format!("invalid JSON on line {}: {e}", index + 1))
+                })
+            })
+            .collect::<Result<_, _>>()?
+    } else {
+        serde_json::from_slice(body)
+            .map_err(|e| APIError::BadRequest(format!("expected a JSON array: {e}")))?
+    };
+    if items.len() > MAX_BATCH_SIZE {
+        return Err(APIError::BadRequest(format!(
+            "at most {MAX_BATCH_SIZE} messages per batch"
+        )));
+    }
+
+    Ok(items
+        .into_iter()
+        .map(|item| {
+            let message: MessageBackend =
+                serde_json::from_value(item).map_err(|e| e.to_string())?;
+            let message = message.validated().map_err(|e| e.to_string())?;
+            check_hostname(token, &message.hostname).map_err(|e| e.to_string())?;
+            Ok(message)
+        })
+        .collect())
+}
+
+/// Accepts a JSON array or, with `Content-Type: application/x-ndjson`, newline
+/// delimited messages. The token is looked up once for the whole batch.
+#[post("/messages/batch")]
+pub(crate) async fn add_messages(
+    auth: BearerAuth,
+    request: HttpRequest,
+    body: web::Bytes,
+    options: web::Query<IngestOptions>,
+    state: web::Data<AppState>,
+    token_state: web::Data<TokenState>,
+    ingestion: web::Data<Ingestion>,
+) -> Result<impl Responder, APIError> {
+    let token: MessageToken = auth.token().trim().to_string();
+    let info = authorize_token(
+        &mut *token_state.token.lock().await,
+        &token,
+        TokenScope::Ingest,
+    )
+    .await
+    .map_err(token_error)?;
+    let org_id = info.org_id.clone();
+
+    let ndjson = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/x-ndjson"));
+    let items = parse_batch(ndjson, &body, &info)?;
+    state
+        .messages_rate_limiter
+        .check(&org_id.to_string(), items.len() as u64)?;
+
+    let mut accepted = Vec::new();
+    let mut results = Vec::with_capacity(items.len());
+    for (index, item) in items.into_iter().enumerate() {
+        match item {
+            Ok(message) => {
+                accepted.push(message);
+                results.push(BatchItemResult {
+                    index,
+                    accepted: true,
+                    error: None,
+                });
+            }
+            Err(error) => results.push(BatchItemResult {
+                index,
+                accepted: false,
+                error: Some(error),
+            }),
         }
     }
 
-    Ok("success".to_string())
+    let response = BatchResponse {
+        accepted: accepted.len(),
+        rejected: results.len() - accepted.len(),
+        results,
+    };
+    info!(
+        "batch of {org_id}: {} accepted, {} rejected",
+        response.accepted, response.rejected
+    );
+    record_token_hosts(
+        &mut *token_state.token.lock().await,
+        &info,
+        accepted.iter().map(|message| message.hostname.as_str()),
+    )
+    .await;
+    ingestion
+        .ingest(org_id, accepted, options.wait_for_ack)
+        .await
+        .map_err(ingest_error)?;
+    Ok(web::Json(response))
 }
 
 #[get("/hostnames")]
 pub(crate) async fn get_message_hostnames(
-    identity: Identity,
+    member: OrgMember,
     state: web::Data<AppState>,
 ) -> Result<impl Responder, APIError> {
     let mut messages_state = state.persist.lock().await;
-    let user_id: UserID = identity.id().unwrap().into();
-
     let hostnames: Vec<String> = messages_state
-        .get_hostnames_of_user(&user_id)
+        .get_hostnames(&member.org_id)
         .await
         .map_err(|_| APIError::InternalServerError)?;
     info!("returning {} objects ", hostnames.len());
     Ok(web::Json(hostnames))
 }
 
+/// Messages of one host of the selected organization of the logged-in user, or
+/// of the organization of a token with the read scope.
 #[get("/messages/{hostname}")]
 pub(crate) async fn get_messages_by_hostname(
     path: web::Path<String>,
-    identity: Identity,
+    query: web::Query<MessageQuery>,
+    member: Result<OrgMember, APIError>,
+    auth: Option<BearerAuth>,
     state: web::Data<AppState>,
 ) -> Result<impl Responder, APIError> {
     let hostname = path.into_inner();
     info!("received request for {}", &hostname);
-    let user_id: UserID = identity.id().unwrap().into();
-    let key = MessageKey { user_id, hostname };
+    let query = query.into_inner();
+    query
+        .offset()
+        .map_err(|e| APIError::BadRequest(e.to_string()))?;
+    let org_id: OrgID = match (member, auth) {
+        (Ok(member), _) => member.org_id,
+        (Err(APIError::Unauthorized), Some(auth)) => {
+            let token: MessageToken = auth.token().trim().to_string();
+            let info = authorize_token(&mut *state.persist.lock().await, &token, TokenScope::Read)
+                .await
+                .map_err(token_error)?;
+            check_hostname(&info, &hostname).map_err(token_error)?;
+            info.org_id
+        }
+        (Err(e), _) => return Err(e),
+    };
+    let key = MessageKey { org_id, hostname };
     let mut messages_state = state.persist.lock().await;
-    let messages: Vec<MessageBackend> = messages_state.find_messages(&key).await.map_err(|e| {
-        error!("{}", e);
-        APIError::InternalServerError
-    })?;
-    info!("returning {} objects ", messages.len());
-    Ok(web::Json(messages))
+    let page: MessagePage = messages_state
+        .query_messages(&key, &query)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+            APIError::InternalServerError
+        })?;
+    info!(
+        "returning {} of {} objects ",
+        page.messages.len(),
+        page.total
+    );
+    Ok(web::Json(page))
+}
+
+/// Streams incoming messages of the selected organization as server-sent
+/// events, optionally restricted to a single hostname.
+#[get("/events/messages")]
+pub(crate) async fn stream_messages(
+    query: web::Query<StreamRequest>,
+    member: OrgMember,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let OrgMember {
+        user_id, org_id, ..
+    } = member;
+    let hostname = query.into_inner().hostname;
+    info!("streaming messages of {org_id} to {user_id}");
+
+    let events = BroadcastStream::new(state.message_events.subscribe()).filter_map(move |event| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                error!("message stream of {org_id}: {e}");
+                return std::future::ready(None);
+            }
+        };
+        if event.org_id != org_id
+            || hostname
+                .as_ref()
+                .is_some_and(|hostname| &event.message.hostname != hostname)
+        {
+            return std::future::ready(None);
+        }
+        let payload = serde_json::to_string(&event.message)
+            .map(|json| Bytes::from(format!("data: {json}\n\n")));
+        std::future::ready(Some(payload))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::authentication::login;
+    use crate::api::login_cookie;
+    use crate::api::organizations::ORG_HEADER;
+    use crate::model::message::MessageEvent;
+    use crate::model::organization::{Organization, Role};
+    use crate::model::token::TokenMetadata;
+    use crate::model::user::{User, UserID};
+    use crate::persistence::token::TokenStore;
+    use crate::persistence::{PersistOrganization, PersistUser};
+    use crate::service::token::hash_token;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::body::MessageBody;
+    use actix_web::cookie::Key;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use std::future::poll_fn;
+
+    #[actix_web::test]
+    async fn test_parse_batch() {
+        let body = br#"[{"hostname": "a", "title": "t", "body": "b", "timestamp": null},
+                        {"hostname": "", "title": "t", "body": "b", "timestamp": null},
+                        {"title": "missing hostname"}]"#;
+        let token = TokenInfo::new(
+            "hash".to_string(),
+            "token".to_string(),
+            OrgID::new(),
+            TokenMetadata::default(),
+            None,
+        );
+        let items = parse_batch(false, body, &token).unwrap();
+        assert_eq!(items.len(), 3);
+        assert!(items[0].as_ref().unwrap().timestamp.is_some());
+        assert!(items[1].is_err());
+        assert!(items[2].is_err());
+
+        let body = b"{\"hostname\": \"a\", \"title\": \"t\", \"body\": \"b\", \"timestamp\": null}\n\n{\"title\": \"t\"}\n";
+        let items = parse_batch(true, body, &token).unwrap();
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_ok());
+        assert!(items[1].is_err());
+
+        let body = b"{\"hostname\": \"a\", \"title\": \"t\", \"body\": \"b\", \"timestamp\": null}\n\nnot json\n";
+        match parse_batch(true, body, &token) {
+            Err(APIError::BadRequest(error)) => {
+                assert!(
+                    error.starts_with("invalid JSON on line 3: expected"),
+                    "{error}"
+                )
+            }
+            _ => panic!("expected a bad request"),
+        }
+
+        assert!(parse_batch(false, b"{}", &token).is_err());
+        let too_many = format!("[{}]", vec!["{}"; MAX_BATCH_SIZE + 1].join(","));
+        assert!(parse_batch(false, too_many.as_bytes(), &token).is_err());
+
+        let restricted = TokenInfo {
+            metadata: TokenMetadata {
+                hostnames: vec!["a".to_string()],
+                ..TokenMetadata::default()
+            },
+            ..token
+        };
+        let body = br#"[{"hostname": "a", "title": "t", "body": "b", "timestamp": null},
+                        {"hostname": "b", "title": "t", "body": "b", "timestamp": null}]"#;
+        let items = parse_batch(false, body, &restricted).unwrap();
+        assert!(items[0].is_ok());
+        assert!(items[1].is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_stream_messages() {
+        let state = web::Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&user).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(stream_messages),
+        )
+        .await;
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+
+        let request = test::TestRequest::get()
+            .uri("/events/messages?hostname=host-a")
+            .cookie(cookie)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert!(response.status().is_success());
+
+        let event = |org_id: OrgID, hostname: &str| MessageEvent {
+            org_id,
+            message: MessageBackend {
+                hostname: hostname.to_string(),
+                title: format!("title-{hostname}"),
+                ..Default::default()
+            },
+        };
+        state
+            .message_events
+            .send(event(OrgID::new(), "host-a"))
+            .unwrap();
+        state
+            .message_events
+            .send(event(OrgID::personal(&user.user_id), "host-b"))
+            .unwrap();
+        state
+            .message_events
+            .send(event(OrgID::personal(&user.user_id), "host-a"))
+            .unwrap();
+
+        let mut body = Box::pin(response.into_body());
+        let chunk = poll_fn(|cx| body.as_mut().poll_next(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(chunk.starts_with("data: "));
+        assert!(chunk.contains("title-host-a"));
+        assert!(!chunk.contains("title-host-b"));
+    }
+
+    #[actix_web::test]
+    async fn test_read_messages_with_token() {
+        let state = web::Data::new(AppState::in_memory());
+        let org_id = OrgID::new();
+        let (read_token, ingest_token) = {
+            let mut persist = state.persist.lock().await;
+            let metadata = TokenMetadata {
+                scopes: vec![TokenScope::Read],
+                hostnames: vec!["host".to_string()],
+                ..TokenMetadata::default()
+            };
+            (
+                persist.create_token(&org_id, &metadata).await.unwrap(),
+                persist.create_token_for_org(&org_id).await.unwrap(),
+            )
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(get_messages_by_hostname),
+        )
+        .await;
+
+        for (token, uri, status) in [
+            (&read_token, "/messages/host", StatusCode::OK),
+            (&read_token, "/messages/other", StatusCode::FORBIDDEN),
+            (&ingest_token, "/messages/host", StatusCode::FORBIDDEN),
+        ] {
+            let request = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", format!("Bearer {token}")))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status, "{uri}");
+        }
+        let request = test::TestRequest::get().uri("/messages/host").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let info = state
+            .persist
+            .lock()
+            .await
+            .get_token_info(&hash_token(&read_token))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(info.last_used_at.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_selected_organization() {
+        let state = web::Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        let team = Organization::new("team".to_string());
+        {
+            let mut persist = state.persist.lock().await;
+            persist.add_user(&user).await.unwrap();
+            persist
+                .add_organization(&team, &UserID::new())
+                .await
+                .unwrap();
+            persist
+                .set_member(&team.org_id, &user.user_id, Role::Viewer)
+                .await
+                .unwrap();
+            let key = MessageKey {
+                org_id: team.org_id.clone(),
+                hostname: "team-host".to_string(),
+            };
+            persist
+                .add_message(&key, &MessageBackend::default())
+                .await
+                .unwrap();
+        }
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(get_message_hostnames),
+        )
+        .await;
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+
+        let request = test::TestRequest::get()
+            .uri("/hostnames")
+            .cookie(cookie.clone())
+            .to_request();
+        let hostnames: Vec<String> = test::call_and_read_body_json(&app, request).await;
+        assert!(hostnames.is_empty());
+        let request = test::TestRequest::get()
+            .uri("/hostnames")
+            .cookie(cookie.clone())
+            .insert_header((ORG_HEADER, team.org_id.to_string()))
+            .to_request();
+        let hostnames: Vec<String> = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(hostnames, ["team-host"]);
+        let request = test::TestRequest::get()
+            .uri("/hostnames")
+            .cookie(cookie)
+            .insert_header((ORG_HEADER, OrgID::new().to_string()))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
 }
@@ -1,23 +1,156 @@
+pub(crate) mod admin;
 pub mod authentication;
+pub(crate) mod authorization;
+pub(crate) mod grpc;
 pub mod messages;
 pub(crate) mod notification_settings;
+pub(crate) mod organizations;
+pub mod password;
+pub(crate) mod rate_limit;
 pub mod registration;
+pub(crate) mod sessions;
 pub mod token;
+pub(crate) mod two_factor;
 pub mod users;
 
-use actix_web::{get, Responder};
+use actix_web::{get, HttpRequest, Responder};
+use lazy_static::lazy_static;
 use log::debug;
 use reqwest::Url;
-use tokio::sync::Mutex;
+use std::env;
+use std::net::IpAddr;
+use tokio::sync::{broadcast, Mutex};
 
-use crate::persistence::redis::RedisDatabaseService;
+use crate::model::message::MessageEvent;
+
+use crate::api::rate_limit::LocalRateLimiter;
+use crate::persistence::Persistence;
 use crate::service::notification_filter::NotificationFilter;
 
+pub(crate) const MESSAGE_EVENTS_CAPACITY: usize = 1024;
+
 pub struct AppState {
-    pub persist: Mutex<RedisDatabaseService>,
+    pub persist: Mutex<Persistence>,
     pub backend_url: Url,
     pub frontend_url: Url,
     pub(crate) notification_filter: Mutex<NotificationFilter>,
+    pub(crate) message_events: broadcast::Sender<MessageEvent>,
+    /// Grants access to the admin API like a user with the admin role.
+    pub(crate) admin_token: Option<String>,
+    /// Limits ingestion per organization.
+    pub(crate) messages_rate_limiter: LocalRateLimiter,
+}
+
+#[cfg(test)]
+impl AppState {
+    pub(crate) fn in_memory() -> Self {
+        Self {
+            persist: Mutex::new(Persistence::in_memory()),
+            backend_url: Url::parse("http://localhost:8081").unwrap(),
+            frontend_url: Url::parse("http://localhost:5173").unwrap(),
+            notification_filter: Mutex::new(NotificationFilter::new()),
+            message_events: broadcast::channel(MESSAGE_EVENTS_CAPACITY).0,
+            admin_token: None,
+            messages_rate_limiter: LocalRateLimiter::new(rate_limit::MESSAGES_RATE_LIMIT),
+        }
+    }
+}
+
+lazy_static! {
+    /// Reverse proxies whose `X-Forwarded-For` header is honoured.
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = trusted_proxies().unwrap_or_default();
+}
+
+/// Parses the comma separated IPs of `SNITCH_TRUSTED_PROXIES`.
+fn trusted_proxies() -> anyhow::Result<Vec<IpAddr>> {
+    let Ok(proxies) = env::var("SNITCH_TRUSTED_PROXIES") else {
+        return Ok(Vec::new());
+    };
+    proxies
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| {
+            proxy
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid IP in SNITCH_TRUSTED_PROXIES: {proxy}"))
+        })
+        .collect()
+}
+
+/// Fails if `SNITCH_TRUSTED_PROXIES` is invalid, checked at startup so that a
+/// typo isn't silently ignored.
+pub(crate) fn check_trusted_proxies() -> anyhow::Result<()> {
+    trusted_proxies().map(|_| ())
+}
+
+/// The peer address, or for requests from a trusted proxy the last address in
+/// `X-Forwarded-For` that isn't a trusted proxy itself. Other clients could
+/// forge the header.
+pub(crate) fn client_ip(request: &HttpRequest) -> String {
+    let forwarded_for = request
+        .headers()
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok());
+    forwarded_client_ip(
+        request.peer_addr().map(|address| address.ip()),
+        forwarded_for,
+        &TRUSTED_PROXIES,
+    )
+    .map(|ip| ip.to_string())
+    .unwrap_or("unknown".to_string())
+}
+
+fn forwarded_client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted: &[IpAddr],
+) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted.contains(&peer) {
+        return Some(peer);
+    }
+    let mut client = peer;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        match hop.trim().parse() {
+            Ok(ip) => {
+                client = ip;
+                if !trusted.contains(&ip) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+/// Logs in through `/login` and returns the session cookie.
+#[cfg(test)]
+pub(crate) async fn login_cookie<S, B>(
+    app: &S,
+    email: &str,
+    password: &str,
+) -> actix_web::cookie::Cookie<'static>
+where
+    S: actix_web::dev::Service<
+        actix_http::Request,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+{
+    let request = actix_web::test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": email, "password": password}))
+        .to_request();
+    let response = actix_web::test::call_service(app, request).await;
+    assert!(response.status().is_success());
+    response
+        .response()
+        .cookies()
+        .next()
+        .expect("no session cookie")
+        .into_owned()
 }
 
 #[get("/")]
@@ -25,3 +158,35 @@ pub(crate) async fn welcome() -> impl Responder {
     debug!("welcome request");
     "welcome"
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_client_ip() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let proxy = ip("10.0.0.1");
+        let trusted = [proxy, ip("10.0.0.2")];
+
+        // forwarding headers of untrusted peers are ignored
+        assert_eq!(
+            forwarded_client_ip(Some(ip("1.2.3.4")), Some("5.6.7.8"), &trusted),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(
+            forwarded_client_ip(Some(proxy), None, &trusted),
+            Some(proxy)
+        );
+        // addresses the client sent itself, left of the last untrusted one, are ignored
+        assert_eq!(
+            forwarded_client_ip(Some(proxy), Some("9.9.9.9, 1.2.3.4, 10.0.0.2"), &trusted),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(
+            forwarded_client_ip(Some(proxy), Some("garbage"), &trusted),
+            Some(proxy)
+        );
+        assert_eq!(forwarded_client_ip(None, Some("1.2.3.4"), &trusted), None);
+    }
+}
@@ -1,44 +1,279 @@
+use crate::api::authorization::OrgMember;
 use crate::api::AppState;
 use crate::errors::APIError;
-use crate::model::user::UserID;
+use crate::model::message::MessageBackend;
+use crate::model::notification_rule::{NotificationRule, RuleID};
+use crate::model::organization::{OrgID, Role};
 use crate::persistence::redis::NotificationSettings;
-use actix_identity::Identity;
-use actix_web::{get, post, services, web, Responder};
-use log::info;
+use crate::persistence::{PersistNotificationRules, PersistNotificationSettings, Persistence};
+use crate::service::notification_rules::evaluate;
+use actix_web::{delete, get, post, put, services, web, HttpResponse, Responder};
+use log::{error, info};
 
 #[post("/notification_settings")]
 pub(crate) async fn set_notification_settings(
-    id: Identity,
+    member: OrgMember,
     notification_settings: web::Json<NotificationSettings>,
     state: web::Data<AppState>,
 ) -> Result<(), APIError> {
     info!("generate new notification_settings request");
-    let user_id: UserID = id.id().unwrap().into();
+    let org_id = member.require(Role::Admin)?.org_id.clone();
     state
         .persist
         .lock()
         .await
-        .set_notification_settings(&user_id, notification_settings.into_inner())
-        .await;
-    Ok(())
+        .set_notification_settings(&org_id, notification_settings.into_inner())
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })
 }
 
 #[get("/notification_settings")]
 pub(crate) async fn get_notification_settings(
-    id: Identity,
+    member: OrgMember,
     state: web::Data<AppState>,
 ) -> Result<impl Responder, APIError> {
     info!("get notification_settings request");
-    let user_id: UserID = id.id().unwrap().into();
+    let org_id = member.org_id;
     let notification_settings = state
         .persist
         .lock()
         .await
-        .get_notification_settings(&user_id)
-        .await;
+        .get_notification_settings(&org_id)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
     Ok(web::Json(notification_settings))
 }
 
-pub fn get_notification_services() -> (get_notification_settings, set_notification_settings) {
-    services![get_notification_settings, set_notification_settings]
+/// Edits hold one persistence guard across [`load_rules`] and [`store_rules`], so
+/// that concurrent edits don't overwrite each other.
+async fn load_rules(
+    persist: &mut Persistence,
+    org_id: &OrgID,
+) -> Result<Vec<NotificationRule>, APIError> {
+    persist.get_notification_rules(org_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })
+}
+
+async fn store_rules(
+    persist: &mut Persistence,
+    org_id: &OrgID,
+    rules: &[NotificationRule],
+) -> Result<(), APIError> {
+    persist
+        .set_notification_rules(org_id, rules)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })
+}
+
+fn validate_rule(rule: &NotificationRule) -> Result<(), APIError> {
+    rule.validate()
+        .map_err(|e| APIError::BadRequest(format!("invalid rule {}: {e}", rule.name)))
+}
+
+#[get("/notification_rules")]
+pub(crate) async fn get_notification_rules(
+    member: OrgMember,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let org_id = member.org_id;
+    let mut persist = state.persist.lock().await;
+    Ok(web::Json(load_rules(&mut persist, &org_id).await?))
+}
+
+/// Appends a rule. Rules are evaluated in the order they were added.
+#[post("/notification_rules")]
+pub(crate) async fn create_notification_rule(
+    member: OrgMember,
+    rule: web::Json<NotificationRule>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let org_id = member.require(Role::Admin)?.org_id.clone();
+    let mut rule = rule.into_inner();
+    validate_rule(&rule)?;
+    rule.rule_id = NotificationRule::new_rule_id();
+    info!("create notification rule {} of {org_id}", rule.rule_id);
+
+    let mut persist = state.persist.lock().await;
+    let mut rules = load_rules(&mut persist, &org_id).await?;
+    rules.push(rule.clone());
+    store_rules(&mut persist, &org_id, &rules).await?;
+    Ok(web::Json(rule))
+}
+
+#[put("/notification_rules/{rule_id}")]
+pub(crate) async fn update_notification_rule(
+    member: OrgMember,
+    path: web::Path<RuleID>,
+    rule: web::Json<NotificationRule>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let org_id = member.require(Role::Admin)?.org_id.clone();
+    let mut rule = rule.into_inner();
+    validate_rule(&rule)?;
+    rule.rule_id = path.into_inner();
+
+    let mut persist = state.persist.lock().await;
+    let mut rules = load_rules(&mut persist, &org_id).await?;
+    let existing = rules
+        .iter_mut()
+        .find(|existing| existing.rule_id == rule.rule_id)
+        .ok_or(APIError::NotFound)?;
+    *existing = rule.clone();
+    store_rules(&mut persist, &org_id, &rules).await?;
+    Ok(web::Json(rule))
+}
+
+#[delete("/notification_rules/{rule_id}")]
+pub(crate) async fn delete_notification_rule(
+    member: OrgMember,
+    path: web::Path<RuleID>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, APIError> {
+    let org_id = member.require(Role::Admin)?.org_id.clone();
+    let rule_id = path.into_inner();
+
+    let mut persist = state.persist.lock().await;
+    let mut rules = load_rules(&mut persist, &org_id).await?;
+    let n_rules = rules.len();
+    rules.retain(|rule| rule.rule_id != rule_id);
+    if rules.len() == n_rules {
+        return Err(APIError::NotFound);
+    }
+    store_rules(&mut persist, &org_id, &rules).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Shows which rules a sample message would match and where it would be sent to.
+#[post("/notification_rules/dry_run")]
+pub(crate) async fn dry_run_notification_rules(
+    member: OrgMember,
+    message: web::Json<MessageBackend>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let org_id = member.org_id;
+    let rules = load_rules(&mut *state.persist.lock().await, &org_id).await?;
+    Ok(web::Json(evaluate(&rules, &message)))
+}
+
+pub fn get_notification_services() -> (
+    get_notification_settings,
+    set_notification_settings,
+    get_notification_rules,
+    dry_run_notification_rules,
+    create_notification_rule,
+    update_notification_rule,
+    delete_notification_rule,
+) {
+    services![
+        get_notification_settings,
+        set_notification_settings,
+        get_notification_rules,
+        dry_run_notification_rules,
+        create_notification_rule,
+        update_notification_rule,
+        delete_notification_rule
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::authentication::login;
+    use crate::api::login_cookie;
+    use crate::model::user::User;
+    use crate::persistence::PersistUser;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::Key;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    #[actix_web::test]
+    async fn test_notification_rules() {
+        let state = web::Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&user).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(get_notification_services()),
+        )
+        .await;
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+
+        let request = test::TestRequest::post()
+            .uri("/notification_rules")
+            .cookie(cookie.clone())
+            .set_json(
+                json!({"name": "broken", "conditions": {"body": "("}, "action": {"type": "mute"}}),
+            )
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let request = test::TestRequest::post()
+            .uri("/notification_rules")
+            .cookie(cookie.clone())
+            .set_json(json!({
+                "name": "critical web",
+                "conditions": {"hostname": "web-*", "severity": "critical"},
+                "action": {"type": "route", "channels": ["telegram"]}
+            }))
+            .to_request();
+        let rule: NotificationRule = test::call_and_read_body_json(&app, request).await;
+        assert!(!rule.rule_id.is_empty());
+
+        let request = test::TestRequest::post()
+            .uri("/notification_rules/dry_run")
+            .cookie(cookie.clone())
+            .set_json(json!({"hostname": "web-1", "title": "t", "body": "b", "timestamp": null, "severity": "critical"}))
+            .to_request();
+        let evaluation: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(
+            evaluation["matched_rules"][0]["rule_id"],
+            json!(rule.rule_id)
+        );
+        assert_eq!(evaluation["channels"], json!(["telegram"]));
+        assert_eq!(evaluation["muted"], json!(false));
+
+        let request = test::TestRequest::delete()
+            .uri(&format!("/notification_rules/{}", rule.rule_id))
+            .cookie(cookie.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let request = test::TestRequest::get()
+            .uri("/notification_rules")
+            .cookie(cookie.clone())
+            .to_request();
+        let rules: Vec<NotificationRule> = test::call_and_read_body_json(&app, request).await;
+        assert!(rules.is_empty());
+
+        let request = test::TestRequest::delete()
+            .uri(&format!("/notification_rules/{}", rule.rule_id))
+            .cookie(cookie)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 }
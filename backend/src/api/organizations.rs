@@ -0,0 +1,659 @@
+use crate::api::authorization::{AuthenticatedUser, OrgMember};
+use crate::api::AppState;
+use crate::errors::APIError;
+use crate::model::organization::{Invitation, OrgID, Organization, Role};
+use crate::model::user::{Nonce, UserID};
+use crate::persistence::{PersistOrganization, PersistUser, Persistence};
+use crate::service::email::{generate_invitation_mail, send_mail};
+use crate::service::token::random_alphanumeric_string;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use lettre::message::Mailbox;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+/// Selects the organization of a request, the personal one if absent, see
+/// [`OrgMember`].
+pub(crate) const ORG_HEADER: &str = "x-snitch-org";
+
+const NONCE_LENGTH: u32 = 40;
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct CreateOrganizationRequest {
+    #[validate(length(min = 1, max = 64))]
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetMemberRequest {
+    role: Role,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct InvitationRequest {
+    #[validate(email)]
+    email: String,
+    role: Role,
+}
+
+/// An organization with the role of the requesting user.
+#[derive(Serialize, Debug)]
+struct OrganizationResponse {
+    #[serde(flatten)]
+    organization: Organization,
+    role: Role,
+}
+
+#[derive(Serialize, Debug)]
+struct MemberResponse {
+    user_id: UserID,
+    email: String,
+    role: Role,
+}
+
+fn internal_error(e: anyhow::Error) -> APIError {
+    error!("{e}");
+    APIError::InternalServerError
+}
+
+/// Fails unless the user is a member of the organization with at least `role`,
+/// for routes naming the organization in the path.
+pub(crate) async fn require_role(
+    persist: &mut Persistence,
+    org_id: &OrgID,
+    user: &AuthenticatedUser,
+    role: Role,
+) -> Result<Role, APIError> {
+    Ok(OrgMember::of(persist, org_id, user)
+        .await?
+        .require(role)?
+        .role)
+}
+
+async fn organization(persist: &mut Persistence, org_id: &OrgID) -> Result<Organization, APIError> {
+    persist
+        .get_organization(org_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(APIError::NotFound)
+}
+
+fn owners(members: &[(UserID, Role)]) -> usize {
+    members
+        .iter()
+        .filter(|(_, role)| *role == Role::Owner)
+        .count()
+}
+
+/// Prepares deleting a user: organizations the user is the only member of are
+/// deleted. Fails without changes if the user is the last owner of an
+/// organization with other members.
+pub(crate) async fn leave_organizations(
+    persist: &mut Persistence,
+    user_id: &UserID,
+) -> Result<(), APIError> {
+    let mut abandoned = Vec::new();
+    for (org_id, role) in persist
+        .get_organizations_of_user(user_id)
+        .await
+        .map_err(internal_error)?
+    {
+        if org_id == OrgID::personal(user_id) {
+            continue;
+        }
+        let members = persist.get_members(&org_id).await.map_err(internal_error)?;
+        if members.len() == 1 {
+            abandoned.push(org_id);
+        } else if role == Role::Owner && owners(&members) == 1 {
+            return Err(APIError::BadRequest(format!(
+                "last owner of organization {org_id}, transfer the ownership first"
+            )));
+        }
+    }
+    for org_id in abandoned {
+        persist
+            .delete_organization(&org_id)
+            .await
+            .map_err(internal_error)?;
+    }
+    Ok(())
+}
+
+#[post("/orgs")]
+pub(crate) async fn create_organization(
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+    request: web::Json<CreateOrganizationRequest>,
+) -> Result<impl Responder, APIError> {
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return Err(APIError::BadRequest(format!("{e}")));
+    }
+    user.require_write()?;
+    let user_id = &user.user_id;
+    let organization = Organization::new(request.name);
+    state
+        .persist
+        .lock()
+        .await
+        .add_organization(&organization, user_id)
+        .await
+        .map_err(internal_error)?;
+    info!("{user_id} created organization {}", organization.org_id);
+    Ok(web::Json(OrganizationResponse {
+        organization,
+        role: Role::Owner,
+    }))
+}
+
+/// The organizations of the user, the personal one first.
+#[get("/orgs")]
+pub(crate) async fn get_organizations(
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let user_id = &user.user_id;
+    let mut persist = state.persist.lock().await;
+    let mut organizations = Vec::new();
+    for (org_id, role) in persist
+        .get_organizations_of_user(user_id)
+        .await
+        .map_err(internal_error)?
+    {
+        if let Some(organization) = persist
+            .get_organization(&org_id)
+            .await
+            .map_err(internal_error)?
+        {
+            organizations.push(OrganizationResponse { organization, role });
+        }
+    }
+    organizations.sort_by_key(|response| {
+        (
+            !response.organization.personal,
+            response.organization.created_at,
+        )
+    });
+    Ok(web::Json(organizations))
+}
+
+#[get("/orgs/{org_id}/members")]
+pub(crate) async fn get_members(
+    path: web::Path<OrgID>,
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let mut persist = state.persist.lock().await;
+    require_role(&mut persist, &path, &user, Role::Viewer).await?;
+    let mut members = Vec::new();
+    for (user_id, role) in persist.get_members(&path).await.map_err(internal_error)? {
+        match persist.get_user_by_id(&user_id).await {
+            Ok(user) => members.push(MemberResponse {
+                user_id,
+                email: user.email,
+                role,
+            }),
+            Err(e) => error!("member {user_id} of {}: {e}", path.as_ref()),
+        }
+    }
+    Ok(web::Json(members))
+}
+
+/// Changes the role of a member. Admins manage viewers and admins, only owners
+/// grant or revoke ownership. The last owner can't be demoted.
+#[put("/orgs/{org_id}/members/{user_id}")]
+pub(crate) async fn set_member(
+    path: web::Path<(OrgID, UserID)>,
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+    request: web::Json<SetMemberRequest>,
+) -> Result<impl Responder, APIError> {
+    let (org_id, member_id) = path.into_inner();
+    let user_id = &user.user_id;
+    let mut persist = state.persist.lock().await;
+    let role = require_role(&mut persist, &org_id, &user, Role::Admin).await?;
+    let members = persist.get_members(&org_id).await.map_err(internal_error)?;
+    let current = members
+        .iter()
+        .find(|(user_id, _)| user_id == &member_id)
+        .map(|(_, role)| *role)
+        .ok_or(APIError::NotFound)?;
+    if (request.role == Role::Owner || current == Role::Owner) && role < Role::Owner {
+        return Err(APIError::Forbidden("requires the owner role".to_string()));
+    }
+    if current == Role::Owner && request.role != Role::Owner && owners(&members) == 1 {
+        return Err(APIError::BadRequest(
+            "an organization needs an owner".to_string(),
+        ));
+    }
+    persist
+        .set_member(&org_id, &member_id, request.role)
+        .await
+        .map_err(internal_error)?;
+    info!("{user_id} made {member_id} {} of {org_id}", request.role);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Removes a member, or leaves the organization when removing oneself. Nobody
+/// leaves a personal organization of their own and the last owner stays.
+#[delete("/orgs/{org_id}/members/{user_id}")]
+pub(crate) async fn remove_member(
+    path: web::Path<(OrgID, UserID)>,
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let (org_id, member_id) = path.into_inner();
+    let user_id = &user.user_id;
+    let mut persist = state.persist.lock().await;
+    let required = if &member_id == user_id {
+        Role::Viewer
+    } else {
+        Role::Admin
+    };
+    let role = require_role(&mut persist, &org_id, &user, required).await?;
+    if org_id == OrgID::personal(&member_id) {
+        return Err(APIError::BadRequest(
+            "personal organizations can't be left".to_string(),
+        ));
+    }
+    let members = persist.get_members(&org_id).await.map_err(internal_error)?;
+    let current = members
+        .iter()
+        .find(|(user_id, _)| user_id == &member_id)
+        .map(|(_, role)| *role)
+        .ok_or(APIError::NotFound)?;
+    if current == Role::Owner && role < Role::Owner {
+        return Err(APIError::Forbidden("requires the owner role".to_string()));
+    }
+    if current == Role::Owner && owners(&members) == 1 {
+        return Err(APIError::BadRequest(
+            "an organization needs an owner".to_string(),
+        ));
+    }
+    persist
+        .remove_member(&org_id, &member_id)
+        .await
+        .map_err(internal_error)?;
+    info!("{user_id} removed {member_id} from {org_id}");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Deletes the organization with its messages, tokens and settings.
+#[delete("/orgs/{org_id}")]
+pub(crate) async fn delete_organization(
+    path: web::Path<OrgID>,
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let user_id = &user.user_id;
+    let mut persist = state.persist.lock().await;
+    require_role(&mut persist, &path, &user, Role::Owner).await?;
+    if organization(&mut persist, &path).await?.personal {
+        return Err(APIError::BadRequest(
+            "personal organizations can't be deleted".to_string(),
+        ));
+    }
+    persist
+        .delete_organization(&path)
+        .await
+        .map_err(internal_error)?;
+    info!("{user_id} deleted organization {}", path.as_ref());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Mails an invitation link to the frontend, which accepts it through
+/// [`accept_invitation`].
+#[post("/orgs/{org_id}/invitations")]
+pub(crate) async fn invite_member(
+    path: web::Path<OrgID>,
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+    request: web::Json<InvitationRequest>,
+) -> Result<impl Responder, APIError> {
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return Err(APIError::BadRequest(format!("{e}")));
+    }
+    let org_id = path.into_inner();
+    let user_id = &user.user_id;
+    let mut persist = state.persist.lock().await;
+    let role = require_role(&mut persist, &org_id, &user, Role::Admin).await?;
+    if request.role > role {
+        return Err(APIError::Forbidden(format!(
+            "requires the {} role",
+            request.role
+        )));
+    }
+    let organization = organization(&mut persist, &org_id).await?;
+
+    let nonce = random_alphanumeric_string(NONCE_LENGTH);
+    let invitation = Invitation {
+        org_id: org_id.clone(),
+        email: request.email,
+        role: request.role,
+        invited_by: user_id.clone(),
+    };
+    persist
+        .add_invitation(&nonce, &invitation)
+        .await
+        .map_err(internal_error)?;
+    let invitation_link = state
+        .frontend_url
+        .join(&format!("invitations/{nonce}"))
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    let receiver: Mailbox = invitation.email.parse().map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    let mail = generate_invitation_mail(
+        &organization.name,
+        &invitation.role.to_string(),
+        &invitation_link,
+    );
+    tokio::task::spawn(async move {
+        if let Err(e) = send_mail(mail, receiver).await {
+            error!("failed sending invitation mail: {e}");
+        }
+    });
+    info!("{user_id} invited a {} to {org_id}", invitation.role);
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Joins the organization of an invitation sent to the email of the user. The
+/// link works once, opening it with another account doesn't use it up.
+#[post("/invitations/{nonce}")]
+pub(crate) async fn accept_invitation(
+    nonce: web::Path<Nonce>,
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let user_id = &user.user_id;
+    let mut persist = state.persist.lock().await;
+    let invitation = persist
+        .get_invitation(&nonce)
+        .await
+        .map_err(internal_error)?
+        .ok_or(APIError::NotFound)?;
+    let user = persist
+        .get_user_by_id(user_id)
+        .await
+        .map_err(internal_error)?;
+    if !user.email.eq_ignore_ascii_case(&invitation.email) {
+        info!("{user_id} can't accept an invitation sent to another email");
+        return Err(APIError::Forbidden(
+            "invitation sent to another email".to_string(),
+        ));
+    }
+    // fails if another request took the invitation in the meantime
+    let invitation = persist
+        .take_invitation(&nonce)
+        .await
+        .map_err(internal_error)?
+        .ok_or(APIError::NotFound)?;
+    let organization = organization(&mut persist, &invitation.org_id).await?;
+    let role = persist
+        .get_role(&organization.org_id, user_id)
+        .await
+        .map_err(internal_error)?
+        .map_or(invitation.role, |role| role.max(invitation.role));
+    persist
+        .set_member(&organization.org_id, user_id, role)
+        .await
+        .map_err(internal_error)?;
+    info!("{user_id} joined {} as {role}", organization.org_id);
+    Ok(web::Json(OrganizationResponse { organization, role }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::authentication::login;
+    use crate::api::login_cookie;
+    use crate::model::user::User;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::{Cookie, Key};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    async fn add_users(state: &AppState, emails: &[&str]) -> Vec<User> {
+        let mut users = Vec::new();
+        for email in emails {
+            let user = User::new(email.to_string(), "asdfasdfasdf".to_string());
+            state.persist.lock().await.add_user(&user).await.unwrap();
+            users.push(user);
+        }
+        users
+    }
+
+    #[actix_web::test]
+    async fn test_organization_roles() {
+        let state = web::Data::new(AppState::in_memory());
+        let users = add_users(&state, &["x.x@x.x", "y.y@y.y"]).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(create_organization)
+                .service(get_organizations)
+                .service(get_members)
+                .service(set_member)
+                .service(remove_member)
+                .service(delete_organization),
+        )
+        .await;
+        let owner = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+        let member = login_cookie(&app, "y.y@y.y", "asdfasdfasdf").await;
+
+        let request = test::TestRequest::post()
+            .uri("/orgs")
+            .cookie(owner.clone())
+            .set_json(json!({"name": "team"}))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(created["role"], "owner");
+        let org_id = OrgID(created["org_id"].as_str().unwrap().to_string());
+        state
+            .persist
+            .lock()
+            .await
+            .set_member(&org_id, &users[1].user_id, Role::Viewer)
+            .await
+            .unwrap();
+
+        let request = test::TestRequest::get()
+            .uri("/orgs")
+            .cookie(member.clone())
+            .to_request();
+        let listed: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(listed[0]["personal"], true);
+        assert_eq!(listed[1]["role"], "viewer");
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/orgs/{org_id}/members"))
+            .cookie(member.clone())
+            .to_request();
+        let members: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(members.as_array().unwrap().len(), 2);
+
+        let set_role = |cookie: &Cookie<'static>, user_id: &UserID, role: &str| {
+            test::TestRequest::put()
+                .uri(&format!("/orgs/{org_id}/members/{user_id}"))
+                .cookie(cookie.clone())
+                .set_json(json!({ "role": role }))
+                .to_request()
+        };
+        let response =
+            test::call_service(&app, set_role(&member, &users[1].user_id, "admin")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&app, set_role(&owner, &users[0].user_id, "admin")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&app, set_role(&owner, &users[1].user_id, "admin")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        // admins don't manage owners
+        let response =
+            test::call_service(&app, set_role(&member, &users[1].user_id, "owner")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let request = test::TestRequest::delete()
+            .uri(&format!("/orgs/{org_id}/members/{}", users[0].user_id))
+            .cookie(member.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let request = test::TestRequest::delete()
+            .uri(&format!("/orgs/{}", users[0].user_id))
+            .cookie(owner.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let request = test::TestRequest::delete()
+            .uri(&format!("/orgs/{org_id}/members/{}", users[1].user_id))
+            .cookie(member.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let request = test::TestRequest::get()
+            .uri(&format!("/orgs/{org_id}/members"))
+            .cookie(member)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let request = test::TestRequest::delete()
+            .uri(&format!("/orgs/{org_id}"))
+            .cookie(owner)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let mut persist = state.persist.lock().await;
+        assert_eq!(persist.get_organization(&org_id).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_accept_invitation() {
+        let state = web::Data::new(AppState::in_memory());
+        let users = add_users(&state, &["x.x@x.x", "y.y@y.y"]).await;
+        let organization = Organization::new("team".to_string());
+        let org_id = &organization.org_id;
+        let mut persist = state.persist.lock().await;
+        persist
+            .add_organization(&organization, &users[0].user_id)
+            .await
+            .unwrap();
+        for (nonce, email) in [("nonce", "Y.Y@y.y"), ("other", "z.z@z.z")] {
+            let invitation = Invitation {
+                org_id: org_id.clone(),
+                email: email.to_string(),
+                role: Role::Admin,
+                invited_by: users[0].user_id.clone(),
+            };
+            persist
+                .add_invitation(&nonce.to_string(), &invitation)
+                .await
+                .unwrap();
+        }
+        drop(persist);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(accept_invitation),
+        )
+        .await;
+        let cookie = login_cookie(&app, "y.y@y.y", "asdfasdfasdf").await;
+        let accept = |nonce: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/invitations/{nonce}"))
+                .cookie(cookie.clone())
+                .to_request()
+        };
+        let response = test::call_service(&app, accept("other")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(state
+            .persist
+            .lock()
+            .await
+            .get_invitation(&"other".to_string())
+            .await
+            .unwrap()
+            .is_some());
+        let accepted: serde_json::Value =
+            test::call_and_read_body_json(&app, accept("nonce")).await;
+        assert_eq!(accepted["role"], "admin");
+        let response = test::call_service(&app, accept("nonce")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            state
+                .persist
+                .lock()
+                .await
+                .get_role(org_id, &users[1].user_id)
+                .await
+                .unwrap(),
+            Some(Role::Admin)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_leave_organizations() {
+        let state = AppState::in_memory();
+        let users = add_users(&state, &["x.x@x.x", "y.y@y.y"]).await;
+        let mut persist = state.persist.lock().await;
+        let alone = Organization::new("alone".to_string());
+        let shared = Organization::new("shared".to_string());
+        for organization in [&alone, &shared] {
+            persist
+                .add_organization(organization, &users[0].user_id)
+                .await
+                .unwrap();
+        }
+        persist
+            .set_member(&shared.org_id, &users[1].user_id, Role::Viewer)
+            .await
+            .unwrap();
+
+        assert!(leave_organizations(&mut persist, &users[0].user_id)
+            .await
+            .is_err());
+        assert!(persist
+            .get_organization(&alone.org_id)
+            .await
+            .unwrap()
+            .is_some());
+
+        persist
+            .set_member(&shared.org_id, &users[1].user_id, Role::Owner)
+            .await
+            .unwrap();
+        leave_organizations(&mut persist, &users[0].user_id)
+            .await
+            .unwrap();
+        assert!(persist
+            .get_organization(&alone.org_id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(persist
+            .get_organization(&shared.org_id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
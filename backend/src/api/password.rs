@@ -0,0 +1,251 @@
+use crate::api::rate_limit::check_rate_limit;
+use crate::api::{client_ip, AppState};
+use crate::errors::APIError;
+use crate::model::user::{Nonce, User};
+use crate::persistence::{PersistPasswordReset, PersistSession, PersistUser, Persistence};
+use crate::service::authentication::hash_password;
+use crate::service::email::{generate_password_reset_mail, send_mail};
+use crate::service::token::random_alphanumeric_string;
+use actix_web::web::Data;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use lettre::message::Mailbox;
+use log::{error, info};
+use reqwest::Url;
+use serde::Deserialize;
+use validator::Validate;
+
+const NONCE_LENGTH: u32 = 40;
+const RATE_LIMIT_WINDOW: i64 = 60 * 60;
+const MAX_REQUESTS_PER_EMAIL: u64 = 3;
+const MAX_REQUESTS_PER_IP: u64 = 20;
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ForgotPasswordRequest {
+    #[validate(email)]
+    email: String,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ResetPasswordRequest {
+    #[validate(length(min = 8, max = 64))]
+    password: String,
+}
+
+/// Mails a single-use reset link if an account with the email exists. The
+/// response is the same either way, so it doesn't reveal registered emails.
+#[post("/password/forgot")]
+pub async fn forgot_password(
+    request: HttpRequest,
+    forgot_request: web::Json<ForgotPasswordRequest>,
+    state: Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let forgot_request = forgot_request.into_inner();
+    if let Err(e) = forgot_request.validate() {
+        return Err(APIError::BadRequest(format!("{e}")));
+    }
+    let email = forgot_request.email;
+
+    let mut persist = state.persist.lock().await;
+    let ip = client_ip(&request);
+    check_rate_limit(
+        &mut persist,
+        &format!("password_forgot_ip:{ip}"),
+        MAX_REQUESTS_PER_IP,
+        RATE_LIMIT_WINDOW,
+    )
+    .await?;
+    check_rate_limit(
+        &mut persist,
+        &format!("password_forgot_email:{}", email.to_lowercase()),
+        MAX_REQUESTS_PER_EMAIL,
+        RATE_LIMIT_WINDOW,
+    )
+    .await?;
+
+    let user = persist.get_user_by_email(&email).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    let Some(user) = user else {
+        info!("password reset requested for unknown email");
+        return Ok(HttpResponse::Accepted().finish());
+    };
+    send_password_reset(&mut persist, &state.frontend_url, &user).await?;
+    info!("password reset requested for {}", user.user_id);
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Stores a reset nonce for the user and mails the link to the frontend.
+pub(crate) async fn send_password_reset(
+    persist: &mut Persistence,
+    frontend_url: &Url,
+    user: &User,
+) -> Result<(), APIError> {
+    let nonce = random_alphanumeric_string(NONCE_LENGTH);
+    persist
+        .add_password_reset(&user.user_id, &nonce)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    let reset_link = frontend_url
+        .join(&format!("password/reset/{nonce}"))
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    let receiver: Mailbox = user.email.parse().map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    // sent in the background so that the response time doesn't depend on the email
+    let mail = generate_password_reset_mail(&reset_link);
+    tokio::task::spawn(async move {
+        if let Err(e) = send_mail(mail, receiver).await {
+            error!("failed sending password reset mail: {e}");
+        }
+    });
+    Ok(())
+}
+
+/// Sets a new password with the nonce of a reset link and logs out all sessions.
+/// The nonce is invalid afterwards.
+#[post("/password/reset/{nonce}")]
+pub async fn reset_password(
+    request: HttpRequest,
+    nonce: web::Path<Nonce>,
+    reset_request: web::Json<ResetPasswordRequest>,
+    state: Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let reset_request = reset_request.into_inner();
+    if let Err(e) = reset_request.validate() {
+        return Err(APIError::BadRequest(format!("{e}")));
+    }
+
+    let mut persist = state.persist.lock().await;
+    let ip = client_ip(&request);
+    check_rate_limit(
+        &mut persist,
+        &format!("password_reset_ip:{ip}"),
+        MAX_REQUESTS_PER_IP,
+        RATE_LIMIT_WINDOW,
+    )
+    .await?;
+
+    let user_id = persist
+        .take_password_reset(&nonce)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?
+        .ok_or_else(|| {
+            info!("unknown or expired password reset nonce");
+            APIError::NotFound
+        })?;
+    let mut user = persist.get_user_by_id(&user_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    user.password_hash = hash_password(&reset_request.password);
+    persist.update_user(&user).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    persist.delete_sessions(&user_id, None).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    info!("password reset of {user_id}");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::authentication::login;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::Key;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_reset_password() {
+        let state = Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        let nonce: Nonce = "nonce".to_string();
+        {
+            let mut persist = state.persist.lock().await;
+            persist.add_user(&user).await.unwrap();
+            persist
+                .add_password_reset(&user.user_id, &nonce)
+                .await
+                .unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(reset_password),
+        )
+        .await;
+        let reset = |password: &str| {
+            test::TestRequest::post()
+                .uri("/password/reset/nonce")
+                .set_json(json!({"password": password}))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, reset("short")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&app, reset("new-password")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test::call_service(&app, reset("other-password")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        for (password, status) in [
+            ("asdfasdfasdf", StatusCode::UNAUTHORIZED),
+            ("new-password", StatusCode::OK),
+        ] {
+            let request = test::TestRequest::post()
+                .uri("/login")
+                .set_json(json!({"email": "x.x@x.x", "password": password}))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_forgot_password_rate_limited() {
+        let state = Data::new(AppState::in_memory());
+        let app =
+            test::init_service(App::new().app_data(state.clone()).service(forgot_password)).await;
+
+        for _ in 0..MAX_REQUESTS_PER_EMAIL {
+            let request = test::TestRequest::post()
+                .uri("/password/forgot")
+                .set_json(json!({"email": "unknown@x.x"}))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+        // case variants of the email share its limit
+        let request = test::TestRequest::post()
+            .uri("/password/forgot")
+            .set_json(json!({"email": "Unknown@X.x"}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("retry-after"));
+    }
+}
@@ -0,0 +1,298 @@
+use crate::api::{client_ip, AppState};
+use crate::errors::APIError;
+use crate::persistence::{PersistRateLimit, Persistence};
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Data;
+use actix_web::ResponseError;
+use chrono::Utc;
+use futures_util::future::LocalBoxFuture;
+use log::{error, info};
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Mutex;
+
+/// A fixed window limit of requests, per client IP with the [`RateLimiter`]
+/// middleware.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimit {
+    /// Prefix of the counter keys.
+    pub(crate) name: &'static str,
+    pub(crate) max_requests: u64,
+    pub(crate) window_seconds: i64,
+}
+
+pub(crate) const REGISTER_RATE_LIMIT: RateLimit = RateLimit {
+    name: "register",
+    max_requests: 10,
+    window_seconds: 60 * 60,
+};
+
+pub(crate) const MESSAGES_RATE_LIMIT: RateLimit = RateLimit {
+    name: "messages",
+    max_requests: 6000,
+    window_seconds: 60,
+};
+
+/// A [`RateLimit`] counted in memory, for hot paths like ingestion that must not
+/// wait for the persistence lock. Each replica counts on its own. Callers count
+/// units like messages, `max_requests` then limits those.
+#[derive(Debug)]
+pub(crate) struct LocalRateLimiter {
+    limit: RateLimit,
+    /// The current window and the requests per key in it.
+    counts: Mutex<(i64, HashMap<String, u64>)>,
+}
+
+impl LocalRateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            counts: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Counts `count` units for `key` and fails once there were more than
+    /// allowed in the current window.
+    pub(crate) fn check(&self, key: &str, count: u64) -> Result<(), APIError> {
+        let now = Utc::now().timestamp();
+        let window = now / self.limit.window_seconds;
+        let mut counts = self.counts.lock().unwrap();
+        if counts.0 != window {
+            *counts = (window, HashMap::new());
+        }
+        let counted = counts.1.entry(key.to_string()).or_default();
+        *counted += count;
+        if *counted > self.limit.max_requests {
+            info!("rate limited {}:{key}", self.limit.name);
+            let retry_after = (window + 1) * self.limit.window_seconds - now;
+            return Err(APIError::TooManyRequests(retry_after as u64));
+        }
+        Ok(())
+    }
+}
+
+/// Exponential backoff for failed attempts, e.g. logins. After `free_failures`
+/// each failure blocks the key for twice as long as the previous one, starting
+/// at one second. Reaching `lockout_failures` locks it for `lockout_seconds`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Backoff {
+    pub(crate) free_failures: u64,
+    pub(crate) lockout_failures: u64,
+    pub(crate) lockout_seconds: i64,
+    /// Failures are counted in fixed windows of this length.
+    pub(crate) window_seconds: i64,
+}
+
+impl Backoff {
+    fn delay(&self, failures: u64) -> Option<i64> {
+        if failures >= self.lockout_failures {
+            Some(self.lockout_seconds)
+        } else if failures > self.free_failures {
+            let exponent = (failures - self.free_failures - 1).min(32);
+            Some((1i64 << exponent).min(self.lockout_seconds))
+        } else {
+            None
+        }
+    }
+}
+
+/// Counts an attempt for `key` and fails once there were more than `max` in the
+/// current window.
+pub(crate) async fn check_rate_limit(
+    persist: &mut Persistence,
+    key: &str,
+    max: u64,
+    window_seconds: i64,
+) -> Result<(), APIError> {
+    let attempts = persist
+        .count_attempt(key, window_seconds)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    if attempts > max {
+        info!("rate limited {key}");
+        return Err(APIError::TooManyRequests(window_seconds as u64));
+    }
+    Ok(())
+}
+
+/// Fails while `key` is blocked by [`record_failure`].
+pub(crate) async fn check_blocked(persist: &mut Persistence, key: &str) -> Result<(), APIError> {
+    let blocked_for = persist.blocked_for(key).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    match blocked_for {
+        Some(seconds) => {
+            info!("{key} blocked for {seconds}s");
+            Err(APIError::TooManyRequests(seconds as u64))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Counts a failure for `key` and blocks it according to `backoff`. Returns
+/// true if this failure locked the key.
+pub(crate) async fn record_failure(
+    persist: &mut Persistence,
+    key: &str,
+    backoff: &Backoff,
+) -> Result<bool, APIError> {
+    let failures = persist
+        .count_attempt(key, backoff.window_seconds)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    if let Some(seconds) = backoff.delay(failures) {
+        persist.block(key, seconds).await.map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    }
+    Ok(failures == backoff.lockout_failures)
+}
+
+/// Forgets the failures of `key`, e.g. after a successful login.
+pub(crate) async fn reset_failures(persist: &mut Persistence, key: &str) -> Result<(), APIError> {
+    persist.reset_attempts(key).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })
+}
+
+/// Middleware applying a [`RateLimit`] per client IP, answering 429 with a
+/// `Retry-After` header once it is exceeded. Use it on handlers with
+/// `wrap = "RateLimiter(REGISTER_RATE_LIMIT)"`.
+pub(crate) struct RateLimiter(pub(crate) RateLimit);
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            limit: self.0,
+        }))
+    }
+}
+
+pub(crate) struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    limit: RateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let limit = self.limit;
+        Box::pin(async move {
+            let checked = match req.app_data::<Data<AppState>>() {
+                Some(state) => {
+                    let key = format!("{}:{}", limit.name, client_ip(req.request()));
+                    let mut persist = state.persist.lock().await;
+                    check_rate_limit(&mut persist, &key, limit.max_requests, limit.window_seconds)
+                        .await
+                }
+                None => {
+                    error!("rate limit {} without app state", limit.name);
+                    Err(APIError::InternalServerError)
+                }
+            };
+            match checked {
+                Ok(()) => Ok(service.call(req).await?.map_into_left_body()),
+                Err(e) => Ok(req.into_response(e.error_response()).map_into_right_body()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    // only the module, so that `#[test]` stays the standard attribute
+    use actix_web::test::{self};
+    use actix_web::{web, App, HttpResponse};
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            free_failures: 2,
+            lockout_failures: 6,
+            lockout_seconds: 600,
+            window_seconds: 3600,
+        };
+        let delays: Vec<Option<i64>> = (1..=7).map(|failures| backoff.delay(failures)).collect();
+        assert_eq!(
+            delays,
+            [None, None, Some(1), Some(2), Some(4), Some(600), Some(600)]
+        );
+    }
+
+    #[test]
+    fn test_local_rate_limiter() {
+        let limiter = LocalRateLimiter::new(RateLimit {
+            name: "test",
+            max_requests: 2,
+            window_seconds: 60,
+        });
+        assert!(limiter.check("a", 1).is_ok());
+        assert!(limiter.check("a", 1).is_ok());
+        assert!(matches!(
+            limiter.check("a", 1),
+            Err(APIError::TooManyRequests(retry_after)) if (1..=60).contains(&retry_after)
+        ));
+        assert!(limiter.check("b", 2).is_ok());
+        assert!(limiter.check("c", 3).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limiter() {
+        let limit = RateLimit {
+            name: "test",
+            max_requests: 2,
+            window_seconds: 60,
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(AppState::in_memory()))
+                .wrap(RateLimiter(limit))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for status in [
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let request = test::TestRequest::get().uri("/").to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status);
+        }
+    }
+}
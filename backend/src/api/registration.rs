@@ -1,3 +1,4 @@
+use crate::api::rate_limit::{RateLimiter, REGISTER_RATE_LIMIT};
 use crate::api::AppState;
 use validator::Validate;
 
@@ -10,7 +11,8 @@ use serde::Deserialize;
 
 use crate::errors::APIError::{BadRequest, InternalServerError};
 use crate::model::user::{Nonce, User};
-use crate::service::email::{generate_registration_mail, send_registration_mail};
+use crate::persistence::PersistPendingUser;
+use crate::service::email::{generate_registration_mail, send_mail};
 use crate::service::token::random_alphanumeric_string;
 use actix_web::get;
 use lettre::message::Mailbox;
@@ -25,7 +27,7 @@ pub struct RegistrationRequest {
     pub(crate) password: String,
 }
 
-#[post("/register")]
+#[post("/register", wrap = "RateLimiter(REGISTER_RATE_LIMIT)")]
 pub async fn register(
     register_request: web::Json<RegistrationRequest>,
     state: Data<AppState>,
@@ -50,7 +52,7 @@ pub async fn register(
         error!("failed adding pending user {}", e);
     }
 
-    if let Err(e) = send_registration_mail(mail, receiver).await {
+    if let Err(e) = send_mail(mail, receiver).await {
         error!("{e}");
         return Err(InternalServerError);
     }
@@ -0,0 +1,158 @@
+use crate::api::authentication::session_id;
+use crate::api::authorization::AuthenticatedUser;
+use crate::api::AppState;
+use crate::errors::APIError;
+use crate::model::user::{SessionID, SessionInfo};
+use crate::persistence::PersistSession;
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{delete, get, web, HttpResponse, Responder};
+use log::{error, info};
+use serde::Serialize;
+use std::cmp::Reverse;
+
+#[derive(Debug, Serialize)]
+struct SessionResponse {
+    #[serde(flatten)]
+    session: SessionInfo,
+    current: bool,
+}
+
+/// Lists the logins of the user, newest first.
+#[get("/sessions")]
+pub(crate) async fn get_sessions(
+    user: AuthenticatedUser,
+    session: Session,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let user_id = user.user_id;
+    let current = session_id(&session);
+    let mut sessions = state
+        .persist
+        .lock()
+        .await
+        .get_sessions(&user_id)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    sessions.sort_by_key(|session| Reverse(session.created_at));
+    let response: Vec<SessionResponse> = sessions
+        .into_iter()
+        .map(|session| SessionResponse {
+            current: Some(&session.session_id) == current.as_ref(),
+            session,
+        })
+        .collect();
+    Ok(web::Json(response))
+}
+
+/// Revokes a login of the user. It is logged out on its next request.
+#[delete("/sessions/{session_id}")]
+pub(crate) async fn delete_session(
+    user: AuthenticatedUser,
+    id: Identity,
+    session: Session,
+    path: web::Path<SessionID>,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, APIError> {
+    let user_id = user.user_id;
+    let revoked = path.into_inner();
+    let deleted = state
+        .persist
+        .lock()
+        .await
+        .delete_session(&user_id, &revoked)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    if !deleted {
+        return Err(APIError::NotFound);
+    }
+    info!("revoked a session of {user_id}");
+    if session_id(&session).as_ref() == Some(&revoked) {
+        id.logout();
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::authentication::{login, validate_session};
+    use crate::api::login_cookie;
+    use crate::api::users::get_user_by_id;
+    use crate::model::user::User;
+    use crate::persistence::PersistUser;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::Key;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn test_list_and_revoke_sessions() {
+        let state = web::Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&user).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(validate_session))
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(get_user_by_id)
+                .service(get_sessions)
+                .service(delete_session),
+        )
+        .await;
+        let other_cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+
+        let request = test::TestRequest::get()
+            .uri("/sessions")
+            .cookie(cookie.clone())
+            .to_request();
+        let sessions: Vec<serde_json::Value> = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(sessions.len(), 2);
+        let other = sessions
+            .iter()
+            .find(|session| session["current"] == false)
+            .unwrap();
+        let other_id = other["session_id"].as_str().unwrap();
+
+        let request = test::TestRequest::delete()
+            .uri(&format!("/sessions/{other_id}"))
+            .cookie(cookie.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let request = test::TestRequest::delete()
+            .uri(&format!("/sessions/{other_id}"))
+            .cookie(cookie.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        for (cookie, status) in [
+            (cookie, StatusCode::OK),
+            (other_cookie, StatusCode::UNAUTHORIZED),
+        ] {
+            let request = test::TestRequest::get()
+                .uri("/user")
+                .cookie(cookie)
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status);
+        }
+    }
+}
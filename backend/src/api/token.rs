@@ -1,46 +1,535 @@
+use crate::api::authorization::OrgMember;
 use crate::errors::APIError;
-use crate::model::message::MessageToken;
-use crate::model::user::UserID;
-use crate::persistence::token::TokenState;
-use actix_identity::Identity;
-use actix_web::{delete, get, post, web, Responder};
-use log::info;
+use crate::model::message::{MessageToken, MAX_HOSTNAME_LENGTH};
+use crate::model::organization::{OrgID, Role};
+use crate::model::token::{TokenHash, TokenHost, TokenID, TokenInfo, TokenMetadata, TokenScope};
+use crate::persistence::token::{TokenState, TokenStore};
+use crate::persistence::Persistence;
+use crate::service::authentication::constant_time_eq;
+use crate::service::token::{hash_token, tokens_of_org, TokenError};
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+const MAX_TOKEN_HOSTNAMES: usize = 100;
+const DEFAULT_ROTATION_GRACE_HOURS: i64 = 24;
+
+fn default_scopes() -> Vec<TokenScope> {
+    vec![TokenScope::Ingest]
+}
+
+#[derive(Deserialize, Debug, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct CreateTokenRequest {
+    #[serde(default)]
+    #[validate(length(max = 64))]
+    name: String,
+    /// Days until the token expires, it never expires if absent.
+    #[validate(range(min = 1, max = 3650))]
+    expires_in_days: Option<i64>,
+    #[serde(default = "default_scopes")]
+    #[validate(length(min = 1))]
+    scopes: Vec<TokenScope>,
+    /// Restricts the token to these hostnames.
+    #[serde(default)]
+    hostnames: Vec<String>,
+}
+
+impl Default for CreateTokenRequest {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            expires_in_days: None,
+            scopes: default_scopes(),
+            hostnames: Vec::new(),
+        }
+    }
+}
+
+fn default_grace_hours() -> i64 {
+    DEFAULT_ROTATION_GRACE_HOURS
+}
+
+#[derive(Deserialize, Debug, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct RotateTokenRequest {
+    /// Hours the rotated token stays valid next to its successor.
+    #[serde(default = "default_grace_hours")]
+    #[validate(range(min = 0, max = 720))]
+    grace_hours: i64,
+}
+
+impl Default for RotateTokenRequest {
+    fn default() -> Self {
+        Self {
+            grace_hours: default_grace_hours(),
+        }
+    }
+}
+
+/// Which hosts still have to switch to the successor of a rotated token.
+#[derive(Serialize, Debug)]
+struct RotationReport {
+    token_id: TokenID,
+    successor: TokenID,
+    rotated_at: DateTime<Utc>,
+    /// The end of the grace window.
+    expires_at: Option<DateTime<Utc>>,
+    hosts_still_using: Vec<TokenHost>,
+}
+
+impl RotationReport {
+    fn new(info: &TokenInfo) -> Option<Self> {
+        let rotation = info.rotation.as_ref()?;
+        Some(Self {
+            token_id: info.token_id.clone(),
+            successor: rotation.successor.clone(),
+            rotated_at: rotation.rotated_at,
+            expires_at: info.metadata.expires_at,
+            hosts_still_using: info.hosts_since_rotation(),
+        })
+    }
+}
+
+/// Also contains the successor itself, see [`CreateTokenResponse`].
+#[derive(Serialize, Debug)]
+struct RotateTokenResponse {
+    token: MessageToken,
+    #[serde(flatten)]
+    info: TokenInfo,
+    /// The rotated token with all hosts that used it so far.
+    previous: TokenInfo,
+}
+
+/// The only response containing the token itself.
+#[derive(Serialize, Debug)]
+struct CreateTokenResponse {
+    token: MessageToken,
+    #[serde(flatten)]
+    info: TokenInfo,
+}
+
+/// Parses an optional JSON body, only an empty one falls back to the defaults.
+fn optional_json<T: DeserializeOwned + Default>(body: &[u8]) -> Result<T, APIError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body).map_err(|e| APIError::BadRequest(e.to_string()))
+}
+
+/// Maps failed token checks of message requests to responses.
+pub(crate) fn token_error(e: TokenError) -> APIError {
+    match e {
+        TokenError::Unknown | TokenError::Expired => {
+            info!("{e}");
+            APIError::Unauthorized
+        }
+        TokenError::MissingScope(_) | TokenError::Hostname(_) => {
+            info!("{e}");
+            APIError::Forbidden(e.to_string())
+        }
+        TokenError::Persistence(e) => {
+            error!("{e}");
+            APIError::InternalServerError
+        }
+    }
+}
 
 #[post("/token")]
 pub(crate) async fn create_token(
-    id: Identity,
+    member: OrgMember,
     token_state: web::Data<TokenState>,
+    body: web::Bytes,
 ) -> Result<impl Responder, APIError> {
     info!("generate new token request");
-    let user_id: UserID = id.id().unwrap().into();
+    let request: CreateTokenRequest = optional_json(&body)?;
+    if let Err(e) = request.validate() {
+        return Err(APIError::BadRequest(format!("{e}")));
+    }
+    if request.hostnames.len() > MAX_TOKEN_HOSTNAMES
+        || request
+            .hostnames
+            .iter()
+            .any(|hostname| hostname.is_empty() || hostname.len() > MAX_HOSTNAME_LENGTH)
+    {
+        return Err(APIError::BadRequest(format!(
+            "at most {MAX_TOKEN_HOSTNAMES} hostnames of 1 to {MAX_HOSTNAME_LENGTH} characters"
+        )));
+    }
+
+    member.require(Role::Admin)?;
+    let org_id = member.org_id;
     let mut tokens = token_state.token.lock().await;
-    let token = tokens.create_token_for_user_id(&user_id).await;
-    Ok(web::Json(token))
+    let now = Utc::now();
+    let metadata = TokenMetadata {
+        name: request.name,
+        created_at: Some(now),
+        expires_at: request
+            .expires_in_days
+            .map(|days| now + Duration::days(days)),
+        scopes: request.scopes,
+        hostnames: request.hostnames,
+    };
+    let token = tokens.create_token(&org_id, &metadata).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    let info = token_info(&mut tokens, &hash_token(&token)).await?;
+    Ok(web::Json(CreateTokenResponse { token, info }))
 }
 
+/// Lists the tokens of the organization, oldest first, without the tokens
+/// themselves.
 #[get("/token")]
 pub(crate) async fn get_token(
-    id: Identity,
+    member: OrgMember,
     token_state: web::Data<TokenState>,
 ) -> Result<impl Responder, APIError> {
     info!("get token request");
-    let user_id: UserID = id.id().unwrap().into();
+    let org_id = member.org_id;
     let mut tokens = token_state.token.lock().await;
-    if let Some(token) = tokens.get_token_of_user_id(&user_id).await {
-        Ok(web::Json(token))
-    } else {
-        Ok(web::Json(vec![]))
-    }
+    let mut infos = tokens_of_org(&mut tokens, &org_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    infos.sort_by_key(|info| info.metadata.created_at);
+    Ok(web::Json(infos))
+}
+
+async fn token_info(tokens: &mut Persistence, hash: &TokenHash) -> Result<TokenInfo, APIError> {
+    tokens
+        .get_token_info(hash)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?
+        .ok_or(APIError::NotFound)
+}
+
+/// Finds a token of the organization by its id or by the token itself.
+async fn find_token(
+    tokens: &mut Persistence,
+    org_id: &OrgID,
+    requested: &str,
+) -> Result<TokenInfo, APIError> {
+    let hash = hash_token(requested);
+    tokens_of_org(tokens, org_id)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?
+        .into_iter()
+        .find(|info| {
+            info.token_id == requested || constant_time_eq(info.hash.as_bytes(), hash.as_bytes())
+        })
+        .ok_or(APIError::NotFound)
 }
 
-#[delete("/token/{token}")]
+/// Deletes a token of the organization by its id. The token itself is accepted
+/// as well.
+#[delete("/token/{token_id}")]
 pub(crate) async fn delete_token(
-    path: web::Path<MessageToken>,
-    _id: Identity,
+    path: web::Path<TokenID>,
+    member: OrgMember,
     token_state: web::Data<TokenState>,
 ) -> Result<impl Responder, APIError> {
     info!("delete token request");
+    member.require(Role::Admin)?;
+    let OrgMember {
+        user_id, org_id, ..
+    } = member;
+    let mut tokens = token_state.token.lock().await;
+    let info = find_token(&mut tokens, &org_id, &path).await?;
+    tokens.delete_token(&info.hash).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    info!("{user_id} deleted token {} of {org_id}", info.token_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Replaces a token with a successor with the same settings. Both are accepted
+/// during the grace window, so hosts can switch one by one.
+#[post("/token/{token_id}/rotate")]
+pub(crate) async fn rotate_token(
+    path: web::Path<TokenID>,
+    member: OrgMember,
+    token_state: web::Data<TokenState>,
+    body: web::Bytes,
+) -> Result<impl Responder, APIError> {
+    info!("rotate token request");
+    let request: RotateTokenRequest = optional_json(&body)?;
+    if let Err(e) = request.validate() {
+        return Err(APIError::BadRequest(format!("{e}")));
+    }
+    member.require(Role::Admin)?;
+    let OrgMember {
+        user_id, org_id, ..
+    } = member;
     let mut tokens = token_state.token.lock().await;
-    tokens.delete_token(&path.into_inner()).await?;
-    Ok("success")
+    let info = find_token(&mut tokens, &org_id, &path).await?;
+    let now = Utc::now();
+    if info.rotation.is_some() {
+        return Err(APIError::BadRequest("token already rotated".to_string()));
+    }
+    if info.is_expired(now) {
+        return Err(APIError::BadRequest("token expired".to_string()));
+    }
+
+    let successor = tokens
+        .rotate_token(&info.hash, now, Duration::hours(request.grace_hours))
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    let previous = token_info(&mut tokens, &info.hash).await?;
+    let info = token_info(&mut tokens, &hash_token(&successor)).await?;
+    info!(
+        "{user_id} rotated token {} of {org_id} to {}",
+        previous.token_id, info.token_id
+    );
+    Ok(web::Json(RotateTokenResponse {
+        token: successor,
+        info,
+        previous,
+    }))
+}
+
+/// Reports the hosts that used a rotated token since the rotation.
+#[get("/token/{token_id}/rotation")]
+pub(crate) async fn get_token_rotation(
+    path: web::Path<TokenID>,
+    member: OrgMember,
+    token_state: web::Data<TokenState>,
+) -> Result<impl Responder, APIError> {
+    let org_id = member.org_id;
+    let mut tokens = token_state.token.lock().await;
+    let info = find_token(&mut tokens, &org_id, &path).await?;
+    RotationReport::new(&info)
+        .map(web::Json)
+        .ok_or(APIError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::authentication::login;
+    use crate::api::login_cookie;
+    use crate::api::AppState;
+    use crate::model::token::token_id;
+    use crate::model::user::User;
+    use crate::persistence::PersistUser;
+    use crate::service::token::record_token_hosts;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::Key;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_token_ownership() {
+        let state = web::Data::new(AppState::in_memory());
+        let persistence = state.persist.lock().await.clone();
+        let token_state = web::Data::new(TokenState::new(persistence));
+        for email in ["x.x@x.x", "y.y@y.y"] {
+            let user = User::new(email.to_string(), "asdfasdfasdf".to_string());
+            state.persist.lock().await.add_user(&user).await.unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .app_data(token_state.clone())
+                .service(login)
+                .service(create_token)
+                .service(get_token)
+                .service(delete_token),
+        )
+        .await;
+        let owner = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+        let other = login_cookie(&app, "y.y@y.y", "asdfasdfasdf").await;
+
+        let request = test::TestRequest::post()
+            .uri("/token")
+            .cookie(owner.clone())
+            .set_json(json!({
+                "name": "ci",
+                "expires_in_days": 30,
+                "scopes": ["ingest", "read"],
+                "hostnames": ["host"],
+            }))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let token = created["token"].as_str().unwrap();
+        let token_id = created["token_id"].as_str().unwrap();
+        assert!(token.starts_with(token_id));
+        assert_eq!(created["name"], "ci");
+        assert!(created["expires_at"].is_string());
+
+        let request = test::TestRequest::get()
+            .uri("/token")
+            .cookie(owner.clone())
+            .to_request();
+        let listed: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["token_id"], token_id);
+        assert_eq!(listed[0]["hostnames"], json!(["host"]));
+        assert!(!listed.to_string().contains(token));
+
+        let request = test::TestRequest::post()
+            .uri("/token")
+            .cookie(owner.clone())
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(created["scopes"], json!(["ingest"]));
+
+        for body in [
+            json!({"hostnames": "web-1"}),
+            json!({"scopes": ["everything"]}),
+            json!({"expires_at": "tomorrow"}),
+        ] {
+            let request = test::TestRequest::post()
+                .uri("/token")
+                .cookie(owner.clone())
+                .set_json(body)
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        let request = test::TestRequest::post()
+            .uri("/token")
+            .cookie(owner.clone())
+            .set_payload("{\"name\": ")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let delete = |cookie: &actix_web::cookie::Cookie<'static>| {
+            test::TestRequest::delete()
+                .uri(&format!("/token/{token_id}"))
+                .cookie(cookie.clone())
+                .to_request()
+        };
+        let response = test::call_service(&app, delete(&other)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = test::call_service(&app, delete(&owner)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test::call_service(&app, delete(&owner)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_token_rotation() {
+        let state = web::Data::new(AppState::in_memory());
+        let persistence = state.persist.lock().await.clone();
+        let token_state = web::Data::new(TokenState::new(persistence));
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&user).await.unwrap();
+        let mut tokens = token_state.token.lock().await;
+        let token = tokens
+            .create_token_for_org(&OrgID::personal(&user.user_id))
+            .await
+            .unwrap();
+        let info = tokens
+            .get_token_info(&hash_token(&token))
+            .await
+            .unwrap()
+            .unwrap();
+        record_token_hosts(&mut tokens, &info, ["a", "b"]).await;
+        drop(tokens);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .app_data(token_state.clone())
+                .service(login)
+                .service(rotate_token)
+                .service(get_token_rotation),
+        )
+        .await;
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+        let rotate = || {
+            test::TestRequest::post()
+                .uri(&format!("/token/{}/rotate", token_id(&token)))
+                .cookie(cookie.clone())
+                .set_json(json!({"grace_hours": 48}))
+                .to_request()
+        };
+        let rotated: serde_json::Value = test::call_and_read_body_json(&app, rotate()).await;
+        let successor = rotated["token"].as_str().unwrap().to_string();
+        assert_eq!(
+            rotated["previous"]["rotation"]["successor"],
+            token_id(&successor)
+        );
+        assert_eq!(rotated["previous"]["hosts"].as_array().unwrap().len(), 2);
+        assert!(rotated["previous"]["expires_at"].is_string());
+        let response = test::call_service(&app, rotate()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // both tokens are valid during the grace window
+        let mut tokens = token_state.token.lock().await;
+        for token in [&token, &successor] {
+            assert_eq!(
+                tokens.get_org_of_token(token).await.unwrap(),
+                Some(OrgID::personal(&user.user_id))
+            );
+        }
+        record_token_hosts(&mut tokens, &info, ["b"]).await;
+        drop(tokens);
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/token/{}/rotation", token_id(&token)))
+            .cookie(cookie.clone())
+            .to_request();
+        let report: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(report["successor"], token_id(&successor));
+        assert_eq!(report["hosts_still_using"].as_array().unwrap().len(), 1);
+        assert_eq!(report["hosts_still_using"][0]["hostname"], "b");
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/token/{}/rotation", token_id(&successor)))
+            .cookie(cookie.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        for body in [
+            json!({"grace_hours": -1}),
+            json!({"grace_hours": "48"}),
+            json!({"grace_hour": 48}),
+        ] {
+            let request = test::TestRequest::post()
+                .uri(&format!("/token/{}/rotate", token_id(&successor)))
+                .cookie(cookie.clone())
+                .set_json(body)
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // the body is optional
+        let request = test::TestRequest::post()
+            .uri(&format!("/token/{}/rotate", token_id(&successor)))
+            .cookie(cookie)
+            .to_request();
+        let rotated: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert!(rotated["token"].is_string());
+    }
 }
@@ -0,0 +1,332 @@
+use crate::api::authorization::AuthenticatedUser;
+use crate::api::users::verified_user;
+use crate::api::AppState;
+use crate::errors::APIError;
+use crate::model::user::{TwoFactor, UserID};
+use crate::persistence::{PersistTwoFactor, Persistence};
+use crate::service::totp;
+use actix_web::{delete, post, web, HttpResponse, Responder};
+use chrono::Utc;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Debug)]
+pub struct EnrollTwoFactorRequest {
+    current_password: String,
+}
+
+#[derive(Serialize, Debug)]
+struct EnrollTwoFactorResponse {
+    secret: String,
+    provisioning_uri: String,
+    /// The provisioning URI as SVG image.
+    qr_code: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TwoFactorCodeRequest {
+    code: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DisableTwoFactorRequest {
+    current_password: String,
+    code: String,
+}
+
+/// Recovery codes are only shown once, only their hashes are stored.
+#[derive(Serialize, Debug)]
+struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+async fn get_two_factor(
+    persist: &mut Persistence,
+    user_id: &UserID,
+) -> Result<Option<TwoFactor>, APIError> {
+    persist.get_two_factor(user_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })
+}
+
+async fn set_two_factor(
+    persist: &mut Persistence,
+    user_id: &UserID,
+    two_factor: &TwoFactor,
+) -> Result<(), APIError> {
+    persist
+        .set_two_factor(user_id, two_factor)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })
+}
+
+fn hash_recovery_codes(recovery_codes: &[String]) -> Vec<String> {
+    recovery_codes
+        .iter()
+        .map(|code| totp::hash_recovery_code(code))
+        .collect()
+}
+
+/// Checks a TOTP code or one of the recovery codes of a user with enabled
+/// two-factor authentication. Accepted codes can't be used again.
+pub(crate) async fn verify_second_factor(
+    persist: &mut Persistence,
+    user_id: &UserID,
+    code: &str,
+) -> Result<bool, APIError> {
+    let Some(mut two_factor) = get_two_factor(persist, user_id)
+        .await?
+        .filter(|two_factor| two_factor.enabled)
+    else {
+        return Ok(false);
+    };
+    let now = Utc::now().timestamp() as u64;
+    if let Some(step) = totp::verify(&two_factor.secret, code, now, two_factor.last_time_step) {
+        two_factor.last_time_step = Some(step);
+    } else if let Some(index) = totp::find_recovery_code(&two_factor.recovery_code_hashes, code) {
+        two_factor.recovery_code_hashes.remove(index);
+        info!(
+            "recovery code used by {user_id}, {} left",
+            two_factor.recovery_code_hashes.len()
+        );
+    } else {
+        info!("wrong second factor of {user_id}");
+        return Ok(false);
+    }
+    set_two_factor(persist, user_id, &two_factor).await?;
+    Ok(true)
+}
+
+/// Starts enrollment with a new secret. Logins only require a code once the
+/// enrollment is confirmed.
+#[post("/user/2fa")]
+pub(crate) async fn enroll_two_factor(
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+    request: web::Json<EnrollTwoFactorRequest>,
+) -> Result<impl Responder, APIError> {
+    let mut persist = state.persist.lock().await;
+    let user_id = user.user_id;
+    let user = verified_user(&mut persist, &user_id, &request.current_password).await?;
+    if get_two_factor(&mut persist, &user_id)
+        .await?
+        .is_some_and(|two_factor| two_factor.enabled)
+    {
+        return Err(APIError::BadRequest(
+            "two-factor authentication already enabled".to_string(),
+        ));
+    }
+
+    let two_factor = TwoFactor {
+        secret: totp::generate_secret(),
+        enabled: false,
+        recovery_code_hashes: Vec::new(),
+        last_time_step: None,
+    };
+    set_two_factor(&mut persist, &user_id, &two_factor).await?;
+    let provisioning_uri = totp::provisioning_uri(&two_factor.secret, &user.email);
+    let qr_code = totp::qr_code(&provisioning_uri).map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    info!("two-factor enrollment started by {user_id}");
+    Ok(web::Json(EnrollTwoFactorResponse {
+        secret: two_factor.secret,
+        provisioning_uri,
+        qr_code,
+    }))
+}
+
+/// Enables two-factor authentication with a code of the enrolled secret and
+/// returns the recovery codes.
+#[post("/user/2fa/confirm")]
+pub(crate) async fn confirm_two_factor(
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+    request: web::Json<TwoFactorCodeRequest>,
+) -> Result<impl Responder, APIError> {
+    let mut persist = state.persist.lock().await;
+    let user_id = user.user_id;
+    let mut two_factor = match get_two_factor(&mut persist, &user_id).await? {
+        None => return Err(APIError::BadRequest("no two-factor enrollment".to_string())),
+        Some(two_factor) if two_factor.enabled => {
+            return Err(APIError::BadRequest(
+                "two-factor authentication already enabled".to_string(),
+            ))
+        }
+        Some(two_factor) => two_factor,
+    };
+    let now = Utc::now().timestamp() as u64;
+    let Some(step) = totp::verify(&two_factor.secret, &request.code, now, None) else {
+        info!("wrong two-factor confirmation code of {user_id}");
+        return Err(APIError::Unauthorized);
+    };
+
+    let recovery_codes = totp::generate_recovery_codes();
+    two_factor.enabled = true;
+    two_factor.last_time_step = Some(step);
+    two_factor.recovery_code_hashes = hash_recovery_codes(&recovery_codes);
+    set_two_factor(&mut persist, &user_id, &two_factor).await?;
+    info!("two-factor authentication enabled by {user_id}");
+    Ok(web::Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+#[delete("/user/2fa")]
+pub(crate) async fn disable_two_factor(
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+    request: web::Json<DisableTwoFactorRequest>,
+) -> Result<impl Responder, APIError> {
+    let mut persist = state.persist.lock().await;
+    let user_id = user.user_id;
+    verified_user(&mut persist, &user_id, &request.current_password).await?;
+    if !verify_second_factor(&mut persist, &user_id, &request.code).await? {
+        return Err(APIError::Unauthorized);
+    }
+    persist.delete_two_factor(&user_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    info!("two-factor authentication disabled by {user_id}");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Replaces all recovery codes, e.g. when they run out.
+#[post("/user/2fa/recovery_codes")]
+pub(crate) async fn regenerate_recovery_codes(
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+    request: web::Json<TwoFactorCodeRequest>,
+) -> Result<impl Responder, APIError> {
+    let mut persist = state.persist.lock().await;
+    let user_id = user.user_id;
+    if !verify_second_factor(&mut persist, &user_id, &request.code).await? {
+        return Err(APIError::Unauthorized);
+    }
+    let Some(mut two_factor) = get_two_factor(&mut persist, &user_id).await? else {
+        return Err(APIError::InternalServerError);
+    };
+    let recovery_codes = totp::generate_recovery_codes();
+    two_factor.recovery_code_hashes = hash_recovery_codes(&recovery_codes);
+    set_two_factor(&mut persist, &user_id, &two_factor).await?;
+    info!("recovery codes regenerated by {user_id}");
+    Ok(web::Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::authentication::{login, login_two_factor};
+    use crate::api::login_cookie;
+    use crate::api::users::get_user_by_id;
+    use crate::model::user::User;
+    use crate::persistence::PersistUser;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::{Cookie, Key};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_two_factor_login() {
+        let state = web::Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&user).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(login_two_factor)
+                .service(get_user_by_id)
+                .service(enroll_two_factor)
+                .service(confirm_two_factor)
+                .service(disable_two_factor),
+        )
+        .await;
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+
+        let request = test::TestRequest::post()
+            .uri("/user/2fa")
+            .cookie(cookie.clone())
+            .set_json(json!({"current_password": "asdfasdfasdf"}))
+            .to_request();
+        let enrollment: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let secret = enrollment["secret"].as_str().unwrap().to_string();
+        assert!(enrollment["provisioning_uri"]
+            .as_str()
+            .unwrap()
+            .starts_with("otpauth://totp/"));
+
+        let now = Utc::now().timestamp() as u64;
+        let request = test::TestRequest::post()
+            .uri("/user/2fa/confirm")
+            .cookie(cookie.clone())
+            .set_json(json!({"code": totp::code_at(&secret, now)}))
+            .to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let recovery_codes = response["recovery_codes"].as_array().unwrap();
+        assert_eq!(recovery_codes.len(), 10);
+        let recovery_code = recovery_codes[0].as_str().unwrap().to_string();
+
+        // the password alone only starts the login
+        let request = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({"email": "x.x@x.x", "password": "asdfasdfasdf"}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let pending: Cookie = response.response().cookies().next().unwrap().into_owned();
+        let request = test::TestRequest::get()
+            .uri("/user")
+            .cookie(pending.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let second_factor = |code: &str| {
+            test::TestRequest::post()
+                .uri("/login/2fa")
+                .cookie(pending.clone())
+                .set_json(json!({"code": code}))
+                .to_request()
+        };
+        // codes are single-use
+        let response = test::call_service(&app, second_factor(&totp::code_at(&secret, now))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test::call_service(&app, second_factor(&recovery_code)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let logged_in: Cookie = response.response().cookies().next().unwrap().into_owned();
+        let response = test::call_service(&app, second_factor(&recovery_code)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::get()
+            .uri("/user")
+            .cookie(logged_in.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = test::TestRequest::delete()
+            .uri("/user/2fa")
+            .cookie(logged_in)
+            .set_json(json!({
+                "current_password": "asdfasdfasdf",
+                "code": totp::code_at(&secret, now + 30),
+            }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+    }
+}
@@ -1,73 +1,517 @@
+use crate::api::authentication::session_id;
+use crate::api::authorization::{AdminUser, AuthenticatedUser};
+use crate::api::organizations::leave_organizations;
 use crate::api::AppState;
 use crate::errors::APIError;
-use crate::model::user::{User, UserID};
-use crate::service::authentication::hash_password;
+use crate::model::user::{Nonce, User, UserID, UserRole};
+use crate::persistence::{PersistEmailChange, PersistSession, PersistUser, Persistence};
+use crate::service::authentication::{hash_password, valid_hash};
+use crate::service::email::{generate_email_change_mail, send_mail};
+use crate::service::token::random_alphanumeric_string;
 use crate::{Deserialize, Serialize};
 use actix_identity::Identity;
-use actix_web::{delete, get, post, web, Responder};
-use log::info;
+use actix_session::Session;
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use lettre::message::Mailbox;
+use log::{error, info};
+use validator::Validate;
+
+const NONCE_LENGTH: u32 = 40;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct UserResponse {
     pub(crate) email: String,
+    pub(crate) role: UserRole,
 }
 
 impl From<User> for UserResponse {
     fn from(user: User) -> Self {
-        UserResponse { email: user.email }
+        UserResponse {
+            email: user.email,
+            role: user.role,
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Validate)]
 pub struct AddUserRequest {
+    #[validate(email)]
     pub(crate) email: String,
+    #[validate(length(min = 8, max = 64))]
     pub(crate) password: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct DeleteUserRequest {
+    password: String,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    #[validate(length(min = 8, max = 64))]
+    new_password: String,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ChangeEmailRequest {
+    current_password: String,
+    #[validate(email)]
+    new_email: String,
+}
+
+/// Loads the user and checks the password, for changes that need re-authentication.
+pub(crate) async fn verified_user(
+    users: &mut Persistence,
+    user_id: &UserID,
+    password: &str,
+) -> Result<User, APIError> {
+    let user = users.get_user_by_id(user_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    if !valid_hash(&user.password_hash, password) {
+        info!("wrong password of {user_id}");
+        return Err(APIError::Unauthorized);
+    }
+    Ok(user)
+}
+
 #[get("/user")]
 pub async fn get_user_by_id(
-    id: Identity,
+    user: AuthenticatedUser,
     state: web::Data<AppState>,
 ) -> Result<impl Responder, APIError> {
-    let user_id: UserID = id.id().unwrap().into();
+    let user_id = user.user_id;
     let mut users = state.persist.lock().await;
-    let user = users.get_user_by_id(&user_id).await;
+    let user = users.get_user_by_id(&user_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
     let response = UserResponse::from(user);
     Ok(web::Json(response))
 }
 
+/// Creates an activated user without the registration mail, for admins.
 #[post("/user")]
 pub(crate) async fn add_user(
+    admin: AdminUser,
     state: web::Data<AppState>,
     user: web::Json<AddUserRequest>,
 ) -> Result<impl Responder, APIError> {
-    info!("add user");
-
-    let new_user = User::new(user.email.clone(), hash_password(&user.password));
-
+    let user = user.into_inner();
+    if let Err(e) = user.validate() {
+        return Err(APIError::BadRequest(format!("{e}")));
+    }
     let mut users = state.persist.lock().await;
-    let added_user = users.add_user(&new_user).await;
-    Ok(web::Json(added_user))
+    let existing = users.get_user_by_email(&user.email).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    if existing.is_some() {
+        return Err(APIError::BadRequest("email already in use".to_string()));
+    }
+    let new_user = User::new(user.email, user.password);
+    users.add_user(&new_user).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    info!("{admin} added user {}", new_user.user_id);
+    Ok(HttpResponse::Created().json(UserResponse::from(new_user)))
 }
 
+/// Deletes the account and all data of the logged-in user after confirming the
+/// password, and ends all its sessions. Organizations only the user is a member of
+/// are deleted as well, the last owner of a shared one has to hand it over first.
 #[delete("/user")]
 pub(crate) async fn delete_user(
+    user: AuthenticatedUser,
     id: Identity,
     state: web::Data<AppState>,
+    request: web::Json<DeleteUserRequest>,
+) -> Result<impl Responder, APIError> {
+    let mut users = state.persist.lock().await;
+    let user_id = user.user_id;
+    verified_user(&mut users, &user_id, &request.password).await?;
+    leave_organizations(&mut users, &user_id).await?;
+    users.delete_user(&user_id).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    id.logout();
+    info!("deleted account {user_id}");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Sets a new password and logs out all other sessions of the user.
+#[post("/user/password")]
+pub(crate) async fn change_password(
+    user: AuthenticatedUser,
+    session: Session,
+    state: web::Data<AppState>,
+    request: web::Json<ChangePasswordRequest>,
 ) -> Result<impl Responder, APIError> {
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return Err(APIError::BadRequest(format!("{e}")));
+    }
     let mut users = state.persist.lock().await;
-    let user_id: UserID = id.id().unwrap().into();
-    let deleted_user = users.delete_user(&user_id).await;
-    Ok(web::Json(deleted_user))
+    let user_id = user.user_id;
+    let mut user = verified_user(&mut users, &user_id, &request.current_password).await?;
+    user.password_hash = hash_password(&request.new_password);
+    users.update_user(&user).await.map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    users
+        .delete_sessions(&user_id, session_id(&session).as_ref())
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    info!("changed password of {user_id}");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Mails a confirmation link to the new address. The email of the user changes
+/// once the link is opened.
+#[post("/user/email")]
+pub(crate) async fn change_email(
+    user: AuthenticatedUser,
+    state: web::Data<AppState>,
+    request: web::Json<ChangeEmailRequest>,
+) -> Result<impl Responder, APIError> {
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return Err(APIError::BadRequest(format!("{e}")));
+    }
+    let mut users = state.persist.lock().await;
+    let user_id = user.user_id;
+    verified_user(&mut users, &user_id, &request.current_password).await?;
+    let existing = users
+        .get_user_by_email(&request.new_email)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    if existing.is_some() {
+        return Err(APIError::BadRequest("email already in use".to_string()));
+    }
+
+    let nonce = random_alphanumeric_string(NONCE_LENGTH);
+    users
+        .add_email_change(&user_id, &request.new_email, &nonce)
+        .await
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    let confirmation_link = state
+        .backend_url
+        .join(&format!("user/email/{nonce}"))
+        .map_err(|e| {
+            error!("{e}");
+            APIError::InternalServerError
+        })?;
+    let receiver: Mailbox = request.new_email.parse().map_err(|e| {
+        error!("{e}");
+        APIError::InternalServerError
+    })?;
+    let mail = generate_email_change_mail(&confirmation_link);
+    tokio::task::spawn(async move {
+        if let Err(e) = send_mail(mail, receiver).await {
+            error!("failed sending email change mail: {e}");
+        }
+    });
+    info!("email change requested by {user_id}");
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[get("/user/email/{nonce}")]
+pub(crate) async fn confirm_email_change(
+    nonce: web::Path<Nonce>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let mut users = state.persist.lock().await;
+    match users.take_email_change(&nonce).await {
+        Ok(Some((user_id, email))) => match users.get_user_by_email(&email).await {
+            Ok(None) => match users.change_email(&user_id, &email).await {
+                Ok(()) => info!("changed email of {user_id}"),
+                Err(e) => error!("{e}"),
+            },
+            Ok(Some(_)) => info!("not changing email of {user_id}: already in use"),
+            Err(e) => error!("{e}"),
+        },
+        Ok(None) => info!("unknown or expired email change nonce"),
+        Err(e) => error!("{e}"),
+    }
+
+    HttpResponse::Found()
+        .append_header(("Location", state.frontend_url.as_str()))
+        .finish()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::api::users::UserResponse;
+    use super::*;
+    use crate::api::authentication::login;
+    use crate::api::authentication::validate_session;
+    use crate::api::login_cookie;
+    use crate::model::organization::OrgID;
     use crate::model::user::User;
+    use crate::persistence::token::TokenStore;
+    use crate::persistence::PersistPendingUser;
+    use actix_identity::IdentityMiddleware;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::cookie::Key;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    // only the module, so that `#[test]` stays the standard attribute
+    use actix_web::test::{self};
+    use actix_web::App;
+    use serde_json::json;
 
     #[test]
     fn test_user_response() {
         let _ = UserResponse::from(User::example());
     }
+
+    #[actix_web::test]
+    async fn test_delete_user() {
+        let state = web::Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        let token = {
+            let mut persist = state.persist.lock().await;
+            persist
+                .add_user_pending(&user, &"nonce".to_string())
+                .await
+                .unwrap();
+            persist.add_user(&user).await.unwrap();
+            persist
+                .create_token_for_org(&OrgID::personal(&user.user_id))
+                .await
+                .unwrap()
+        };
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(get_user_by_id)
+                .service(delete_user),
+        )
+        .await;
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+        let other_session = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+
+        let request = test::TestRequest::delete()
+            .uri("/user")
+            .cookie(cookie.clone())
+            .set_json(json!({"password": "wrong-password"}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::delete()
+            .uri("/user")
+            .cookie(cookie)
+            .set_json(json!({"password": "asdfasdfasdf"}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let removal = response
+            .response()
+            .cookies()
+            .next()
+            .expect("session not removed");
+        assert_eq!(removal.value(), "");
+
+        let mut persist = state.persist.lock().await;
+        assert!(persist
+            .get_user_by_email("x.x@x.x")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(persist.get_org_of_token(&token).await.unwrap(), None);
+        assert!(persist.get_users_pending().await.unwrap().is_empty());
+        drop(persist);
+
+        let request = test::TestRequest::get()
+            .uri("/user")
+            .cookie(other_session)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({"email": "x.x@x.x", "password": "asdfasdfasdf"}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_add_user() {
+        let state = web::Data::new(AppState::in_memory());
+        let mut admin = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&admin).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(add_user),
+        )
+        .await;
+        let add = |cookie: Option<&actix_web::cookie::Cookie<'static>>| {
+            let request = test::TestRequest::post()
+                .uri("/user")
+                .set_json(json!({"email": "y.y@y.y", "password": "qwerqwerqwer"}));
+            match cookie {
+                Some(cookie) => request.cookie(cookie.clone()).to_request(),
+                None => request.to_request(),
+            }
+        };
+
+        let response = test::call_service(&app, add(None)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+        let response = test::call_service(&app, add(Some(&cookie))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        admin.role = UserRole::Admin;
+        state.persist.lock().await.add_user(&admin).await.unwrap();
+        let response = test::call_service(&app, add(Some(&cookie))).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = test::call_service(&app, add(Some(&cookie))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        login_cookie(&app, "y.y@y.y", "qwerqwerqwer").await;
+    }
+
+    #[actix_web::test]
+    async fn test_change_password() {
+        let state = web::Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        state.persist.lock().await.add_user(&user).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(validate_session))
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(get_user_by_id)
+                .service(change_password),
+        )
+        .await;
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+        let other_cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+        let change = |current_password: &str| {
+            test::TestRequest::post()
+                .uri("/user/password")
+                .cookie(cookie.clone())
+                .set_json(
+                    json!({"current_password": current_password, "new_password": "new-password"}),
+                )
+                .to_request()
+        };
+
+        let response = test::call_service(&app, change("wrong-password")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test::call_service(&app, change("asdfasdfasdf")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        for (cookie, status) in [
+            (cookie, StatusCode::OK),
+            (other_cookie, StatusCode::UNAUTHORIZED),
+        ] {
+            let request = test::TestRequest::get()
+                .uri("/user")
+                .cookie(cookie)
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status);
+        }
+        login_cookie(&app, "x.x@x.x", "new-password").await;
+    }
+
+    #[actix_web::test]
+    async fn test_change_email() {
+        let state = web::Data::new(AppState::in_memory());
+        let user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        let other_user = User::new("y.y@y.y".to_string(), "asdfasdfasdf".to_string());
+        {
+            let mut persist = state.persist.lock().await;
+            persist.add_user(&user).await.unwrap();
+            persist.add_user(&other_user).await.unwrap();
+        }
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .app_data(state.clone())
+                .service(login)
+                .service(change_email)
+                .service(confirm_email_change),
+        )
+        .await;
+        let cookie = login_cookie(&app, "x.x@x.x", "asdfasdfasdf").await;
+        for (current_password, new_email, status) in [
+            ("wrong-password", "z.z@z.z", StatusCode::UNAUTHORIZED),
+            ("asdfasdfasdf", "invalid", StatusCode::BAD_REQUEST),
+            ("asdfasdfasdf", "y.y@y.y", StatusCode::BAD_REQUEST),
+            ("asdfasdfasdf", "z.z@z.z", StatusCode::ACCEPTED),
+        ] {
+            let request = test::TestRequest::post()
+                .uri("/user/email")
+                .cookie(cookie.clone())
+                .set_json(json!({"current_password": current_password, "new_email": new_email}))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status);
+        }
+
+        state
+            .persist
+            .lock()
+            .await
+            .add_email_change(&user.user_id, "z.z@z.z", &"nonce".to_string())
+            .await
+            .unwrap();
+        let request = test::TestRequest::get()
+            .uri("/user/email/nonce")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+
+        let mut persist = state.persist.lock().await;
+        assert!(persist
+            .get_user_by_email("x.x@x.x")
+            .await
+            .unwrap()
+            .is_none());
+        let changed = persist.get_user_by_email("z.z@z.z").await.unwrap().unwrap();
+        assert_eq!(changed.user_id, user.user_id);
+    }
 }
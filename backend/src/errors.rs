@@ -1,4 +1,5 @@
 use crate::model::user::User;
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::{error::ResponseError, HttpResponse};
 use derive_more::Display;
 use std::error::Error;
@@ -12,6 +13,20 @@ pub enum APIError {
     BadRequest(String),
 
     Unauthorized,
+
+    #[display(fmt = "Forbidden: {_0}")]
+    Forbidden(String),
+
+    #[display(fmt = "NotFound")]
+    NotFound,
+
+    /// The request may succeed when retried after the given number of seconds.
+    #[display(fmt = "ServiceUnavailable")]
+    ServiceUnavailable(u64),
+
+    /// Rate limited, retry after the given number of seconds.
+    #[display(fmt = "TooManyRequests")]
+    TooManyRequests(u64),
 }
 
 // impl ResponseError trait allows to convert our errors into http responses with appropriate data
@@ -23,6 +38,14 @@ impl ResponseError for APIError {
             }
             APIError::BadRequest(ref message) => HttpResponse::BadRequest().json(message),
             APIError::Unauthorized => HttpResponse::Unauthorized().finish(),
+            APIError::Forbidden(ref message) => HttpResponse::Forbidden().json(message),
+            APIError::NotFound => HttpResponse::NotFound().finish(),
+            APIError::ServiceUnavailable(retry_after) => HttpResponse::ServiceUnavailable()
+                .insert_header((RETRY_AFTER, retry_after.to_string()))
+                .finish(),
+            APIError::TooManyRequests(retry_after) => HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, retry_after.to_string()))
+                .finish(),
         }
     }
 }
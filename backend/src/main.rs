@@ -5,12 +5,14 @@ mod model;
 mod persistence;
 mod service;
 
-use crate::persistence::token::TokenState;
+use crate::persistence::token::{TokenState, TokenStore};
 use actix::Actor;
 use actix_cors::Cors;
 use actix_identity::IdentityMiddleware;
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
-use actix_web::cookie::{Key, SameSite};
+use actix_session::config::BrowserSession;
+use actix_session::SessionMiddleware;
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::SameSite;
 use std::env;
 use std::str::FromStr;
 
@@ -18,33 +20,45 @@ use crate::api::registration::register_reply;
 use actix_web::web::Data;
 use actix_web::{middleware, services, web, App, HttpServer};
 use api::{
-    authentication::{index, login, logout},
-    messages::{add_message, get_messages_by_hostname},
+    authentication::{index, login, login_two_factor, logout, validate_session},
+    grpc::{self, IngestionService},
+    messages::{add_message, add_messages, get_messages_by_hostname},
+    organizations::{
+        accept_invitation, create_organization, delete_organization, get_members,
+        get_organizations, invite_member, remove_member, set_member, ORG_HEADER,
+    },
+    password::{forgot_password, reset_password},
     registration::register,
+    sessions::{delete_session, get_sessions},
     token::{create_token, get_token},
-    users::{delete_user, get_user_by_id},
-    welcome, AppState,
+    two_factor::{
+        confirm_two_factor, disable_two_factor, enroll_two_factor, regenerate_recovery_codes,
+    },
+    users::{
+        add_user, change_email, change_password, confirm_email_change, delete_user, get_user_by_id,
+    },
+    welcome, AppState, MESSAGE_EVENTS_CAPACITY,
 };
-use log::error;
-use persistence::redis::RedisDatabaseService;
+use log::{error, info};
+use persistence::{PersistOrganization, PersistUser, Persistence, SESSION_MAX_AGE};
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 
-use crate::api::messages::get_message_hostnames;
-use crate::api::token::delete_token;
-use tokio::sync::Mutex;
-
-fn get_secret_key() -> Key {
-    Key::generate()
-}
+use crate::api::messages::{get_message_hostnames, stream_messages};
+use crate::api::token::{delete_token, get_token_rotation, rotate_token};
+use tokio::sync::{broadcast, Mutex};
 
 const USER_COOKIE_NAME: &str = "snitch-user";
 const PORT: u16 = 8081;
+const GRPC_PORT: u16 = 50051;
 
+use crate::api::admin::get_admin_services;
 use crate::api::notification_settings::get_notification_services;
-use crate::service::kafka::{KafkaActor, KafkaManager, KafkaPersistClient};
+use crate::api::rate_limit::{LocalRateLimiter, MESSAGES_RATE_LIMIT};
+use crate::service::ingestion::Ingestion;
 use crate::service::notification_dispatcher::NotificationManager;
 use crate::service::notification_filter::NotificationFilter;
+use crate::service::session::{session_key, SnitchSessionStore};
 use actix_web::http::header;
 
 const SAME_SITE: SameSite = SameSite::Strict;
@@ -68,31 +82,78 @@ async fn main() -> std::io::Result<()> {
     };
     let notification_addr = web::Data::new(notification_actor.start());
 
-    let kafka_actor = KafkaActor::new(KafkaManager::new());
-    let kafka_addr = Data::new(kafka_actor.start());
-
-    let db_service = RedisDatabaseService::new()
+    service::token::check_token_pepper().unwrap_or_else(|e| {
+        error!("{e}");
+        std::process::exit(1)
+    });
+    api::check_trusted_proxies().unwrap_or_else(|e| {
+        error!("{e}");
+        std::process::exit(1)
+    });
+    let mut db_service = Persistence::new()
         .await
-        .expect("failed to create redis service");
+        .expect("failed to create persistence service");
+    match db_service.migrate_plaintext_tokens().await {
+        Ok(0) => {}
+        Ok(migrated) => info!("hashed {migrated} plaintext tokens"),
+        Err(e) => {
+            error!("failed to migrate plaintext tokens: {e}");
+            std::process::exit(1)
+        }
+    }
+    match db_service.migrate_user_index().await {
+        Ok(0) => {}
+        Ok(migrated) => info!("indexed {migrated} users"),
+        Err(e) => {
+            error!("failed to index users: {e}");
+            std::process::exit(1)
+        }
+    }
+    match db_service.migrate_personal_organizations().await {
+        Ok(0) => {}
+        Ok(migrated) => info!("created {migrated} personal organizations"),
+        Err(e) => {
+            error!("failed to create personal organizations: {e}");
+            std::process::exit(1)
+        }
+    }
     let state = Data::new(AppState {
         notification_filter: Mutex::new(notification_filter),
-        persist: Mutex::new(db_service),
+        message_events: broadcast::channel(MESSAGE_EVENTS_CAPACITY).0,
+        persist: Mutex::new(db_service.clone()),
         backend_url: Url::from_str(&backend_url)
             .unwrap_or_else(|_| panic!("failed to parse as url: {backend_url}")),
         frontend_url: Url::from_str(&frontend_url)
             .unwrap_or_else(|_| panic!("failed to parse as url: {frontend_url}")),
+        admin_token: env::var("SNITCH_ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty()),
+        messages_rate_limiter: LocalRateLimiter::new(MESSAGES_RATE_LIMIT),
     });
 
-    KafkaPersistClient::new(state.clone());
+    let ingestion = Data::new(
+        Ingestion::new(state.clone(), notification_addr.clone()).unwrap_or_else(|e| {
+            error!("failed to set up ingestion: {e}");
+            std::process::exit(1)
+        }),
+    );
 
-    let db_token_service = RedisDatabaseService::new()
-        .await
-        .expect("failed to create redis service");
+    let state_token = Data::new(TokenState::new(db_service));
+    let secret_key = session_key().unwrap_or_else(|e| {
+        error!("{e}");
+        std::process::exit(1)
+    });
+    let session_store = SnitchSessionStore::from_env().await.unwrap_or_else(|e| {
+        error!("failed to set up session store: {e}");
+        std::process::exit(1)
+    });
 
-    let state_token = Data::new(TokenState::new(db_token_service.connection));
-    let secret_key = get_secret_key();
+    let grpc_server = grpc::serve(
+        ([0, 0, 0, 0], GRPC_PORT).into(),
+        IngestionService::new(state.clone(), state_token.clone(), ingestion.clone()),
+    );
 
-    HttpServer::new(move || {
+    let http_server = HttpServer::new(move || {
         let cookie_domain = std::env::var("SNITCH_COOKIE_DOMAIN")
             .map_err(|e| {
                 error!("Failed loading SNITCH_COOKIE_DOMAIN: {e}");
@@ -103,59 +164,107 @@ async fn main() -> std::io::Result<()> {
 
         let services = services![
             welcome,
-            add_message,
             register,
             register_reply,
+            forgot_password,
+            reset_password,
             login,
+            login_two_factor,
             logout,
             index,
-            get_messages_by_hostname,
-            get_message_hostnames,
+        ];
+        let services_user = services![
             get_user_by_id,
+            add_user,
             delete_user,
+            change_password,
+            change_email,
+            confirm_email_change,
+            get_sessions,
+            delete_session,
+            enroll_two_factor,
+            confirm_two_factor,
+            disable_two_factor,
+            regenerate_recovery_codes,
+        ];
+        let services_messages = services![
+            add_message,
+            add_messages,
+            stream_messages,
+            get_messages_by_hostname,
+            get_message_hostnames,
+        ];
+        let services_token = services![
+            create_token,
+            get_token,
+            delete_token,
+            rotate_token,
+            get_token_rotation,
+        ];
+        let services_organizations = services![
+            create_organization,
+            get_organizations,
+            get_members,
+            set_member,
+            remove_member,
+            delete_organization,
+            invite_member,
+            accept_invitation,
         ];
-        let services_token = services![create_token, get_token, delete_token,];
 
         let session_middleware =
-            SessionMiddleware::builder(CookieSessionStore::default(), secret_key.clone())
+            SessionMiddleware::builder(session_store.clone(), secret_key.clone())
                 .cookie_http_only(true)
                 .cookie_domain(cookie_domain)
                 .cookie_path("/".into())
                 .cookie_name(USER_COOKIE_NAME.to_string())
                 .cookie_same_site(SAME_SITE)
                 .cookie_secure(true)
+                .session_lifecycle(
+                    BrowserSession::default().state_ttl(Duration::seconds(SESSION_MAX_AGE)),
+                )
                 .build();
 
         App::new()
+            .wrap(middleware::from_fn(validate_session))
             .wrap(cors)
             .wrap(IdentityMiddleware::default())
             .wrap(session_middleware)
             .service(services)
+            .service(services_user)
+            .service(services_messages)
             .service(services_token)
+            .service(services_organizations)
             .service(get_notification_services())
+            .service(get_admin_services())
             .wrap(middleware::NormalizePath::trim())
             .wrap(middleware::Logger::default())
             .app_data(state.clone())
-            .app_data(kafka_addr.clone())
+            .app_data(ingestion.clone())
             .app_data(notification_addr.clone())
             .app_data(state_token.clone())
     })
     .bind(("0.0.0.0", PORT))?
-    .run()
-    .await
+    .run();
+
+    tokio::select! {
+        result = http_server => result,
+        result = grpc_server => result.map_err(std::io::Error::other),
+    }
 }
 
 fn setup_cors(frontend_url: &str, backend_url: &str) -> Cors {
     Cors::default()
         .allowed_origin(frontend_url)
         .allowed_origin(backend_url)
-        .allowed_methods(vec!["GET", "POST", "DELETE", "OPTIONS"])
+        .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"])
         .allowed_headers(vec![
             header::AUTHORIZATION,
             header::ACCEPT,
             header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
             header::COOKIE,
             header::CONTENT_TYPE,
+            header::HeaderName::from_static(ORG_HEADER),
         ])
         .expose_headers(vec![header::SET_COOKIE])
         .supports_credentials()
@@ -1,3 +1,4 @@
+use crate::model::organization::OrgID;
 use actix_web::cookie::time::macros::time;
 use chatterbox::message::{Message as ChatterboxMessage, Notification};
 use chrono::{DateTime, Utc};
@@ -5,61 +6,195 @@ use prost::{Enumeration, Message};
 use rdkafka::message::ToBytes;
 use redis::{FromRedisValue, RedisResult, RedisWrite, ToRedisArgs, Value};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::io::Cursor;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::OnceLock;
 use std::time::SystemTime;
+use thiserror::Error;
 
 pub type MessageToken = String;
 
+/// Published on [`AppState::message_events`](crate::api::AppState) for every persisted message.
+#[derive(Clone, Debug)]
+pub(crate) struct MessageEvent {
+    pub org_id: OrgID,
+    pub message: MessageBackend,
+}
+
 pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/greeter.rs"));
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl From<Severity> for proto::Severity {
+    fn from(value: Severity) -> Self {
+        match value {
+            Severity::Info => proto::Severity::Info,
+            Severity::Warning => proto::Severity::Warning,
+            Severity::Critical => proto::Severity::Critical,
+        }
+    }
+}
+
+impl Severity {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "INFO",
+            Severity::Warning => "WARNING",
+            Severity::Critical => "CRITICAL",
+        }
+    }
+
+    /// Maps the protobuf value, treating unspecified and unknown values as absent.
+    fn from_proto(value: i32) -> Option<Self> {
+        match proto::Severity::try_from(value).ok()? {
+            proto::Severity::Unspecified => None,
+            proto::Severity::Info => Some(Severity::Info),
+            proto::Severity::Warning => Some(Severity::Warning),
+            proto::Severity::Critical => Some(Severity::Critical),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
 pub(crate) struct MessageBackend {
     pub hostname: String,
     pub title: String,
     pub body: String,
     pub timestamp: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    /// Name of the check or script that produced the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+    /// Chosen by the client. Retries with the same key are stored only once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+pub(crate) const MAX_HOSTNAME_LENGTH: usize = 255;
+pub(crate) const MAX_TITLE_LENGTH: usize = 1024;
+pub(crate) const MAX_BODY_LENGTH: usize = 64 * 1024;
+pub(crate) const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+pub(crate) const MAX_SOURCE_LENGTH: usize = 255;
+pub(crate) const MAX_CLIENT_VERSION_LENGTH: usize = 64;
+pub(crate) const MAX_LABELS: usize = 32;
+pub(crate) const MAX_LABEL_KEY_LENGTH: usize = 64;
+pub(crate) const MAX_LABEL_VALUE_LENGTH: usize = 255;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum InvalidMessage {
+    #[error("hostname must not be empty")]
+    EmptyHostname,
+    #[error("{field} exceeds {max} bytes")]
+    TooLong { field: &'static str, max: usize },
+    #[error("at most {max} labels")]
+    TooManyLabels { max: usize },
+}
+
+impl MessageBackend {
+    /// Checks a message received from a client. A missing timestamp is set to
+    /// the time of arrival.
+    pub(crate) fn validated(mut self) -> Result<Self, InvalidMessage> {
+        if self.hostname.is_empty() {
+            return Err(InvalidMessage::EmptyHostname);
+        }
+        for (field, value, max) in [
+            ("hostname", self.hostname.as_str(), MAX_HOSTNAME_LENGTH),
+            ("title", self.title.as_str(), MAX_TITLE_LENGTH),
+            ("body", self.body.as_str(), MAX_BODY_LENGTH),
+            (
+                "idempotency_key",
+                self.idempotency_key.as_deref().unwrap_or_default(),
+                MAX_IDEMPOTENCY_KEY_LENGTH,
+            ),
+            (
+                "source",
+                self.source.as_deref().unwrap_or_default(),
+                MAX_SOURCE_LENGTH,
+            ),
+            (
+                "client_version",
+                self.client_version.as_deref().unwrap_or_default(),
+                MAX_CLIENT_VERSION_LENGTH,
+            ),
+        ]
+        .into_iter()
+        .chain(self.labels.iter().flat_map(|(key, value)| {
+            [
+                ("label key", key.as_str(), MAX_LABEL_KEY_LENGTH),
+                ("label value", value.as_str(), MAX_LABEL_VALUE_LENGTH),
+            ]
+        })) {
+            if value.len() > max {
+                return Err(InvalidMessage::TooLong { field, max });
+            }
+        }
+        if self.labels.len() > MAX_LABELS {
+            return Err(InvalidMessage::TooManyLabels { max: MAX_LABELS });
+        }
+        self.timestamp.get_or_insert_with(Utc::now);
+        Ok(self)
+    }
 }
 
 impl From<MessageBackend> for ProtoMessageBackend {
     fn from(value: MessageBackend) -> Self {
-        let timestamp = value.timestamp.unwrap();
-        let s: SystemTime = timestamp.into();
-        let timestamp = prost_types::Timestamp::from(s);
+        let timestamp: SystemTime = value.timestamp.unwrap_or_else(Utc::now).into();
+        let timestamp = prost_types::Timestamp::from(timestamp);
         Self {
             hostname: value.hostname,
             title: value.title,
             body: value.body,
             timestamp: Some(timestamp),
+            severity: value
+                .severity
+                .map(proto::Severity::from)
+                .unwrap_or(proto::Severity::Unspecified)
+                .into(),
+            source: value.source,
+            labels: value.labels.into_iter().collect(),
+            client_version: value.client_version,
+            idempotency_key: value.idempotency_key,
         }
     }
 }
 
 impl From<ProtoMessageBackend> for MessageBackend {
     fn from(value: ProtoMessageBackend) -> Self {
-        let timestamp = value.timestamp.unwrap();
-        let s = chrono::DateTime::<Utc>::from_timestamp(timestamp.seconds, timestamp.nanos as u32);
+        // Missing and out of range timestamps are left to `validated`.
+        let timestamp = value.timestamp.and_then(|timestamp| {
+            let nanos = u32::try_from(timestamp.nanos).ok()?;
+            DateTime::<Utc>::from_timestamp(timestamp.seconds, nanos)
+        });
 
         Self {
             hostname: value.hostname,
             title: value.title,
             body: value.body,
-            timestamp: s,
+            timestamp,
+            severity: Severity::from_proto(value.severity),
+            source: value.source,
+            labels: value.labels.into_iter().collect(),
+            client_version: value.client_version,
+            idempotency_key: value.idempotency_key,
         }
     }
 }
 
 pub type ProtoMessageBackend = proto::BackendMessage;
 
-impl From<&String> for ProtoMessageBackend {
-    fn from(s: &String) -> Self {
-        ProtoMessageBackend::decode(&mut Cursor::new(s)).unwrap()
-    }
-}
-
 impl Serialize for ProtoMessageBackend {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -89,10 +224,171 @@ impl ToRedisArgs for MessageBackend {
 
 impl Notification for MessageBackend {
     fn message(&self) -> ChatterboxMessage {
-        let body = self.body.clone() + "\n\n" + &self.hostname;
-        ChatterboxMessage {
-            title: self.title.clone(),
-            body,
+        let mut body = self.body.clone() + "\n\n" + &self.hostname;
+        if let Some(source) = &self.source {
+            body = body + " (" + source + ")";
+        }
+        let title = match self.severity {
+            Some(severity) => format!("[{}] {}", severity.as_str(), self.title),
+            None => self.title.clone(),
+        };
+        ChatterboxMessage { title, body }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_message() -> MessageBackend {
+        MessageBackend {
+            hostname: "host".to_string(),
+            title: "disk".to_string(),
+            body: "disk full".to_string(),
+            timestamp: Some(Utc::now()),
+            severity: Some(Severity::Critical),
+            source: Some("check_disk".to_string()),
+            labels: BTreeMap::from([("env".to_string(), "prod".to_string())]),
+            client_version: Some("1.2.0".to_string()),
+            idempotency_key: Some("retry-1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let message = example_message();
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.contains(r#""severity":"critical""#));
+        let decoded: MessageBackend = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn test_json_without_metadata() {
+        let json = r#"{"hostname":"host","title":"t","body":"b","timestamp":null}"#;
+        let message: MessageBackend = serde_json::from_str(json).unwrap();
+        assert_eq!(message.severity, None);
+        assert!(message.labels.is_empty());
+        assert_eq!(serde_json::to_string(&message).unwrap(), json);
+    }
+
+    #[test]
+    fn test_validated() {
+        let message = MessageBackend {
+            hostname: "host".to_string(),
+            ..Default::default()
+        };
+        assert!(message.clone().validated().unwrap().timestamp.is_some());
+
+        let empty = MessageBackend::default();
+        assert_eq!(
+            empty.validated().unwrap_err(),
+            InvalidMessage::EmptyHostname
+        );
+
+        let too_long = MessageBackend {
+            body: "x".repeat(MAX_BODY_LENGTH + 1),
+            ..message.clone()
+        };
+        assert_eq!(
+            too_long.validated().unwrap_err(),
+            InvalidMessage::TooLong {
+                field: "body",
+                max: MAX_BODY_LENGTH
+            }
+        );
+
+        let too_long = MessageBackend {
+            source: Some("x".repeat(MAX_SOURCE_LENGTH + 1)),
+            ..message.clone()
+        };
+        assert_eq!(
+            too_long.validated().unwrap_err(),
+            InvalidMessage::TooLong {
+                field: "source",
+                max: MAX_SOURCE_LENGTH
+            }
+        );
+        let too_long = MessageBackend {
+            client_version: Some("x".repeat(MAX_CLIENT_VERSION_LENGTH + 1)),
+            ..message.clone()
+        };
+        assert_eq!(
+            too_long.validated().unwrap_err(),
+            InvalidMessage::TooLong {
+                field: "client_version",
+                max: MAX_CLIENT_VERSION_LENGTH
+            }
+        );
+        let too_long = MessageBackend {
+            labels: BTreeMap::from([("x".repeat(MAX_LABEL_KEY_LENGTH + 1), String::new())]),
+            ..message.clone()
+        };
+        assert_eq!(
+            too_long.validated().unwrap_err(),
+            InvalidMessage::TooLong {
+                field: "label key",
+                max: MAX_LABEL_KEY_LENGTH
+            }
+        );
+        let too_long = MessageBackend {
+            labels: BTreeMap::from([("env".to_string(), "x".repeat(MAX_LABEL_VALUE_LENGTH + 1))]),
+            ..message.clone()
+        };
+        assert_eq!(
+            too_long.validated().unwrap_err(),
+            InvalidMessage::TooLong {
+                field: "label value",
+                max: MAX_LABEL_VALUE_LENGTH
+            }
+        );
+
+        let labels = |n: usize| (0..n).map(|i| (i.to_string(), String::new())).collect();
+        let many_labels = MessageBackend {
+            labels: labels(MAX_LABELS),
+            ..message.clone()
+        };
+        assert!(many_labels.validated().is_ok());
+        let too_many_labels = MessageBackend {
+            labels: labels(MAX_LABELS + 1),
+            ..message
+        };
+        assert_eq!(
+            too_many_labels.validated().unwrap_err(),
+            InvalidMessage::TooManyLabels { max: MAX_LABELS }
+        );
+    }
+
+    #[test]
+    fn test_proto_without_timestamp() {
+        let proto: ProtoMessageBackend = MessageBackend::default().into();
+        assert!(proto.timestamp.is_some());
+
+        let proto = ProtoMessageBackend {
+            timestamp: Some(prost_types::Timestamp {
+                seconds: i64::MAX,
+                nanos: -1,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(MessageBackend::from(proto).timestamp, None);
+    }
+
+    #[test]
+    fn test_proto_roundtrip() {
+        let message = example_message();
+        let proto: ProtoMessageBackend = message.clone().into();
+        let bytes = serialize_message(&proto);
+        let decoded = ProtoMessageBackend::decode(bytes.as_slice()).unwrap();
+        assert_eq!(MessageBackend::from(decoded), message);
+
+        let mut proto: ProtoMessageBackend = MessageBackend {
+            timestamp: Some(Utc::now()),
+            ..Default::default()
         }
+        .into();
+        assert_eq!(proto.severity, proto::Severity::Unspecified as i32);
+        proto.severity = 42;
+        assert_eq!(MessageBackend::from(proto).severity, None);
     }
 }
@@ -1,2 +1,5 @@
 pub mod message;
+pub(crate) mod notification_rule;
+pub mod organization;
+pub(crate) mod token;
 pub mod user;
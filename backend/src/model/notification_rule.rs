@@ -0,0 +1,172 @@
+use crate::model::message::{MessageBackend, Severity};
+use anyhow::Result;
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+pub(crate) type RuleID = String;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Channel {
+    Telegram,
+    Slack,
+    Email,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub(crate) enum RuleAction {
+    /// Send matching messages to these channels only.
+    Route { channels: Vec<Channel> },
+    /// Do not notify about matching messages at all.
+    Mute,
+}
+
+/// All present conditions have to hold for a rule to match.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct RuleConditions {
+    /// Glob on the hostname, e.g. `web-*`.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Regex searched in the title.
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Regex searched in the body.
+    #[serde(default)]
+    pub body: Option<String>,
+    /// Minimum severity. Messages without severity never match.
+    #[serde(default)]
+    pub severity: Option<Severity>,
+    /// Labels the message has to carry with exactly these values.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub(crate) compiled: CompiledPatterns,
+}
+
+impl RuleConditions {
+    /// The compiled patterns, `None` if one of them is invalid.
+    fn patterns(&self) -> Option<&Patterns> {
+        self.compiled
+            .0
+            .get_or_init(|| Patterns::compile(self).ok())
+            .as_ref()
+    }
+}
+
+/// The hostname glob and the regexes of [`RuleConditions`].
+#[derive(Debug, Clone)]
+struct Patterns {
+    hostname: Option<GlobMatcher>,
+    title: Option<Regex>,
+    body: Option<Regex>,
+}
+
+impl Patterns {
+    fn compile(conditions: &RuleConditions) -> Result<Self> {
+        Ok(Self {
+            hostname: conditions
+                .hostname
+                .as_deref()
+                .map(|hostname| Glob::new(hostname).map(|glob| glob.compile_matcher()))
+                .transpose()?,
+            title: conditions.title.as_deref().map(Regex::new).transpose()?,
+            body: conditions.body.as_deref().map(Regex::new).transpose()?,
+        })
+    }
+}
+
+/// The patterns, compiled once when the rule is stored or loaded and shared by
+/// its clones, so that they aren't compiled again for every message. Always
+/// equal, as they follow from the conditions.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CompiledPatterns(OnceLock<Option<Patterns>>);
+
+impl PartialEq for CompiledPatterns {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for CompiledPatterns {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NotificationRule {
+    #[serde(default)]
+    pub rule_id: RuleID,
+    pub name: String,
+    #[serde(default)]
+    pub conditions: RuleConditions,
+    pub action: RuleAction,
+}
+
+impl NotificationRule {
+    pub(crate) fn new_rule_id() -> RuleID {
+        Uuid::new_v4().to_string()
+    }
+
+    /// Compiles the patterns ahead of the first match.
+    pub(crate) fn compile(&self) {
+        self.conditions.patterns();
+    }
+
+    /// Checks that the hostname glob and the regexes compile.
+    pub(crate) fn validate(&self) -> Result<()> {
+        Patterns::compile(&self.conditions).map(|_| ())
+    }
+
+    /// Rules with invalid patterns never match.
+    pub(crate) fn matches(&self, message: &MessageBackend) -> bool {
+        let conditions = &self.conditions;
+        let Some(patterns) = conditions.patterns() else {
+            return false;
+        };
+        if patterns
+            .hostname
+            .as_ref()
+            .is_some_and(|glob| !glob.is_match(&message.hostname))
+            || !regex_matches(&patterns.title, &message.title)
+            || !regex_matches(&patterns.body, &message.body)
+        {
+            return false;
+        }
+        if let Some(severity) = conditions.severity {
+            if message.severity.is_none_or(|s| s < severity) {
+                return false;
+            }
+        }
+        conditions
+            .labels
+            .iter()
+            .all(|(key, value)| message.labels.get(key) == Some(value))
+    }
+}
+
+fn regex_matches(regex: &Option<Regex>, haystack: &str) -> bool {
+    regex.as_ref().is_none_or(|regex| regex.is_match(haystack))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compiled_patterns() {
+        let json = r#"{"rule_id":"4c1e4a8e-3f1b-4d2a-9a51-0b6f3b1f2c7d","name":"web","conditions":{"hostname":"web-*","title":"cpu"},"action":{"type":"mute"}}"#;
+        let rule: NotificationRule = serde_json::from_str(json).unwrap();
+        assert!(rule.conditions.compiled.0.get().is_none());
+        rule.compile();
+
+        let loaded = rule.clone();
+        assert!(loaded.conditions.compiled.0.get().unwrap().is_some());
+        assert_eq!(loaded, serde_json::from_str(json).unwrap());
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(serde_json::from_str::<NotificationRule>(json).unwrap()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use crate::model::user::UserID;
+use chrono::{DateTime, Utc};
+use derive_more::{Display, FromStr};
+use rdkafka::message::ToBytes;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Owns hosts, messages, tokens and notification settings. Every user has a
+/// personal organization sharing the id of the user, so data stored per user
+/// before organizations existed belongs to it.
+#[derive(
+    Serialize, Deserialize, Debug, Display, FromStr, Hash, Ord, Eq, PartialOrd, PartialEq, Clone,
+)]
+pub struct OrgID(pub String);
+
+impl OrgID {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    pub fn personal(user_id: &UserID) -> Self {
+        Self(user_id.0.clone())
+    }
+}
+
+impl Default for OrgID {
+    fn default() -> Self {
+        OrgID(Uuid::default().to_string())
+    }
+}
+
+impl From<String> for OrgID {
+    fn from(value: String) -> Self {
+        OrgID(value)
+    }
+}
+
+impl ToBytes for OrgID {
+    fn to_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// What a member may do, each role includes the ones below it.
+#[derive(
+    Serialize, Deserialize, Debug, Display, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    /// Reads messages, hosts and settings.
+    #[display(fmt = "viewer")]
+    Viewer,
+    /// Additionally manages tokens, notification settings and members.
+    #[display(fmt = "admin")]
+    Admin,
+    /// Additionally manages owners and deletes the organization.
+    #[display(fmt = "owner")]
+    Owner,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Organization {
+    pub(crate) org_id: OrgID,
+    pub(crate) name: String,
+    /// Personal organizations can't be deleted or left by their owner.
+    pub(crate) personal: bool,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+impl Organization {
+    pub(crate) fn new(name: String) -> Self {
+        Self {
+            org_id: OrgID::new(),
+            name,
+            personal: false,
+            created_at: Utc::now(),
+        }
+    }
+
+    pub(crate) fn personal(user_id: &UserID) -> Self {
+        Self {
+            org_id: OrgID::personal(user_id),
+            name: "Personal".to_string(),
+            personal: true,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// An emailed invitation, accepted by the user with this email.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Invitation {
+    pub(crate) org_id: OrgID,
+    pub(crate) email: String,
+    pub(crate) role: Role,
+    pub(crate) invited_by: UserID,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles() {
+        assert!(Role::Owner > Role::Admin);
+        assert!(Role::Admin > Role::Viewer);
+        assert_eq!(serde_json::to_string(&Role::Admin).unwrap(), "\"admin\"");
+        assert_eq!(Role::Viewer.to_string(), "viewer");
+
+        let user_id = UserID::new();
+        let personal = Organization::personal(&user_id);
+        assert_eq!(personal.org_id.to_string(), user_id.to_string());
+        assert!(personal.personal);
+        assert_ne!(
+            Organization::new("team".to_string()).org_id,
+            personal.org_id
+        );
+    }
+}
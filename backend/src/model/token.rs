@@ -0,0 +1,271 @@
+use crate::model::message::MessageToken;
+use crate::model::organization::OrgID;
+use chrono::{DateTime, Duration, Utc};
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+/// Identifies a token in listings and on deletion without revealing it.
+pub(crate) type TokenID = String;
+
+/// Keyed hash of a token, tokens are only stored as such.
+pub(crate) type TokenHash = String;
+
+/// Length of the token prefix used as [`TokenID`].
+const TOKEN_ID_LENGTH: usize = 8;
+
+pub(crate) fn token_id(token: &str) -> TokenID {
+    token.chars().take(TOKEN_ID_LENGTH).collect()
+}
+
+/// What a token may be used for.
+#[derive(Serialize, Deserialize, Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TokenScope {
+    /// Sending messages.
+    #[display(fmt = "ingest")]
+    Ingest,
+    /// Reading messages with `GET /messages/{hostname}`.
+    #[display(fmt = "read")]
+    Read,
+}
+
+/// Set when creating a token. Tokens from before metadata existed get the
+/// default: unnamed, ingest-only, for all hostnames and never expiring.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TokenMetadata {
+    #[serde(default)]
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+    pub(crate) scopes: Vec<TokenScope>,
+    /// Hostnames the token may send or read messages of, all if empty.
+    #[serde(default)]
+    pub(crate) hostnames: Vec<String>,
+}
+
+impl TokenMetadata {
+    /// The metadata of a token replacing this one. It keeps the settings and,
+    /// for expiring tokens, the lifetime.
+    pub(crate) fn successor(&self, now: DateTime<Utc>) -> Self {
+        let expires_at = match (self.created_at, self.expires_at) {
+            (Some(created_at), Some(expires_at)) => Some(now + (expires_at - created_at)),
+            (_, expires_at) => expires_at,
+        };
+        Self {
+            created_at: Some(now),
+            expires_at,
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for TokenMetadata {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            created_at: None,
+            expires_at: None,
+            scopes: vec![TokenScope::Ingest],
+            hostnames: Vec::new(),
+        }
+    }
+}
+
+/// Set on a token once a successor replaced it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TokenRotation {
+    pub(crate) successor: TokenID,
+    pub(crate) rotated_at: DateTime<Utc>,
+}
+
+/// A host messages were sent for with a token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TokenHost {
+    pub(crate) hostname: String,
+    pub(crate) last_used_at: DateTime<Utc>,
+}
+
+/// A stored token as listed to its organization, without the secret.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TokenInfo {
+    pub(crate) token_id: TokenID,
+    #[serde(skip)]
+    pub(crate) hash: TokenHash,
+    #[serde(skip)]
+    pub(crate) org_id: OrgID,
+    #[serde(flatten)]
+    pub(crate) metadata: TokenMetadata,
+    pub(crate) last_used_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) rotation: Option<TokenRotation>,
+    /// Ordered by hostname.
+    pub(crate) hosts: Vec<TokenHost>,
+}
+
+impl TokenInfo {
+    pub(crate) fn new(
+        hash: TokenHash,
+        token_id: TokenID,
+        org_id: OrgID,
+        metadata: TokenMetadata,
+        last_used_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            token_id,
+            hash,
+            org_id,
+            metadata,
+            last_used_at,
+            rotation: None,
+            hosts: Vec::new(),
+        }
+    }
+
+    /// Marks the token as replaced by `successor`. It stays valid for `grace`,
+    /// but not beyond its own expiry.
+    pub(crate) fn rotate(&mut self, successor: &MessageToken, now: DateTime<Utc>, grace: Duration) {
+        let valid_until = now + grace;
+        self.metadata.expires_at = Some(match self.metadata.expires_at {
+            Some(expires_at) => expires_at.min(valid_until),
+            None => valid_until,
+        });
+        self.rotation = Some(TokenRotation {
+            successor: token_id(successor),
+            rotated_at: now,
+        });
+    }
+
+    /// Records that messages of `hostname` were sent with the token.
+    pub(crate) fn use_host(&mut self, hostname: &str, now: DateTime<Utc>) {
+        match self
+            .hosts
+            .binary_search_by(|host| host.hostname.as_str().cmp(hostname))
+        {
+            Ok(index) => self.hosts[index].last_used_at = now,
+            Err(index) => self.hosts.insert(
+                index,
+                TokenHost {
+                    hostname: hostname.to_string(),
+                    last_used_at: now,
+                },
+            ),
+        }
+    }
+
+    /// The hosts that used the token since it was rotated, i.e. whose
+    /// configuration still has to be updated.
+    pub(crate) fn hosts_since_rotation(&self) -> Vec<TokenHost> {
+        let Some(rotation) = &self.rotation else {
+            return Vec::new();
+        };
+        self.hosts
+            .iter()
+            .filter(|host| host.last_used_at >= rotation.rotated_at)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.metadata
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub(crate) fn has_scope(&self, scope: TokenScope) -> bool {
+        self.metadata.scopes.contains(&scope)
+    }
+
+    pub(crate) fn allows_hostname(&self, hostname: &str) -> bool {
+        self.metadata.hostnames.is_empty()
+            || self
+                .metadata
+                .hostnames
+                .iter()
+                .any(|allowed| allowed == hostname)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_token_info() {
+        let token: MessageToken = "abcdefghijklmnop".to_string();
+        let now = Utc::now();
+        let metadata = TokenMetadata {
+            expires_at: Some(now),
+            hostnames: vec!["host".to_string()],
+            ..TokenMetadata::default()
+        };
+        let info = TokenInfo::new(
+            "hash".to_string(),
+            token_id(&token),
+            OrgID::new(),
+            metadata,
+            None,
+        );
+        assert_eq!(info.token_id, "abcdefgh");
+        assert!(info.is_expired(now));
+        assert!(!info.is_expired(now - Duration::seconds(1)));
+        assert!(info.has_scope(TokenScope::Ingest));
+        assert!(!info.has_scope(TokenScope::Read));
+        assert!(info.allows_hostname("host"));
+        assert!(!info.allows_hostname("other"));
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["token_id"], "abcdefgh");
+        assert!(json.get("org_id").is_none());
+        assert!(json.get("hash").is_none());
+        assert_eq!(json["scopes"], serde_json::json!(["ingest"]));
+        assert!(json.get("rotation").is_none());
+    }
+
+    #[test]
+    fn test_token_rotation() {
+        let token: MessageToken = "abcdefghijklmnop".to_string();
+        let successor: MessageToken = "qrstuvwxyzabcdef".to_string();
+        let created_at = Utc::now() - Duration::days(10);
+        let metadata = TokenMetadata {
+            name: "ci".to_string(),
+            created_at: Some(created_at),
+            expires_at: Some(created_at + Duration::days(30)),
+            ..TokenMetadata::default()
+        };
+        let mut info = TokenInfo::new(
+            "hash".to_string(),
+            token_id(&token),
+            OrgID::new(),
+            metadata,
+            None,
+        );
+        let now = Utc::now();
+        info.use_host("b", now - Duration::hours(1));
+        info.use_host("a", now - Duration::hours(2));
+
+        let next = info.metadata.successor(now);
+        assert_eq!(next.name, "ci");
+        assert_eq!(next.created_at, Some(now));
+        assert_eq!(next.expires_at, Some(now + Duration::days(30)));
+
+        info.rotate(&successor, now, Duration::hours(24));
+        assert_eq!(info.metadata.expires_at, Some(now + Duration::hours(24)));
+        assert_eq!(info.rotation.as_ref().unwrap().successor, "qrstuvwx");
+        assert!(info.hosts_since_rotation().is_empty());
+        info.use_host("b", now + Duration::minutes(1));
+        info.use_host("c", now + Duration::minutes(2));
+        let hostnames: Vec<String> = info
+            .hosts_since_rotation()
+            .into_iter()
+            .map(|host| host.hostname)
+            .collect();
+        assert_eq!(hostnames, ["b", "c"]);
+
+        // rotating doesn't extend the validity
+        info.rotate(&successor, now, Duration::days(365));
+        assert_eq!(info.metadata.expires_at, Some(now + Duration::hours(24)));
+    }
+}
@@ -1,7 +1,9 @@
 use derive_more::{Display, FromStr};
 
 use crate::api::registration::RegistrationRequest;
+use crate::persistence::SESSION_MAX_AGE;
 use crate::service::authentication::hash_password;
+use chrono::{DateTime, Duration, Utc};
 use rdkafka::message::ToBytes;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
@@ -10,6 +12,56 @@ use uuid;
 use uuid::Uuid;
 
 pub(crate) type Nonce = String;
+pub(crate) type SessionID = String;
+
+/// A login of a user, listed so that users can revoke logins they don't recognize.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SessionInfo {
+    pub(crate) session_id: SessionID,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) ip: Option<String>,
+    pub(crate) user_agent: Option<String>,
+}
+
+impl SessionInfo {
+    /// Whether the session is past its max-age, see [`SESSION_MAX_AGE`].
+    pub(crate) fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.created_at + Duration::seconds(SESSION_MAX_AGE) <= now
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn example(session_id: &str) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            created_at: Utc::now(),
+            ip: None,
+            user_agent: None,
+        }
+    }
+}
+
+/// TOTP enrollment of a user, only checked at login once `enabled`. The secret
+/// is base32 encoded as in provisioning URIs, recovery codes are stored hashed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TwoFactor {
+    pub(crate) secret: String,
+    pub(crate) enabled: bool,
+    pub(crate) recovery_code_hashes: Vec<String>,
+    /// The last accepted time step, codes are not accepted twice.
+    pub(crate) last_time_step: Option<u64>,
+}
+
+impl TwoFactor {
+    #[allow(dead_code)]
+    pub(crate) fn example() -> Self {
+        Self {
+            secret: "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".to_string(),
+            enabled: true,
+            recovery_code_hashes: Vec::new(),
+            last_time_step: None,
+        }
+    }
+}
 
 #[derive(
     Serialize, Deserialize, Debug, Display, FromStr, Hash, Ord, Eq, PartialOrd, PartialEq, Clone,
@@ -40,11 +92,35 @@ impl From<String> for UserID {
     }
 }
 
+/// What a user may do across all organizations, independent of the roles in
+/// them.
+#[derive(
+    Serialize, Deserialize, Debug, Display, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UserRole {
+    /// Reads data of the own organizations but changes nothing in them.
+    #[display(fmt = "read_only")]
+    ReadOnly,
+    #[default]
+    #[display(fmt = "user")]
+    User,
+    /// Additionally manages users.
+    #[display(fmt = "admin")]
+    Admin,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct User {
     pub user_id: UserID,
     pub email: String,
     pub password_hash: String,
+    /// Users stored before roles existed are regular users.
+    #[serde(default)]
+    pub(crate) role: UserRole,
+    /// Suspended by an admin, the user can't log in.
+    #[serde(default)]
+    pub(crate) suspended: bool,
 }
 
 impl User {
@@ -54,6 +130,8 @@ impl User {
             user_id: UserID::new(),
             email,
             password_hash,
+            role: UserRole::default(),
+            suspended: false,
         }
     }
 
@@ -0,0 +1,1087 @@
+use crate::errors::APIInternalError;
+use crate::model::message::{MessageBackend, MessageToken};
+use crate::model::notification_rule::NotificationRule;
+use crate::model::organization::{Invitation, OrgID, Organization, Role};
+use crate::model::token::{token_id, TokenHash, TokenInfo, TokenMetadata};
+use crate::model::user::{Nonce, SessionID, SessionInfo, TwoFactor, User, UserID};
+use crate::persistence::redis::NotificationSettings;
+use crate::persistence::token::{TokenStore, TOKEN_LENGTH};
+use crate::persistence::{
+    MessageKey, PersistEmailChange, PersistIdempotencyKey, PersistMessage,
+    PersistNotificationRules, PersistNotificationSettings, PersistOrganization,
+    PersistPasswordReset, PersistPendingUser, PersistRateLimit, PersistSession, PersistTwoFactor,
+    PersistUser, MAX_MESSAGES, TTL,
+};
+use crate::service::token::{hash_token, random_alphanumeric_string};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+
+#[derive(Debug, Default)]
+struct InMemoryData {
+    users: HashMap<UserID, User>,
+    user_emails: HashMap<String, UserID>,
+    users_pending: HashMap<Nonce, (User, DateTime<Utc>)>,
+    notification_settings: HashMap<OrgID, NotificationSettings>,
+    notification_rules: HashMap<OrgID, Vec<NotificationRule>>,
+    messages: HashMap<MessageKey, Vec<MessageBackend>>,
+    tokens: HashMap<TokenHash, TokenInfo>,
+    idempotency_keys: HashMap<(OrgID, String), DateTime<Utc>>,
+    password_resets: HashMap<Nonce, (UserID, DateTime<Utc>)>,
+    email_changes: HashMap<Nonce, (UserID, String, DateTime<Utc>)>,
+    sessions: HashMap<UserID, BTreeMap<SessionID, SessionInfo>>,
+    attempts: HashMap<String, (u64, DateTime<Utc>)>,
+    blocks: HashMap<String, DateTime<Utc>>,
+    two_factors: HashMap<UserID, TwoFactor>,
+    organizations: HashMap<OrgID, Organization>,
+    members: HashMap<OrgID, BTreeMap<UserID, Role>>,
+    invitations: HashMap<Nonce, (Invitation, DateTime<Utc>)>,
+}
+
+impl InMemoryData {
+    fn delete_organization(&mut self, org_id: &OrgID) {
+        self.organizations.remove(org_id);
+        self.members.remove(org_id);
+        self.invitations
+            .retain(|_, (invitation, _)| &invitation.org_id != org_id);
+        self.notification_settings.remove(org_id);
+        self.notification_rules.remove(org_id);
+        self.messages.retain(|key, _| &key.org_id != org_id);
+        self.tokens.retain(|_, info| &info.org_id != org_id);
+        self.idempotency_keys
+            .retain(|(owner, _), _| owner != org_id);
+    }
+
+    /// Rotated tokens are removed at the end of their grace window.
+    fn remove_expired_rotations(&mut self) {
+        let now = Utc::now();
+        self.tokens
+            .retain(|_, info| info.rotation.is_none() || !info.is_expired(now));
+    }
+}
+
+/// Keeps all data in process memory. Intended for tests and local development,
+/// nothing survives a restart. Clones share the same data.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryDatabaseService {
+    data: Arc<Mutex<InMemoryData>>,
+}
+
+impl InMemoryDatabaseService {
+    fn data(&self) -> MutexGuard<'_, InMemoryData> {
+        self.data.lock().expect("in-memory database poisoned")
+    }
+}
+
+impl PersistMessage for InMemoryDatabaseService {
+    async fn add_message(&mut self, key: &MessageKey, message: &MessageBackend) -> Result<()> {
+        self.data()
+            .messages
+            .entry(key.clone())
+            .or_default()
+            .push(message.clone());
+        Ok(())
+    }
+
+    async fn find_messages(&mut self, key: &MessageKey) -> Result<Vec<MessageBackend>> {
+        let data = self.data();
+        let messages = data
+            .messages
+            .get(key)
+            .map(|messages| {
+                let skip = messages.len().saturating_sub(MAX_MESSAGES as usize + 1);
+                messages.iter().skip(skip).cloned().collect()
+            })
+            .unwrap_or_default();
+        Ok(messages)
+    }
+
+    async fn get_hostnames(&mut self, org_id: &OrgID) -> Result<Vec<String>> {
+        let hostnames: BTreeSet<String> = self
+            .data()
+            .messages
+            .keys()
+            .filter(|key| &key.org_id == org_id)
+            .map(|key| key.hostname.clone())
+            .collect();
+        Ok(hostnames.into_iter().collect())
+    }
+}
+
+impl PersistUser for InMemoryDatabaseService {
+    async fn add_user(&mut self, user: &User) -> Result<()> {
+        let mut data = self.data();
+        data.users.insert(user.user_id.clone(), user.clone());
+        data.user_emails
+            .insert(user.email.clone(), user.user_id.clone());
+        Ok(())
+    }
+
+    async fn update_user(&mut self, user: &User) -> Result<()> {
+        let mut data = self.data();
+        let stored = data
+            .users
+            .get_mut(&user.user_id)
+            .ok_or(anyhow!("no user with id {}", user.user_id))?;
+        *stored = user.clone();
+        Ok(())
+    }
+
+    async fn delete_user(&mut self, user_id: &UserID) -> Result<()> {
+        let mut data = self.data();
+        if let Some(user) = data.users.remove(user_id) {
+            data.users_pending
+                .retain(|_, (pending, _)| pending.email != user.email);
+        }
+        data.user_emails.retain(|_, owner| owner != user_id);
+        data.delete_organization(&OrgID::personal(user_id));
+        for members in data.members.values_mut() {
+            members.remove(user_id);
+        }
+        data.password_resets
+            .retain(|_, (owner, _)| owner != user_id);
+        data.email_changes
+            .retain(|_, (owner, _, _)| owner != user_id);
+        data.sessions.remove(user_id);
+        data.two_factors.remove(user_id);
+        info!("deleted user {user_id}");
+        Ok(())
+    }
+
+    async fn get_user_by_id(&mut self, user_id: &UserID) -> Result<User> {
+        self.data()
+            .users
+            .get(user_id)
+            .cloned()
+            .ok_or(anyhow!("no user with id {user_id}"))
+    }
+
+    async fn get_user_by_email(&mut self, email: &str) -> Result<Option<User>> {
+        let data = self.data();
+        let user = data
+            .user_emails
+            .get(email)
+            .and_then(|user_id| data.users.get(user_id))
+            .cloned();
+        Ok(user)
+    }
+
+    async fn get_user_index(&mut self) -> Result<Vec<(String, UserID)>> {
+        Ok(self
+            .data()
+            .user_emails
+            .iter()
+            .map(|(email, user_id)| (email.clone(), user_id.clone()))
+            .collect())
+    }
+
+    /// Users in memory were always indexed.
+    async fn migrate_user_index(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+
+    async fn change_email(&mut self, user_id: &UserID, email: &str) -> Result<()> {
+        let mut data = self.data();
+        let user = data
+            .users
+            .get_mut(user_id)
+            .ok_or(anyhow!("no user with id {user_id}"))?;
+        let old_email = std::mem::replace(&mut user.email, email.to_string());
+        data.user_emails.remove(&old_email);
+        data.user_emails.insert(email.to_string(), user_id.clone());
+        Ok(())
+    }
+}
+
+impl PersistPendingUser for InMemoryDatabaseService {
+    async fn add_user_pending(&mut self, user: &User, nonce: &Nonce) -> Result<()> {
+        if self.get_user_by_email(&user.email).await?.is_some() {
+            info!("not adding user pending as user already exists: {user}");
+            return Err(Error::new(APIInternalError::UserAlreadyExists(
+                user.clone(),
+            )));
+        }
+        let expires_at = Utc::now() + Duration::seconds(TTL::PendingUser as i64);
+        self.data()
+            .users_pending
+            .insert(nonce.clone(), (user.clone(), expires_at));
+        Ok(())
+    }
+
+    async fn get_user_pending(&mut self, nonce: &Nonce) -> Result<User> {
+        let mut data = self.data();
+        data.users_pending
+            .retain(|_, (_, expires_at)| *expires_at > Utc::now());
+        data.users_pending
+            .get(nonce)
+            .map(|(user, _)| user.clone())
+            .ok_or(anyhow!("no pending user for nonce {nonce}"))
+    }
+
+    async fn delete_user_pending(&mut self, nonce: &Nonce) -> Result<()> {
+        self.data().users_pending.remove(nonce);
+        Ok(())
+    }
+
+    async fn get_users_pending(&mut self) -> Result<Vec<User>> {
+        let now = Utc::now();
+        let pending = self
+            .data()
+            .users_pending
+            .values()
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(user, _)| user.clone())
+            .collect();
+        Ok(pending)
+    }
+
+    async fn get_nonce_of_user_pending(&mut self, user_id: &UserID) -> Result<Option<Nonce>> {
+        let now = Utc::now();
+        Ok(self
+            .data()
+            .users_pending
+            .iter()
+            .find(|(_, (user, expires_at))| &user.user_id == user_id && *expires_at > now)
+            .map(|(nonce, _)| nonce.clone()))
+    }
+}
+
+impl PersistPasswordReset for InMemoryDatabaseService {
+    async fn add_password_reset(&mut self, user_id: &UserID, nonce: &Nonce) -> Result<()> {
+        let expires_at = Utc::now() + Duration::seconds(TTL::PasswordReset as i64);
+        self.data()
+            .password_resets
+            .insert(nonce.clone(), (user_id.clone(), expires_at));
+        Ok(())
+    }
+
+    async fn take_password_reset(&mut self, nonce: &Nonce) -> Result<Option<UserID>> {
+        Ok(self
+            .data()
+            .password_resets
+            .remove(nonce)
+            .filter(|(_, expires_at)| *expires_at > Utc::now())
+            .map(|(user_id, _)| user_id))
+    }
+}
+
+impl PersistEmailChange for InMemoryDatabaseService {
+    async fn add_email_change(
+        &mut self,
+        user_id: &UserID,
+        email: &str,
+        nonce: &Nonce,
+    ) -> Result<()> {
+        let expires_at = Utc::now() + Duration::seconds(TTL::EmailChange as i64);
+        self.data().email_changes.insert(
+            nonce.clone(),
+            (user_id.clone(), email.to_string(), expires_at),
+        );
+        Ok(())
+    }
+
+    async fn take_email_change(&mut self, nonce: &Nonce) -> Result<Option<(UserID, String)>> {
+        Ok(self
+            .data()
+            .email_changes
+            .remove(nonce)
+            .filter(|(_, _, expires_at)| *expires_at > Utc::now())
+            .map(|(user_id, email, _)| (user_id, email)))
+    }
+}
+
+impl PersistSession for InMemoryDatabaseService {
+    async fn add_session(&mut self, user_id: &UserID, session: &SessionInfo) -> Result<()> {
+        self.data()
+            .sessions
+            .entry(user_id.clone())
+            .or_default()
+            .insert(session.session_id.clone(), session.clone());
+        Ok(())
+    }
+
+    async fn get_sessions(&mut self, user_id: &UserID) -> Result<Vec<SessionInfo>> {
+        let now = Utc::now();
+        let mut data = self.data();
+        let Some(sessions) = data.sessions.get_mut(user_id) else {
+            return Ok(Vec::new());
+        };
+        sessions.retain(|_, session| !session.is_expired(now));
+        Ok(sessions.values().cloned().collect())
+    }
+
+    async fn has_session(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<bool> {
+        Ok(self
+            .data()
+            .sessions
+            .get(user_id)
+            .is_some_and(|sessions| sessions.contains_key(session_id)))
+    }
+
+    async fn delete_session(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<bool> {
+        Ok(self
+            .data()
+            .sessions
+            .get_mut(user_id)
+            .and_then(|sessions| sessions.remove(session_id))
+            .is_some())
+    }
+
+    async fn delete_sessions(&mut self, user_id: &UserID, keep: Option<&SessionID>) -> Result<()> {
+        if let Some(sessions) = self.data().sessions.get_mut(user_id) {
+            sessions.retain(|session_id, _| Some(session_id) == keep);
+        }
+        Ok(())
+    }
+}
+
+impl PersistRateLimit for InMemoryDatabaseService {
+    async fn count_attempt(&mut self, key: &str, window_seconds: i64) -> Result<u64> {
+        let mut data = self.data();
+        let now = Utc::now();
+        data.attempts.retain(|_, (_, expires_at)| *expires_at > now);
+        let (attempts, _) = data
+            .attempts
+            .entry(key.to_string())
+            .or_insert((0, now + Duration::seconds(window_seconds)));
+        *attempts += 1;
+        Ok(*attempts)
+    }
+
+    async fn block(&mut self, key: &str, seconds: i64) -> Result<()> {
+        self.data()
+            .blocks
+            .insert(key.to_string(), Utc::now() + Duration::seconds(seconds));
+        Ok(())
+    }
+
+    async fn blocked_for(&mut self, key: &str) -> Result<Option<i64>> {
+        let now = Utc::now();
+        Ok(self
+            .data()
+            .blocks
+            .get(key)
+            .filter(|blocked_until| **blocked_until > now)
+            .map(|blocked_until| (*blocked_until - now).num_seconds().max(1)))
+    }
+
+    async fn reset_attempts(&mut self, key: &str) -> Result<()> {
+        let mut data = self.data();
+        data.attempts.remove(key);
+        data.blocks.remove(key);
+        Ok(())
+    }
+}
+
+impl PersistTwoFactor for InMemoryDatabaseService {
+    async fn get_two_factor(&mut self, user_id: &UserID) -> Result<Option<TwoFactor>> {
+        Ok(self.data().two_factors.get(user_id).cloned())
+    }
+
+    async fn set_two_factor(&mut self, user_id: &UserID, two_factor: &TwoFactor) -> Result<()> {
+        self.data()
+            .two_factors
+            .insert(user_id.clone(), two_factor.clone());
+        Ok(())
+    }
+
+    async fn delete_two_factor(&mut self, user_id: &UserID) -> Result<()> {
+        self.data().two_factors.remove(user_id);
+        Ok(())
+    }
+}
+
+impl PersistNotificationSettings for InMemoryDatabaseService {
+    async fn get_notification_settings(&mut self, org_id: &OrgID) -> Result<NotificationSettings> {
+        Ok(self
+            .data()
+            .notification_settings
+            .get(org_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_notification_settings(
+        &mut self,
+        org_id: &OrgID,
+        notification_settings: NotificationSettings,
+    ) -> Result<()> {
+        self.data()
+            .notification_settings
+            .insert(org_id.clone(), notification_settings);
+        Ok(())
+    }
+}
+
+impl PersistIdempotencyKey for InMemoryDatabaseService {
+    async fn claim_idempotency_key(&mut self, org_id: &OrgID, key: &str) -> Result<bool> {
+        let mut data = self.data();
+        let now = Utc::now();
+        data.idempotency_keys
+            .retain(|_, expires_at| *expires_at > now);
+        let expires_at = now + Duration::seconds(TTL::IdempotencyKey as i64);
+        Ok(data
+            .idempotency_keys
+            .insert((org_id.clone(), key.to_string()), expires_at)
+            .is_none())
+    }
+
+    async fn release_idempotency_key(&mut self, org_id: &OrgID, key: &str) -> Result<()> {
+        self.data()
+            .idempotency_keys
+            .remove(&(org_id.clone(), key.to_string()));
+        Ok(())
+    }
+}
+
+impl PersistNotificationRules for InMemoryDatabaseService {
+    async fn get_notification_rules(&mut self, org_id: &OrgID) -> Result<Vec<NotificationRule>> {
+        Ok(self
+            .data()
+            .notification_rules
+            .get(org_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_notification_rules(
+        &mut self,
+        org_id: &OrgID,
+        rules: &[NotificationRule],
+    ) -> Result<()> {
+        let rules = rules.to_vec();
+        rules.iter().for_each(NotificationRule::compile);
+        self.data().notification_rules.insert(org_id.clone(), rules);
+        Ok(())
+    }
+}
+
+impl PersistOrganization for InMemoryDatabaseService {
+    async fn add_organization(
+        &mut self,
+        organization: &Organization,
+        owner: &UserID,
+    ) -> Result<()> {
+        let mut data = self.data();
+        data.organizations
+            .insert(organization.org_id.clone(), organization.clone());
+        data.members
+            .entry(organization.org_id.clone())
+            .or_default()
+            .insert(owner.clone(), Role::Owner);
+        Ok(())
+    }
+
+    async fn get_organization(&mut self, org_id: &OrgID) -> Result<Option<Organization>> {
+        Ok(self.data().organizations.get(org_id).cloned())
+    }
+
+    async fn delete_organization(&mut self, org_id: &OrgID) -> Result<()> {
+        self.data().delete_organization(org_id);
+        info!("deleted organization {org_id}");
+        Ok(())
+    }
+
+    async fn set_member(&mut self, org_id: &OrgID, user_id: &UserID, role: Role) -> Result<()> {
+        self.data()
+            .members
+            .entry(org_id.clone())
+            .or_default()
+            .insert(user_id.clone(), role);
+        Ok(())
+    }
+
+    async fn remove_member(&mut self, org_id: &OrgID, user_id: &UserID) -> Result<()> {
+        if let Some(members) = self.data().members.get_mut(org_id) {
+            members.remove(user_id);
+        }
+        Ok(())
+    }
+
+    async fn get_members(&mut self, org_id: &OrgID) -> Result<Vec<(UserID, Role)>> {
+        Ok(self
+            .data()
+            .members
+            .get(org_id)
+            .map(|members| {
+                members
+                    .iter()
+                    .map(|(user_id, role)| (user_id.clone(), *role))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_role(&mut self, org_id: &OrgID, user_id: &UserID) -> Result<Option<Role>> {
+        Ok(self
+            .data()
+            .members
+            .get(org_id)
+            .and_then(|members| members.get(user_id))
+            .copied())
+    }
+
+    async fn get_organizations_of_user(&mut self, user_id: &UserID) -> Result<Vec<(OrgID, Role)>> {
+        let data = self.data();
+        let mut organizations: Vec<(OrgID, Role)> = data
+            .members
+            .iter()
+            .filter_map(|(org_id, members)| Some((org_id.clone(), *members.get(user_id)?)))
+            .collect();
+        organizations.sort();
+        Ok(organizations)
+    }
+
+    async fn add_invitation(&mut self, nonce: &Nonce, invitation: &Invitation) -> Result<()> {
+        let expires_at = Utc::now() + Duration::seconds(TTL::Invitation as i64);
+        self.data()
+            .invitations
+            .insert(nonce.clone(), (invitation.clone(), expires_at));
+        Ok(())
+    }
+
+    async fn get_invitation(&mut self, nonce: &Nonce) -> Result<Option<Invitation>> {
+        Ok(self
+            .data()
+            .invitations
+            .get(nonce)
+            .filter(|(_, expires_at)| *expires_at > Utc::now())
+            .map(|(invitation, _)| invitation.clone()))
+    }
+
+    async fn take_invitation(&mut self, nonce: &Nonce) -> Result<Option<Invitation>> {
+        Ok(self
+            .data()
+            .invitations
+            .remove(nonce)
+            .filter(|(_, expires_at)| *expires_at > Utc::now())
+            .map(|(invitation, _)| invitation))
+    }
+
+    async fn migrate_personal_organizations(&mut self) -> Result<usize> {
+        let user_ids: Vec<UserID> = self.data().users.keys().cloned().collect();
+        let mut migrated = 0;
+        for user_id in user_ids {
+            let org_id = OrgID::personal(&user_id);
+            if self.get_organization(&org_id).await?.is_none() {
+                self.add_organization(&Organization::personal(&user_id), &user_id)
+                    .await?;
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
+    }
+}
+
+impl TokenStore for InMemoryDatabaseService {
+    async fn create_token(
+        &mut self,
+        org_id: &OrgID,
+        metadata: &TokenMetadata,
+    ) -> Result<MessageToken> {
+        let token = random_alphanumeric_string(TOKEN_LENGTH);
+        let hash = hash_token(&token);
+        let info = TokenInfo::new(
+            hash.clone(),
+            token_id(&token),
+            org_id.clone(),
+            metadata.clone(),
+            None,
+        );
+        self.data().tokens.insert(hash, info);
+        Ok(token)
+    }
+
+    async fn get_tokens_of_org(&mut self, org_id: &OrgID) -> Result<Vec<TokenHash>> {
+        let mut data = self.data();
+        data.remove_expired_rotations();
+        Ok(data
+            .tokens
+            .iter()
+            .filter(|(_, info)| &info.org_id == org_id)
+            .map(|(hash, _)| hash.clone())
+            .collect())
+    }
+
+    async fn get_token_info(&mut self, hash: &TokenHash) -> Result<Option<TokenInfo>> {
+        let mut data = self.data();
+        data.remove_expired_rotations();
+        Ok(data.tokens.get(hash).cloned())
+    }
+
+    async fn set_token_last_used(
+        &mut self,
+        hash: &TokenHash,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<()> {
+        if let Some(info) = self.data().tokens.get_mut(hash) {
+            info.last_used_at = Some(last_used_at);
+        }
+        Ok(())
+    }
+
+    async fn set_token_host_used(
+        &mut self,
+        hash: &TokenHash,
+        hostname: &str,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<()> {
+        if let Some(info) = self.data().tokens.get_mut(hash) {
+            info.use_host(hostname, last_used_at);
+        }
+        Ok(())
+    }
+
+    async fn rotate_token(
+        &mut self,
+        hash: &TokenHash,
+        now: DateTime<Utc>,
+        grace: Duration,
+    ) -> Result<MessageToken> {
+        let successor = random_alphanumeric_string(TOKEN_LENGTH);
+        let mut data = self.data();
+        let info = data.tokens.get_mut(hash).ok_or(anyhow!("unknown token"))?;
+        let next = TokenInfo::new(
+            hash_token(&successor),
+            token_id(&successor),
+            info.org_id.clone(),
+            info.metadata.successor(now),
+            None,
+        );
+        info.rotate(&successor, now, grace);
+        data.tokens.insert(next.hash.clone(), next);
+        Ok(successor)
+    }
+
+    async fn delete_token(&mut self, hash: &TokenHash) -> Result<()> {
+        self.data()
+            .tokens
+            .remove(hash)
+            .map(|_| ())
+            .ok_or(anyhow!("unknown token"))
+    }
+
+    /// Tokens in memory were always hashed.
+    async fn migrate_plaintext_tokens(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::SESSION_MAX_AGE;
+
+    #[tokio::test]
+    async fn test_add_delete_user() {
+        let mut test_user = User::example();
+        test_user.email = "x.x@x.x".to_string();
+        let mut db = InMemoryDatabaseService::default();
+
+        db.add_user(&test_user).await.unwrap();
+        let x = db
+            .get_user_by_email(&test_user.email)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(x, test_user);
+
+        db.delete_user(&test_user.user_id).await.unwrap();
+        assert!(db.get_user_by_id(&test_user.user_id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_user_data() {
+        let test_user = User::example();
+        let user_id = &test_user.user_id;
+        let org_id = &OrgID::personal(user_id);
+        let other_user = User::new("y.y@y.y".to_string(), "asdfasdfasdf".to_string());
+        let mut db = InMemoryDatabaseService::default();
+        for user in [&test_user, &other_user] {
+            let org_id = OrgID::personal(&user.user_id);
+            db.add_user(user).await.unwrap();
+            db.add_organization(&Organization::personal(&user.user_id), &user.user_id)
+                .await
+                .unwrap();
+            db.create_token_for_org(&org_id).await.unwrap();
+            db.add_session(&user.user_id, &SessionInfo::example("session"))
+                .await
+                .unwrap();
+            db.set_two_factor(&user.user_id, &TwoFactor::example())
+                .await
+                .unwrap();
+            db.set_notification_settings(&org_id, NotificationSettings::default())
+                .await
+                .unwrap();
+            db.set_notification_rules(&org_id, &[]).await.unwrap();
+            db.claim_idempotency_key(&org_id, "key").await.unwrap();
+            let key = MessageKey {
+                org_id: org_id.clone(),
+                hostname: "host".to_string(),
+            };
+            db.add_message(&key, &MessageBackend::default())
+                .await
+                .unwrap();
+        }
+        let other_org_id = &OrgID::personal(&other_user.user_id);
+        db.set_member(other_org_id, user_id, Role::Viewer)
+            .await
+            .unwrap();
+
+        db.delete_user(user_id).await.unwrap();
+        {
+            let data = db.data();
+            assert!(!data.users.contains_key(user_id));
+            assert!(!data.user_emails.values().any(|owner| owner == user_id));
+            assert!(!data.organizations.contains_key(org_id));
+            assert!(!data.members.contains_key(org_id));
+            assert!(!data.notification_settings.contains_key(org_id));
+            assert!(!data.notification_rules.contains_key(org_id));
+            assert!(!data.messages.keys().any(|key| &key.org_id == org_id));
+            assert!(!data.tokens.values().any(|info| &info.org_id == org_id));
+            assert!(!data.sessions.contains_key(user_id));
+            assert!(!data.two_factors.contains_key(user_id));
+            assert!(!data
+                .idempotency_keys
+                .keys()
+                .any(|(owner, _)| owner == org_id));
+        }
+        assert!(db
+            .get_user_by_email(&test_user.email)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(db.get_members(other_org_id).await.unwrap().len(), 1);
+        assert_eq!(db.get_tokens_of_org(other_org_id).await.unwrap().len(), 1);
+        assert_eq!(db.get_hostnames(other_org_id).await.unwrap().len(), 1);
+
+        // deleting again is a no-op
+        db.delete_user(user_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_reset() {
+        let user_id = UserID::new();
+        let nonce: Nonce = "nonce".to_string();
+        let mut db = InMemoryDatabaseService::default();
+
+        db.add_password_reset(&user_id, &nonce).await.unwrap();
+        assert_eq!(db.take_password_reset(&nonce).await.unwrap(), Some(user_id));
+        assert_eq!(db.take_password_reset(&nonce).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_change_email() {
+        let test_user = User::example();
+        let mut db = InMemoryDatabaseService::default();
+        db.add_user(&test_user).await.unwrap();
+
+        db.change_email(&test_user.user_id, "y.y@y.y")
+            .await
+            .unwrap();
+        assert!(db
+            .get_user_by_email(&test_user.email)
+            .await
+            .unwrap()
+            .is_none());
+        let user = db.get_user_by_email("y.y@y.y").await.unwrap().unwrap();
+        assert_eq!(user.user_id, test_user.user_id);
+        assert_eq!(user.email, "y.y@y.y");
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let user_id = UserID::new();
+        let (first, second): (SessionID, SessionID) = ("first".to_string(), "second".to_string());
+        let mut db = InMemoryDatabaseService::default();
+        db.add_session(&user_id, &SessionInfo::example(&first))
+            .await
+            .unwrap();
+        db.add_session(&user_id, &SessionInfo::example(&second))
+            .await
+            .unwrap();
+        assert_eq!(db.get_sessions(&user_id).await.unwrap().len(), 2);
+        assert!(db.has_session(&user_id, &first).await.unwrap());
+        assert!(!db.has_session(&UserID::new(), &first).await.unwrap());
+
+        db.delete_sessions(&user_id, Some(&second)).await.unwrap();
+        assert!(!db.has_session(&user_id, &first).await.unwrap());
+        assert!(db.has_session(&user_id, &second).await.unwrap());
+        assert!(db.delete_session(&user_id, &second).await.unwrap());
+        assert!(!db.delete_session(&user_id, &second).await.unwrap());
+        assert!(db.get_sessions(&user_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_count_attempt() {
+        let mut db = InMemoryDatabaseService::default();
+        assert_eq!(db.count_attempt("key", 60).await.unwrap(), 1);
+        assert_eq!(db.count_attempt("key", 60).await.unwrap(), 2);
+        assert_eq!(db.count_attempt("other", 60).await.unwrap(), 1);
+        assert_eq!(db.count_attempt("expired", 0).await.unwrap(), 1);
+        assert_eq!(db.count_attempt("expired", 0).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let mut db = InMemoryDatabaseService::default();
+        assert_eq!(db.blocked_for("key").await.unwrap(), None);
+        db.count_attempt("key", 60).await.unwrap();
+        db.block("key", 60).await.unwrap();
+        assert!(db.blocked_for("key").await.unwrap().is_some());
+        assert_eq!(db.blocked_for("other").await.unwrap(), None);
+        db.block("expired", 0).await.unwrap();
+        assert_eq!(db.blocked_for("expired").await.unwrap(), None);
+
+        db.reset_attempts("key").await.unwrap();
+        assert_eq!(db.blocked_for("key").await.unwrap(), None);
+        assert_eq!(db.count_attempt("key", 60).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_confirm_user_pending() {
+        let test_user = User::example();
+        let nonce: Nonce = "nonce".to_string();
+        let mut db = InMemoryDatabaseService::default();
+
+        db.add_user_pending(&test_user, &nonce).await.unwrap();
+        db.confirm_user_pending(&nonce).await.unwrap();
+        assert!(db.get_user_pending(&nonce).await.is_err());
+        assert_eq!(
+            db.get_nonce_of_user_pending(&test_user.user_id)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            db.get_user_by_id(&test_user.user_id).await.unwrap(),
+            test_user
+        );
+        assert!(db.add_user_pending(&test_user, &nonce).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_messages() {
+        let org_id = OrgID::new();
+        let mut db = InMemoryDatabaseService::default();
+        let mut test_message = MessageBackend::default();
+
+        let n_hostnames = 3;
+        for i in 0..n_hostnames {
+            test_message.hostname = format!("testhostname-{}", i);
+            let key = MessageKey {
+                org_id: org_id.clone(),
+                hostname: test_message.hostname.clone(),
+            };
+            db.add_message(&key, &test_message).await.unwrap();
+            assert_eq!(db.find_messages(&key).await.unwrap().len(), 1);
+        }
+
+        let hostnames = db.get_hostnames(&org_id).await.unwrap();
+        assert_eq!(hostnames.len(), n_hostnames);
+        assert!(db.get_hostnames(&OrgID::new()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_token_store() {
+        let mut db = InMemoryDatabaseService::default();
+        let org_id = OrgID::new();
+        let token = db.create_token_for_org(&org_id).await.unwrap();
+        let _ = db.create_token_for_org(&org_id).await.unwrap();
+        assert_eq!(db.get_tokens_of_org(&org_id).await.unwrap().len(), 2);
+        assert_eq!(db.get_org_of_token(&token).await.unwrap(), Some(org_id));
+        assert_eq!(db.get_token_info(&token).await.unwrap(), None);
+
+        let hash = hash_token(&token);
+        let info = db.get_token_info(&hash).await.unwrap().unwrap();
+        assert_eq!(info.token_id, token_id(&token));
+        assert_eq!(info.metadata, TokenMetadata::default());
+        assert_eq!(info.last_used_at, None);
+        let now = Utc::now();
+        db.set_token_last_used(&hash, now).await.unwrap();
+        let info = db.get_token_info(&hash).await.unwrap().unwrap();
+        assert_eq!(info.last_used_at, Some(now));
+
+        db.delete_token(&hash).await.unwrap();
+        assert_eq!(db.get_org_of_token(&token).await.unwrap(), None);
+        assert_eq!(db.get_token_info(&hash).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_token() {
+        let mut db = InMemoryDatabaseService::default();
+        let org_id = OrgID::new();
+        let token = db.create_token_for_org(&org_id).await.unwrap();
+        let now = Utc::now();
+        db.set_token_host_used(&hash_token(&token), "host", now)
+            .await
+            .unwrap();
+
+        let successor = db
+            .rotate_token(&hash_token(&token), now, Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(db.get_tokens_of_org(&org_id).await.unwrap().len(), 2);
+        for token in [&token, &successor] {
+            assert_eq!(
+                db.get_org_of_token(token).await.unwrap(),
+                Some(org_id.clone())
+            );
+        }
+        let info = db
+            .get_token_info(&hash_token(&successor))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(info.hosts.is_empty());
+
+        // the old token is rejected after the grace window
+        let rotated_at = Utc::now() - Duration::hours(2);
+        let newer = db
+            .rotate_token(&hash_token(&successor), rotated_at, Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(db.get_org_of_token(&successor).await.unwrap(), None);
+        assert_eq!(db.get_org_of_token(&newer).await.unwrap(), Some(org_id));
+    }
+
+    #[tokio::test]
+    async fn test_claim_idempotency_key() {
+        let mut db = InMemoryDatabaseService::default();
+        let org_id = OrgID::new();
+        assert!(db.claim_idempotency_key(&org_id, "key").await.unwrap());
+        assert!(!db.claim_idempotency_key(&org_id, "key").await.unwrap());
+        assert!(db.claim_idempotency_key(&org_id, "other").await.unwrap());
+        assert!(db
+            .claim_idempotency_key(&OrgID::new(), "key")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_organizations() {
+        let owner = UserID::new();
+        let member = UserID::new();
+        let organization = Organization::new("team".to_string());
+        let org_id = &organization.org_id;
+        let mut db = InMemoryDatabaseService::default();
+
+        db.add_organization(&organization, &owner).await.unwrap();
+        assert_eq!(
+            db.get_organization(org_id).await.unwrap(),
+            Some(organization.clone())
+        );
+        db.set_member(org_id, &member, Role::Viewer).await.unwrap();
+        db.set_member(org_id, &member, Role::Admin).await.unwrap();
+        assert_eq!(
+            db.get_role(org_id, &member).await.unwrap(),
+            Some(Role::Admin)
+        );
+        assert_eq!(db.get_members(org_id).await.unwrap().len(), 2);
+        assert_eq!(
+            db.get_organizations_of_user(&member).await.unwrap(),
+            [(org_id.clone(), Role::Admin)]
+        );
+
+        db.remove_member(org_id, &member).await.unwrap();
+        assert_eq!(db.get_role(org_id, &member).await.unwrap(), None);
+        assert!(db
+            .get_organizations_of_user(&member)
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.create_token_for_org(org_id).await.unwrap();
+        db.delete_organization(org_id).await.unwrap();
+        assert_eq!(db.get_organization(org_id).await.unwrap(), None);
+        assert!(db
+            .get_organizations_of_user(&owner)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(db.get_tokens_of_org(org_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invitation() {
+        let nonce: Nonce = "nonce".to_string();
+        let invitation = Invitation {
+            org_id: OrgID::new(),
+            email: "x.x@x.x".to_string(),
+            role: Role::Viewer,
+            invited_by: UserID::new(),
+        };
+        let mut db = InMemoryDatabaseService::default();
+
+        db.add_invitation(&nonce, &invitation).await.unwrap();
+        assert_eq!(
+            db.get_invitation(&nonce).await.unwrap(),
+            Some(invitation.clone())
+        );
+        assert_eq!(db.take_invitation(&nonce).await.unwrap(), Some(invitation));
+        assert_eq!(db.take_invitation(&nonce).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_expired_sessions() {
+        let user_id = User::example().user_id;
+        let mut db = InMemoryDatabaseService::default();
+        let mut expired = SessionInfo::example("expired");
+        expired.created_at -= Duration::seconds(SESSION_MAX_AGE);
+        db.add_session(&user_id, &expired).await.unwrap();
+        db.add_session(&user_id, &SessionInfo::example("current"))
+            .await
+            .unwrap();
+
+        let sessions = db.get_sessions(&user_id).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id, "current");
+        assert!(!db
+            .has_session(&user_id, &"expired".to_string())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_expired_rotation() {
+        let org_id = OrgID::new();
+        let mut db = InMemoryDatabaseService::default();
+        let token = db.create_token_for_org(&org_id).await.unwrap();
+        let hash = hash_token(&token);
+
+        let successor = db
+            .rotate_token(&hash, Utc::now(), Duration::zero())
+            .await
+            .unwrap();
+        assert_eq!(db.get_token_info(&hash).await.unwrap(), None);
+        assert_eq!(
+            db.get_tokens_of_org(&org_id).await.unwrap(),
+            vec![hash_token(&successor)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate_personal_organizations() {
+        let test_user = User::example();
+        let mut db = InMemoryDatabaseService::default();
+        db.add_user(&test_user).await.unwrap();
+
+        assert_eq!(db.migrate_personal_organizations().await.unwrap(), 1);
+        assert_eq!(db.migrate_personal_organizations().await.unwrap(), 0);
+        let org_id = OrgID::personal(&test_user.user_id);
+        assert!(
+            db.get_organization(&org_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .personal
+        );
+        assert_eq!(
+            db.get_role(&org_id, &test_user.user_id).await.unwrap(),
+            Some(Role::Owner)
+        );
+    }
+}
@@ -1,22 +1,55 @@
+pub mod memory;
 pub mod redis;
+pub mod sql;
 pub mod token;
 
-use crate::model::message::MessageBackend;
-use crate::model::user::UserID;
+use crate::model::message::{MessageBackend, MessageToken};
+use crate::model::notification_rule::NotificationRule;
+use crate::model::organization::{Invitation, OrgID, Organization, Role};
+use crate::model::token::{TokenHash, TokenInfo, TokenMetadata};
+use crate::model::user::{Nonce, SessionID, SessionInfo, TwoFactor, User, UserID};
+use crate::persistence::memory::InMemoryDatabaseService;
+use crate::persistence::redis::{NotificationSettings, RedisDatabaseService};
+use crate::persistence::sql::SqlDatabaseService;
+use crate::persistence::token::TokenStore;
+use std::env;
 use std::format;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) const MAX_MESSAGES: isize = 1000;
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MINUTE: usize = 60;
+const DAY: usize = 60 * MINUTE * 24;
+
+pub(crate) enum TTL {
+    PendingUser = (15 * MINUTE) as isize,
+    Message = DAY as isize,
+    IdempotencyKey = (2 * DAY) as isize,
+    PasswordReset = (30 * MINUTE) as isize,
+    EmailChange = (60 * MINUTE) as isize,
+    Invitation = (7 * DAY) as isize,
+    /// Hosts of a token are forgotten once none used it for this long.
+    TokenHosts = (30 * DAY) as isize,
+}
+
+/// Seconds until a login session expires.
+pub(crate) const SESSION_MAX_AGE: i64 = DAY as i64;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct MessageKey {
-    pub user_id: UserID,
+    pub org_id: OrgID,
     pub hostname: String,
 }
 
 impl Default for MessageKey {
     fn default() -> Self {
         MessageKey {
-            user_id: UserID::default(),
+            org_id: OrgID::default(),
             hostname: "default_hostname".to_string(),
         }
     }
@@ -24,7 +57,88 @@ impl Default for MessageKey {
 
 impl MessageKey {
     fn to_redis_key(&self) -> String {
-        format!("messages:{}:{}", self.user_id, self.hostname)
+        format!("messages:{}:{}", self.org_id, self.hostname)
+    }
+}
+
+/// Filters and pages messages of one host. Pages are ordered newest first and
+/// `cursor` is the opaque `next_cursor` of the previous [`MessagePage`].
+#[derive(Debug, Default, Deserialize)]
+pub struct MessageQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub search: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<MessageBackend>,
+    pub next_cursor: Option<String>,
+    pub total: usize,
+}
+
+impl MessageQuery {
+    pub(crate) fn offset(&self) -> Result<usize> {
+        match &self.cursor {
+            None => Ok(0),
+            Some(cursor) => cursor
+                .parse()
+                .map_err(|_| anyhow!("invalid cursor: {cursor}")),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_MESSAGES as usize)
+    }
+
+    fn matches(&self, message: &MessageBackend) -> bool {
+        if self.since.is_some() || self.until.is_some() {
+            let Some(timestamp) = message.timestamp else {
+                return false;
+            };
+            if self.since.is_some_and(|since| timestamp < since)
+                || self.until.is_some_and(|until| timestamp > until)
+            {
+                return false;
+            }
+        }
+        match &self.search {
+            None => true,
+            Some(search) => {
+                let search = search.to_lowercase();
+                message.title.to_lowercase().contains(&search)
+                    || message.body.to_lowercase().contains(&search)
+            }
+        }
+    }
+
+    pub(crate) fn page(&self, total: usize, messages: Vec<MessageBackend>) -> Result<MessagePage> {
+        let next = self.offset()? + self.limit();
+        Ok(MessagePage {
+            messages,
+            next_cursor: (next < total).then(|| next.to_string()),
+            total,
+        })
+    }
+
+    /// Applies the query to messages in chronological order.
+    pub(crate) fn apply(&self, messages: Vec<MessageBackend>) -> Result<MessagePage> {
+        let filtered: Vec<MessageBackend> = messages
+            .into_iter()
+            .rev()
+            .filter(|message| self.matches(message))
+            .collect();
+        let total = filtered.len();
+        let messages = filtered
+            .into_iter()
+            .skip(self.offset()?)
+            .take(self.limit())
+            .collect();
+        self.page(total, messages)
     }
 }
 
@@ -36,5 +150,664 @@ pub trait PersistMessage {
     ) -> Result<()>;
 
     async fn find_messages(&mut self, message_key: &MessageKey) -> Result<Vec<MessageBackend>>;
-    async fn get_hostnames_of_user(&mut self, user_id: &UserID) -> Result<Vec<String>>;
+    async fn get_hostnames(&mut self, org_id: &OrgID) -> Result<Vec<String>>;
+
+    async fn query_messages(
+        &mut self,
+        message_key: &MessageKey,
+        query: &MessageQuery,
+    ) -> Result<MessagePage> {
+        let messages = self.find_messages(message_key).await?;
+        query.apply(messages)
+    }
+}
+
+pub trait PersistUser {
+    async fn add_user(&mut self, user: &User) -> Result<()>;
+    /// Saves changes to an existing user, e.g. its password or role. Fails if
+    /// the user doesn't exist (anymore). Use [`change_email`](Self::change_email)
+    /// for the email.
+    async fn update_user(&mut self, user: &User) -> Result<()>;
+    /// Deletes the user with everything stored for it: the email index, sessions,
+    /// memberships and the personal organization with its tokens, notification
+    /// settings and rules, messages and idempotency keys. Deleting an already
+    /// (partially) deleted user removes what is left.
+    async fn delete_user(&mut self, user_id: &UserID) -> Result<()>;
+    async fn get_user_by_id(&mut self, user_id: &UserID) -> Result<User>;
+    async fn get_user_by_email(&mut self, email: &str) -> Result<Option<User>>;
+    /// The email and id of all users in no particular order, for the admin API.
+    async fn get_user_index(&mut self) -> Result<Vec<(String, UserID)>>;
+    /// Indexes users stored before the user index existed and returns how many
+    /// were indexed.
+    async fn migrate_user_index(&mut self) -> Result<usize>;
+    /// Changes the email of the user and moves the email index along with it.
+    async fn change_email(&mut self, user_id: &UserID, email: &str) -> Result<()>;
+}
+
+pub trait PersistPendingUser: PersistUser {
+    async fn add_user_pending(&mut self, user: &User, nonce: &Nonce) -> Result<()>;
+    async fn get_user_pending(&mut self, nonce: &Nonce) -> Result<User>;
+    async fn delete_user_pending(&mut self, nonce: &Nonce) -> Result<()>;
+    /// Unexpired pending users, without the nonces of their confirmation links.
+    async fn get_users_pending(&mut self) -> Result<Vec<User>>;
+    /// The nonce of the confirmation link of an unexpired pending user.
+    async fn get_nonce_of_user_pending(&mut self, user_id: &UserID) -> Result<Option<Nonce>>;
+
+    async fn confirm_user_pending(&mut self, nonce: &Nonce) -> Result<()> {
+        let user = self.get_user_pending(nonce).await?;
+        self.add_user(&user).await?;
+        self.delete_user_pending(nonce).await?;
+        info!("confirmed {:?}", user);
+        Ok(())
+    }
+}
+
+/// Single-use nonces of password reset links, valid for [`TTL::PasswordReset`].
+pub trait PersistPasswordReset {
+    async fn add_password_reset(&mut self, user_id: &UserID, nonce: &Nonce) -> Result<()>;
+    /// Returns the user of an unexpired nonce and invalidates the nonce.
+    async fn take_password_reset(&mut self, nonce: &Nonce) -> Result<Option<UserID>>;
+}
+
+/// Email changes waiting for confirmation from the new address, valid for
+/// [`TTL::EmailChange`].
+pub trait PersistEmailChange {
+    async fn add_email_change(
+        &mut self,
+        user_id: &UserID,
+        email: &str,
+        nonce: &Nonce,
+    ) -> Result<()>;
+    /// Returns the user and new email of an unexpired nonce and invalidates the nonce.
+    async fn take_email_change(&mut self, nonce: &Nonce) -> Result<Option<(UserID, String)>>;
+}
+
+/// Logged-in sessions of a user. A session whose id is no longer stored is
+/// logged out on its next request. Sessions are dropped after [`SESSION_MAX_AGE`].
+pub trait PersistSession {
+    async fn add_session(&mut self, user_id: &UserID, session: &SessionInfo) -> Result<()>;
+    async fn get_sessions(&mut self, user_id: &UserID) -> Result<Vec<SessionInfo>>;
+    async fn has_session(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<bool>;
+    /// Returns false if the session did not exist.
+    async fn delete_session(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<bool>;
+    /// Deletes all sessions of the user except `keep`.
+    async fn delete_sessions(&mut self, user_id: &UserID, keep: Option<&SessionID>) -> Result<()>;
+}
+
+/// TOTP enrollments, see [`TwoFactor`].
+pub trait PersistTwoFactor {
+    async fn get_two_factor(&mut self, user_id: &UserID) -> Result<Option<TwoFactor>>;
+    async fn set_two_factor(&mut self, user_id: &UserID, two_factor: &TwoFactor) -> Result<()>;
+    async fn delete_two_factor(&mut self, user_id: &UserID) -> Result<()>;
+}
+
+/// Counts attempts per key in fixed windows, for rate limiting. Keys can
+/// additionally be blocked for a while, for backoff after failed logins.
+pub trait PersistRateLimit {
+    /// Records an attempt and returns the number of attempts in the current
+    /// window, including this one.
+    async fn count_attempt(&mut self, key: &str, window_seconds: i64) -> Result<u64>;
+    async fn block(&mut self, key: &str, seconds: i64) -> Result<()>;
+    /// Returns the remaining seconds of a block.
+    async fn blocked_for(&mut self, key: &str) -> Result<Option<i64>>;
+    /// Forgets the attempts and block of a key.
+    async fn reset_attempts(&mut self, key: &str) -> Result<()>;
+}
+
+pub trait PersistNotificationSettings {
+    async fn get_notification_settings(&mut self, org_id: &OrgID) -> Result<NotificationSettings>;
+    async fn set_notification_settings(
+        &mut self,
+        org_id: &OrgID,
+        notification_settings: NotificationSettings,
+    ) -> Result<()>;
+}
+
+/// Remembers idempotency keys of ingested messages for [`TTL::IdempotencyKey`].
+pub trait PersistIdempotencyKey {
+    /// Returns false if the organization already claimed the key.
+    async fn claim_idempotency_key(&mut self, org_id: &OrgID, key: &str) -> Result<bool>;
+    /// Forgets a claimed key, so that a message that failed to be stored can be
+    /// retried.
+    async fn release_idempotency_key(&mut self, org_id: &OrgID, key: &str) -> Result<()>;
+}
+
+/// Notification rules are stored as one ordered list per organization.
+pub trait PersistNotificationRules {
+    async fn get_notification_rules(&mut self, org_id: &OrgID) -> Result<Vec<NotificationRule>>;
+    async fn set_notification_rules(
+        &mut self,
+        org_id: &OrgID,
+        rules: &[NotificationRule],
+    ) -> Result<()>;
+}
+
+/// Organizations with their members. The role of a member is stored for the
+/// organization and for the user, both are updated together.
+pub trait PersistOrganization {
+    async fn add_organization(&mut self, organization: &Organization, owner: &UserID)
+        -> Result<()>;
+    async fn get_organization(&mut self, org_id: &OrgID) -> Result<Option<Organization>>;
+    /// Deletes the organization with its memberships, tokens, notification
+    /// settings and rules, messages and idempotency keys.
+    async fn delete_organization(&mut self, org_id: &OrgID) -> Result<()>;
+    /// Adds a member or changes its role.
+    async fn set_member(&mut self, org_id: &OrgID, user_id: &UserID, role: Role) -> Result<()>;
+    async fn remove_member(&mut self, org_id: &OrgID, user_id: &UserID) -> Result<()>;
+    async fn get_members(&mut self, org_id: &OrgID) -> Result<Vec<(UserID, Role)>>;
+    async fn get_role(&mut self, org_id: &OrgID, user_id: &UserID) -> Result<Option<Role>>;
+    async fn get_organizations_of_user(&mut self, user_id: &UserID) -> Result<Vec<(OrgID, Role)>>;
+    /// Invitations are valid for [`TTL::Invitation`].
+    async fn add_invitation(&mut self, nonce: &Nonce, invitation: &Invitation) -> Result<()>;
+    /// Returns an unexpired invitation without invalidating the nonce.
+    async fn get_invitation(&mut self, nonce: &Nonce) -> Result<Option<Invitation>>;
+    /// Returns an unexpired invitation and invalidates the nonce.
+    async fn take_invitation(&mut self, nonce: &Nonce) -> Result<Option<Invitation>>;
+    /// Creates the personal organizations of users from before organizations
+    /// existed and returns how many were created.
+    async fn migrate_personal_organizations(&mut self) -> Result<usize>;
+}
+
+/// The storage backend selected at startup via `SNITCH_PERSISTENCE`.
+#[derive(Clone, Debug)]
+pub enum Backend {
+    Redis(RedisDatabaseService),
+    InMemory(InMemoryDatabaseService),
+}
+
+/// Dispatches to the selected [`Backend`]. If `SNITCH_HISTORY_DATABASE_URL` is set,
+/// messages are additionally written to a SQL database which keeps them beyond
+/// the backend's retention. The backend then caches recent messages, see
+/// [`PersistMessage`] below for which reads it serves.
+#[derive(Clone, Debug)]
+pub struct Persistence {
+    backend: Backend,
+    history: Option<SqlDatabaseService>,
+}
+
+impl Persistence {
+    pub async fn new() -> Result<Self> {
+        let backend = env::var("SNITCH_PERSISTENCE").unwrap_or("redis".to_string());
+        info!("using {backend} persistence");
+        let backend = match backend.as_str() {
+            "redis" => Backend::Redis(RedisDatabaseService::new().await?),
+            "memory" => Backend::InMemory(InMemoryDatabaseService::default()),
+            _ => bail!("unknown SNITCH_PERSISTENCE: {backend}"),
+        };
+
+        let history = match env::var("SNITCH_HISTORY_DATABASE_URL") {
+            Ok(url) => Some(SqlDatabaseService::new(&url).await?),
+            Err(_) => None,
+        };
+        Ok(Persistence { backend, history })
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Persistence {
+            backend: Backend::InMemory(InMemoryDatabaseService::default()),
+            history: None,
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident.$method:ident($($arg:expr),*)) => {
+        match &mut $self.backend {
+            Backend::Redis(db) => db.$method($($arg),*).await,
+            Backend::InMemory(db) => db.$method($($arg),*).await,
+        }
+    };
+}
+
+impl PersistMessage for Persistence {
+    async fn add_message(&mut self, key: &MessageKey, message: &MessageBackend) -> Result<()> {
+        if let Some(history) = self.history.as_mut() {
+            history.add_message(key, message).await?;
+        }
+        if let Err(e) = dispatch!(self.add_message(key, message)) {
+            if self.history.is_none() {
+                return Err(e);
+            }
+            error!("failed caching message: {e}");
+        }
+        Ok(())
+    }
+
+    /// The backend serves the recent messages if it still caches as many as are
+    /// returned, older ones are read from the history.
+    async fn find_messages(&mut self, key: &MessageKey) -> Result<Vec<MessageBackend>> {
+        let cached = dispatch!(self.find_messages(key));
+        let Some(history) = self.history.as_mut() else {
+            return cached;
+        };
+        match cached {
+            Ok(messages) if messages.len() > MAX_MESSAGES as usize => Ok(messages),
+            Ok(_) => history.find_messages(key).await,
+            Err(e) => {
+                error!("failed reading cached messages: {e}");
+                history.find_messages(key).await
+            }
+        }
+    }
+
+    /// Reads only the history if configured, as filters, search and the total
+    /// cover all messages rather than the cached recent ones.
+    async fn query_messages(
+        &mut self,
+        key: &MessageKey,
+        query: &MessageQuery,
+    ) -> Result<MessagePage> {
+        match self.history.as_mut() {
+            Some(history) => history.query_messages(key, query).await,
+            None => dispatch!(self.query_messages(key, query)),
+        }
+    }
+
+    async fn get_hostnames(&mut self, org_id: &OrgID) -> Result<Vec<String>> {
+        let mut hostnames: BTreeSet<String> =
+            dispatch!(self.get_hostnames(org_id))?.into_iter().collect();
+        if let Some(history) = self.history.as_mut() {
+            hostnames.extend(history.get_hostnames(org_id).await?);
+        }
+        Ok(hostnames.into_iter().collect())
+    }
+}
+
+impl PersistUser for Persistence {
+    /// Also creates the personal organization of the user.
+    async fn add_user(&mut self, user: &User) -> Result<()> {
+        dispatch!(self.add_user(user))?;
+        self.add_organization(&Organization::personal(&user.user_id), &user.user_id)
+            .await
+    }
+
+    async fn update_user(&mut self, user: &User) -> Result<()> {
+        dispatch!(self.update_user(user))
+    }
+
+    /// Deletes the personal organization with its message history first, a
+    /// failure leaves the user in place so that the deletion can be retried.
+    async fn delete_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.delete_organization(&OrgID::personal(user_id)).await?;
+        dispatch!(self.delete_user(user_id))
+    }
+
+    async fn get_user_by_id(&mut self, user_id: &UserID) -> Result<User> {
+        dispatch!(self.get_user_by_id(user_id))
+    }
+
+    async fn get_user_by_email(&mut self, email: &str) -> Result<Option<User>> {
+        dispatch!(self.get_user_by_email(email))
+    }
+
+    async fn get_user_index(&mut self) -> Result<Vec<(String, UserID)>> {
+        dispatch!(self.get_user_index())
+    }
+
+    async fn migrate_user_index(&mut self) -> Result<usize> {
+        dispatch!(self.migrate_user_index())
+    }
+
+    async fn change_email(&mut self, user_id: &UserID, email: &str) -> Result<()> {
+        dispatch!(self.change_email(user_id, email))
+    }
+}
+
+impl PersistPendingUser for Persistence {
+    async fn add_user_pending(&mut self, user: &User, nonce: &Nonce) -> Result<()> {
+        dispatch!(self.add_user_pending(user, nonce))
+    }
+
+    async fn get_user_pending(&mut self, nonce: &Nonce) -> Result<User> {
+        dispatch!(self.get_user_pending(nonce))
+    }
+
+    async fn delete_user_pending(&mut self, nonce: &Nonce) -> Result<()> {
+        dispatch!(self.delete_user_pending(nonce))
+    }
+
+    async fn get_users_pending(&mut self) -> Result<Vec<User>> {
+        dispatch!(self.get_users_pending())
+    }
+
+    async fn get_nonce_of_user_pending(&mut self, user_id: &UserID) -> Result<Option<Nonce>> {
+        dispatch!(self.get_nonce_of_user_pending(user_id))
+    }
+}
+
+impl PersistPasswordReset for Persistence {
+    async fn add_password_reset(&mut self, user_id: &UserID, nonce: &Nonce) -> Result<()> {
+        dispatch!(self.add_password_reset(user_id, nonce))
+    }
+
+    async fn take_password_reset(&mut self, nonce: &Nonce) -> Result<Option<UserID>> {
+        dispatch!(self.take_password_reset(nonce))
+    }
+}
+
+impl PersistEmailChange for Persistence {
+    async fn add_email_change(
+        &mut self,
+        user_id: &UserID,
+        email: &str,
+        nonce: &Nonce,
+    ) -> Result<()> {
+        dispatch!(self.add_email_change(user_id, email, nonce))
+    }
+
+    async fn take_email_change(&mut self, nonce: &Nonce) -> Result<Option<(UserID, String)>> {
+        dispatch!(self.take_email_change(nonce))
+    }
+}
+
+impl PersistSession for Persistence {
+    async fn add_session(&mut self, user_id: &UserID, session: &SessionInfo) -> Result<()> {
+        dispatch!(self.add_session(user_id, session))
+    }
+
+    async fn get_sessions(&mut self, user_id: &UserID) -> Result<Vec<SessionInfo>> {
+        dispatch!(self.get_sessions(user_id))
+    }
+
+    async fn has_session(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<bool> {
+        dispatch!(self.has_session(user_id, session_id))
+    }
+
+    async fn delete_session(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<bool> {
+        dispatch!(self.delete_session(user_id, session_id))
+    }
+
+    async fn delete_sessions(&mut self, user_id: &UserID, keep: Option<&SessionID>) -> Result<()> {
+        dispatch!(self.delete_sessions(user_id, keep))
+    }
+}
+
+impl PersistRateLimit for Persistence {
+    async fn count_attempt(&mut self, key: &str, window_seconds: i64) -> Result<u64> {
+        dispatch!(self.count_attempt(key, window_seconds))
+    }
+
+    async fn block(&mut self, key: &str, seconds: i64) -> Result<()> {
+        dispatch!(self.block(key, seconds))
+    }
+
+    async fn blocked_for(&mut self, key: &str) -> Result<Option<i64>> {
+        dispatch!(self.blocked_for(key))
+    }
+
+    async fn reset_attempts(&mut self, key: &str) -> Result<()> {
+        dispatch!(self.reset_attempts(key))
+    }
+}
+
+impl PersistNotificationSettings for Persistence {
+    async fn get_notification_settings(&mut self, org_id: &OrgID) -> Result<NotificationSettings> {
+        dispatch!(self.get_notification_settings(org_id))
+    }
+
+    async fn set_notification_settings(
+        &mut self,
+        org_id: &OrgID,
+        notification_settings: NotificationSettings,
+    ) -> Result<()> {
+        dispatch!(self.set_notification_settings(org_id, notification_settings))
+    }
+}
+
+impl PersistIdempotencyKey for Persistence {
+    async fn claim_idempotency_key(&mut self, org_id: &OrgID, key: &str) -> Result<bool> {
+        dispatch!(self.claim_idempotency_key(org_id, key))
+    }
+
+    async fn release_idempotency_key(&mut self, org_id: &OrgID, key: &str) -> Result<()> {
+        dispatch!(self.release_idempotency_key(org_id, key))
+    }
+}
+
+impl PersistTwoFactor for Persistence {
+    async fn get_two_factor(&mut self, user_id: &UserID) -> Result<Option<TwoFactor>> {
+        dispatch!(self.get_two_factor(user_id))
+    }
+
+    async fn set_two_factor(&mut self, user_id: &UserID, two_factor: &TwoFactor) -> Result<()> {
+        dispatch!(self.set_two_factor(user_id, two_factor))
+    }
+
+    async fn delete_two_factor(&mut self, user_id: &UserID) -> Result<()> {
+        dispatch!(self.delete_two_factor(user_id))
+    }
+}
+
+impl PersistNotificationRules for Persistence {
+    async fn get_notification_rules(&mut self, org_id: &OrgID) -> Result<Vec<NotificationRule>> {
+        dispatch!(self.get_notification_rules(org_id))
+    }
+
+    async fn set_notification_rules(
+        &mut self,
+        org_id: &OrgID,
+        rules: &[NotificationRule],
+    ) -> Result<()> {
+        dispatch!(self.set_notification_rules(org_id, rules))
+    }
+}
+
+impl PersistOrganization for Persistence {
+    async fn add_organization(
+        &mut self,
+        organization: &Organization,
+        owner: &UserID,
+    ) -> Result<()> {
+        dispatch!(self.add_organization(organization, owner))
+    }
+
+    async fn get_organization(&mut self, org_id: &OrgID) -> Result<Option<Organization>> {
+        dispatch!(self.get_organization(org_id))
+    }
+
+    /// Deletes the message history first, like deleting a user.
+    async fn delete_organization(&mut self, org_id: &OrgID) -> Result<()> {
+        if let Some(history) = self.history.as_mut() {
+            history.delete_messages_of_org(org_id).await?;
+        }
+        dispatch!(self.delete_organization(org_id))
+    }
+
+    async fn set_member(&mut self, org_id: &OrgID, user_id: &UserID, role: Role) -> Result<()> {
+        dispatch!(self.set_member(org_id, user_id, role))
+    }
+
+    async fn remove_member(&mut self, org_id: &OrgID, user_id: &UserID) -> Result<()> {
+        dispatch!(self.remove_member(org_id, user_id))
+    }
+
+    async fn get_members(&mut self, org_id: &OrgID) -> Result<Vec<(UserID, Role)>> {
+        dispatch!(self.get_members(org_id))
+    }
+
+    async fn get_role(&mut self, org_id: &OrgID, user_id: &UserID) -> Result<Option<Role>> {
+        dispatch!(self.get_role(org_id, user_id))
+    }
+
+    async fn get_organizations_of_user(&mut self, user_id: &UserID) -> Result<Vec<(OrgID, Role)>> {
+        dispatch!(self.get_organizations_of_user(user_id))
+    }
+
+    async fn add_invitation(&mut self, nonce: &Nonce, invitation: &Invitation) -> Result<()> {
+        dispatch!(self.add_invitation(nonce, invitation))
+    }
+
+    async fn get_invitation(&mut self, nonce: &Nonce) -> Result<Option<Invitation>> {
+        dispatch!(self.get_invitation(nonce))
+    }
+
+    async fn take_invitation(&mut self, nonce: &Nonce) -> Result<Option<Invitation>> {
+        dispatch!(self.take_invitation(nonce))
+    }
+
+    async fn migrate_personal_organizations(&mut self) -> Result<usize> {
+        dispatch!(self.migrate_personal_organizations())
+    }
+}
+
+impl TokenStore for Persistence {
+    async fn create_token(
+        &mut self,
+        org_id: &OrgID,
+        metadata: &TokenMetadata,
+    ) -> Result<MessageToken> {
+        dispatch!(self.create_token(org_id, metadata))
+    }
+
+    async fn get_tokens_of_org(&mut self, org_id: &OrgID) -> Result<Vec<TokenHash>> {
+        dispatch!(self.get_tokens_of_org(org_id))
+    }
+
+    async fn get_token_info(&mut self, hash: &TokenHash) -> Result<Option<TokenInfo>> {
+        dispatch!(self.get_token_info(hash))
+    }
+
+    async fn set_token_last_used(
+        &mut self,
+        hash: &TokenHash,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<()> {
+        dispatch!(self.set_token_last_used(hash, last_used_at))
+    }
+
+    async fn set_token_host_used(
+        &mut self,
+        hash: &TokenHash,
+        hostname: &str,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<()> {
+        dispatch!(self.set_token_host_used(hash, hostname, last_used_at))
+    }
+
+    async fn rotate_token(
+        &mut self,
+        hash: &TokenHash,
+        now: DateTime<Utc>,
+        grace: Duration,
+    ) -> Result<MessageToken> {
+        dispatch!(self.rotate_token(hash, now, grace))
+    }
+
+    async fn delete_token(&mut self, hash: &TokenHash) -> Result<()> {
+        dispatch!(self.delete_token(hash))
+    }
+
+    async fn migrate_plaintext_tokens(&mut self) -> Result<usize> {
+        dispatch!(self.migrate_plaintext_tokens())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn test_messages() -> Vec<MessageBackend> {
+        (0..10)
+            .map(|i| MessageBackend {
+                hostname: "testhostname".to_string(),
+                title: format!("title-{i}"),
+                body: if i % 2 == 0 { "disk full" } else { "all good" }.to_string(),
+                timestamp: Some(Utc.timestamp_opt(1_700_000_000 + i * 60, 0).unwrap()),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_query_pagination() {
+        let mut query = MessageQuery {
+            limit: Some(4),
+            ..Default::default()
+        };
+        let page = query.apply(test_messages()).unwrap();
+        assert_eq!(page.total, 10);
+        assert_eq!(page.messages.len(), 4);
+        assert_eq!(page.messages[0].title, "title-9");
+        assert_eq!(page.next_cursor.as_deref(), Some("4"));
+
+        query.cursor = Some("8".to_string());
+        let page = query.apply(test_messages()).unwrap();
+        assert_eq!(page.messages.len(), 2);
+        assert_eq!(page.messages[1].title, "title-0");
+        assert_eq!(page.next_cursor, None);
+
+        query.cursor = Some("invalid".to_string());
+        assert!(query.apply(test_messages()).is_err());
+    }
+
+    #[test]
+    fn test_query_filter() {
+        let query = MessageQuery {
+            since: Some(Utc.timestamp_opt(1_700_000_000 + 2 * 60, 0).unwrap()),
+            until: Some(Utc.timestamp_opt(1_700_000_000 + 6 * 60, 0).unwrap()),
+            search: Some("DISK".to_string()),
+            ..Default::default()
+        };
+        let page = query.apply(test_messages()).unwrap();
+        let titles: Vec<&str> = page.messages.iter().map(|m| m.title.as_str()).collect();
+        assert_eq!(titles, vec!["title-6", "title-4", "title-2"]);
+        assert_eq!(page.total, 3);
+    }
+
+    #[tokio::test]
+    async fn test_update_user() {
+        let mut persist = Persistence::in_memory();
+        let mut user = User::new("x.x@x.x".to_string(), "asdfasdfasdf".to_string());
+        assert!(persist.update_user(&user).await.is_err());
+        persist.add_user(&user).await.unwrap();
+        let org_id = OrgID::personal(&user.user_id);
+        let personal = persist.get_organization(&org_id).await.unwrap().unwrap();
+
+        user.suspended = true;
+        persist.update_user(&user).await.unwrap();
+        assert!(
+            persist
+                .get_user_by_id(&user.user_id)
+                .await
+                .unwrap()
+                .suspended
+        );
+        let unchanged = persist.get_organization(&org_id).await.unwrap().unwrap();
+        assert_eq!(unchanged.created_at, personal.created_at);
+    }
+
+    #[tokio::test]
+    async fn test_find_cached_messages() {
+        let path = std::env::temp_dir().join(format!("snitch-history-{}.db", OrgID::new()));
+        let history = SqlDatabaseService::new(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        let mut persist = Persistence {
+            backend: Backend::InMemory(InMemoryDatabaseService::default()),
+            history: Some(history),
+        };
+        let key = MessageKey::default();
+        let message = MessageBackend {
+            hostname: key.hostname.clone(),
+            ..Default::default()
+        };
+
+        // only in the history, e.g. after the cache expired
+        let history = persist.history.as_mut().unwrap();
+        history.add_message(&key, &message).await.unwrap();
+        assert_eq!(persist.find_messages(&key).await.unwrap().len(), 1);
+
+        for _ in 0..MAX_MESSAGES {
+            dispatch!(persist.add_message(&key, &message)).unwrap();
+        }
+        assert_eq!(persist.find_messages(&key).await.unwrap().len(), 1);
+
+        dispatch!(persist.add_message(&key, &message)).unwrap();
+        assert_eq!(
+            persist.find_messages(&key).await.unwrap().len(),
+            MAX_MESSAGES as usize + 1
+        );
+    }
 }
@@ -1,15 +1,26 @@
 use crate::errors::APIInternalError;
 use crate::model::message::MessageBackend;
-use crate::model::user::{Nonce, User, UserID};
-use crate::persistence::{MessageKey, PersistMessage};
+use crate::model::notification_rule::{Channel, NotificationRule};
+use crate::model::organization::{Invitation, OrgID, Organization, Role};
+use crate::model::user::{Nonce, SessionID, SessionInfo, TwoFactor, User, UserID};
+use crate::persistence::token::TokenStore;
+use crate::persistence::{
+    MessageKey, PersistEmailChange, PersistIdempotencyKey, PersistMessage,
+    PersistNotificationRules, PersistNotificationSettings, PersistOrganization,
+    PersistPasswordReset, PersistPendingUser, PersistRateLimit, PersistSession, PersistTwoFactor,
+    PersistUser, MAX_MESSAGES, SESSION_MAX_AGE, TTL,
+};
+use std::collections::{BTreeSet, HashMap};
 use std::env;
 use std::result::Result::Ok as StdOk;
+use std::sync::{Arc, Mutex};
 
 use anyhow::{Error, Ok, Result};
 use chatterbox::dispatcher::email::Email;
 use chatterbox::dispatcher::slack::Slack;
 use chatterbox::dispatcher::telegram::Telegram;
 use chatterbox::dispatcher::Sender;
+use chrono::Utc;
 use log::{debug, info};
 use redis::JsonAsyncCommands;
 use redis::{aio, RedisResult};
@@ -17,18 +28,15 @@ use redis::{AsyncCommands, FromRedisValue};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-#[derive(Debug)]
+/// Compiled notification rules with the JSON they were loaded from.
+type NotificationRulesCache = HashMap<OrgID, (String, Vec<NotificationRule>)>;
+
+#[derive(Debug, Clone)]
 pub struct RedisDatabaseService {
     pub connection: aio::MultiplexedConnection,
-}
-
-const MAX_MESSAGES: isize = 1000;
-const MINUTE: usize = 60;
-const DAY: usize = 60 * MINUTE * 24;
-
-enum TTL {
-    PendingUser = (15 * MINUTE) as isize,
-    Message = DAY as isize,
+    /// The notification rules of each organization with their patterns compiled,
+    /// keyed by the JSON they were loaded from.
+    notification_rules: Arc<Mutex<NotificationRulesCache>>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
@@ -38,6 +46,23 @@ pub(crate) struct NotificationSettings {
     email: Option<Email>,
 }
 
+impl NotificationSettings {
+    pub(crate) fn has_channels(&self) -> bool {
+        self.telegram.is_some() || self.slack.is_some() || self.email.is_some()
+    }
+
+    /// Drops all channels not in `channels`.
+    pub(crate) fn restrict_to(self, channels: &BTreeSet<Channel>) -> Self {
+        Self {
+            telegram: self
+                .telegram
+                .filter(|_| channels.contains(&Channel::Telegram)),
+            slack: self.slack.filter(|_| channels.contains(&Channel::Slack)),
+            email: self.email.filter(|_| channels.contains(&Channel::Email)),
+        }
+    }
+}
+
 impl FromRedisValue for NotificationSettings {
     fn from_redis_value(v: &redis::Value) -> RedisResult<Self> {
         let s: String = FromRedisValue::from_redis_value(v)?;
@@ -55,72 +80,242 @@ impl From<NotificationSettings> for Sender {
     }
 }
 
+/// The connection url built from `SNITCH_REDIS_URL` and `SNITCH_REDIS_PASSWORD`.
+pub(crate) fn redis_url() -> String {
+    let url = env::var("SNITCH_REDIS_URL").expect("SNITCH_REDIS_URL not defined");
+    info!("connecting to redis {}", url);
+
+    let password = env::var("SNITCH_REDIS_PASSWORD").expect("SNITCH_REDIS_PASSWORD not defined");
+    format!("redis://:{}@{}", password, url)
+}
+
 impl RedisDatabaseService {
     pub async fn new() -> Result<Self> {
-        let url = env::var("SNITCH_REDIS_URL").expect("SNITCH_REDIS_URL not defined");
-        info!("connecting to redis {}", url);
-
-        let password =
-            env::var("SNITCH_REDIS_PASSWORD").expect("SNITCH_REDIS_PASSWORD not defined");
-        let url = format!("redis://:{}@{}", password, url);
+        let url = redis_url();
         debug!("connecting to {url}");
         let client = redis::Client::open(url)?;
         let connection = client.get_multiplexed_async_connection().await?;
-        Ok(RedisDatabaseService { connection })
+        Ok(RedisDatabaseService {
+            connection,
+            notification_rules: Arc::default(),
+        })
     }
 
-    pub async fn add_user_index(&mut self, user: &User) {
+    async fn add_user_index(&mut self, user: &User) -> Result<()> {
         let user_id = &user.user_id;
         let email = &user.email;
-        let _: () = self
-            .connection
+        redis::pipe()
+            .atomic()
             .set(format!("user_email:{email}"), user_id.to_string())
-            .await
-            .unwrap();
+            .hset("user_emails", email, user_id.to_string())
+            .query_async::<()>(&mut self.connection)
+            .await?;
+        Ok(())
     }
 
-    pub async fn add_user(&mut self, user: &User) {
+    /// The keys matching `pattern`. Iterates with `SCAN`, which unlike `KEYS`
+    /// doesn't block the server while going through the whole database.
+    pub(crate) async fn scan_keys(&mut self, pattern: &str) -> Result<Vec<String>> {
+        let mut iter: redis::AsyncIter<String> = self.connection.scan_match(pattern).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    /// The keys of everything stored for an organization, except the
+    /// memberships kept for its members.
+    async fn organization_keys(&mut self, org_id: &OrgID) -> Result<Vec<String>> {
+        let tokens = self.get_tokens_of_org(org_id).await?;
+        let mut keys = self.scan_keys(&format!("messages:{org_id}:*")).await?;
+        keys.extend(self.scan_keys(&format!("idempotency:{org_id}:*")).await?);
+        keys.extend(tokens.iter().flat_map(|token| {
+            [
+                format!("token_to_user_id:{token}"),
+                format!("token_hosts:{token}"),
+            ]
+        }));
+        keys.push(format!("user_id_to_token:{org_id}"));
+        keys.push(format!("notification_settings:{org_id}"));
+        keys.push(format!("notification_rules:{org_id}"));
+        keys.push(format!("org_members:{org_id}"));
+        keys.push(format!("org:{org_id}"));
+        Ok(keys)
+    }
+}
+
+fn parse_role(role: &str) -> Result<Role> {
+    Ok(serde_json::from_value(json!(role))?)
+}
+
+impl PersistUser for RedisDatabaseService {
+    async fn add_user(&mut self, user: &User) -> Result<()> {
         let user_id = &user.user_id;
         let _: () = self
             .connection
             .json_set(format!("user:{user_id}"), "$", &json!(user))
-            .await
-            .unwrap();
-        self.add_user_index(user).await;
+            .await?;
+        self.add_user_index(user).await
     }
 
-    pub async fn delete_user(&mut self, user_id: &UserID) {
-        let _: () = self
+    async fn update_user(&mut self, user: &User) -> Result<()> {
+        let user_id = &user.user_id;
+        let updated: Option<String> = redis::cmd("JSON.SET")
+            .arg(format!("user:{user_id}"))
+            .arg("$")
+            .arg(serde_json::to_string(user)?)
+            .arg("XX")
+            .query_async(&mut self.connection)
+            .await?;
+        match updated {
+            Some(_) => Ok(()),
+            None => Err(anyhow::anyhow!("no user with id {user_id}")),
+        }
+    }
+
+    /// Collects all keys of the user and its personal organization and deletes
+    /// them in one transaction, together with its memberships and pending
+    /// registrations of its email. The user document goes last, so a failed
+    /// deletion can be repeated.
+    async fn delete_user(&mut self, user_id: &UserID) -> Result<()> {
+        let user: Option<String> = self
             .connection
-            .json_del(format!("user:{user_id}"), ".")
-            .await
-            .unwrap();
+            .json_get(format!("user:{user_id}"), ".")
+            .await?;
+        let org_ids: Vec<String> = self
+            .connection
+            .hkeys(format!("user_orgs:{user_id}"))
+            .await?;
+        let mut keys = self.organization_keys(&OrgID::personal(user_id)).await?;
+        keys.push(format!("sessions:{user_id}"));
+        keys.push(format!("two_factor:{user_id}"));
+        keys.push(format!("user_orgs:{user_id}"));
+        for (index, prefix) in [
+            ("password_resets", "password_reset"),
+            ("email_changes", "email_change"),
+        ] {
+            let index = format!("{index}:{user_id}");
+            let nonces: Vec<String> = self.connection.smembers(&index).await?;
+            keys.extend(nonces.iter().map(|nonce| format!("{prefix}:{nonce}")));
+            keys.push(index);
+        }
+        let mut email = None;
+        if let Some(user) = user {
+            let user: User = serde_json::from_str(&user)?;
+            keys.push(format!("user_email:{}", user.email));
+            for key in self.scan_keys("user_pending:*").await? {
+                let pending: Option<String> = self.connection.json_get(&key, ".").await?;
+                let Some(pending) = pending else {
+                    continue;
+                };
+                let pending: User = serde_json::from_str(&pending)?;
+                if pending.email == user.email {
+                    keys.push(key);
+                    keys.push(format!("user_pending_id:{}", pending.user_id));
+                }
+            }
+            email = Some(user.email);
+        }
+        keys.push(format!("user:{user_id}"));
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for org_id in org_ids {
+            pipe.hdel(format!("org_members:{org_id}"), user_id.to_string());
+        }
+        if let Some(email) = email {
+            pipe.hdel("user_emails", email);
+        }
+        let _: () = pipe.del(&keys).query_async(&mut self.connection).await?;
+        info!("deleted user {user_id}");
+        Ok(())
+    }
+
+    async fn get_user_by_id(&mut self, user_id: &UserID) -> Result<User> {
+        info!("get user by user_id {user_id}");
+        let user_str: String = self
+            .connection
+            .json_get(format!("user:{user_id}"), ".")
+            .await?;
+        Ok(serde_json::from_str(&user_str)?)
+    }
+
+    async fn get_user_by_email(&mut self, email: &str) -> Result<Option<User>> {
+        info!("get user by email {email}");
+        let user_id: Option<String> = self.connection.get(format!("user_email:{email}")).await?;
+        if let Some(user_id) = user_id {
+            info!("found: {:?}", user_id);
+            return Ok(Some(self.get_user_by_id(&user_id.into()).await?));
+        };
+        Ok(None)
+    }
+
+    async fn get_user_index(&mut self) -> Result<Vec<(String, UserID)>> {
+        let index: HashMap<String, String> = self.connection.hgetall("user_emails").await?;
+        Ok(index
+            .into_iter()
+            .map(|(email, user_id)| (email, user_id.into()))
+            .collect())
+    }
+
+    /// Only runs while the index doesn't exist yet.
+    async fn migrate_user_index(&mut self) -> Result<usize> {
+        let indexed: bool = self.connection.exists("user_emails").await?;
+        if indexed {
+            return Ok(0);
+        }
+        let mut migrated = 0;
+        for key in self.scan_keys("user:*").await? {
+            let user: Option<String> = self.connection.json_get(&key, ".").await?;
+            if let Some(user) = user {
+                self.add_user_index(&serde_json::from_str(&user)?).await?;
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
     }
 
-    pub async fn add_user_pending(&mut self, user: &User, nonce: &Nonce) -> Result<()> {
-        if self.get_user_by_email(&user.email).await.is_some() {
+    async fn change_email(&mut self, user_id: &UserID, email: &str) -> Result<()> {
+        let mut user = self.get_user_by_id(user_id).await?;
+        let old_email = std::mem::replace(&mut user.email, email.to_string());
+        let _: () = redis::pipe()
+            .atomic()
+            .json_set(format!("user:{user_id}"), "$", &json!(user))?
+            .del(format!("user_email:{old_email}"))
+            .set(format!("user_email:{email}"), user_id.to_string())
+            .hdel("user_emails", &old_email)
+            .hset("user_emails", email, user_id.to_string())
+            .query_async(&mut self.connection)
+            .await?;
+        Ok(())
+    }
+}
+
+impl PersistPendingUser for RedisDatabaseService {
+    async fn add_user_pending(&mut self, user: &User, nonce: &Nonce) -> Result<()> {
+        if self.get_user_by_email(&user.email).await?.is_some() {
             info!("not adding user pending as user already exists: {user}");
             return Err(Error::new(APIInternalError::UserAlreadyExists(
                 user.clone(),
             )));
         }
         let key = format!("user_pending:{nonce}");
-        self.connection.json_set(&key, "$", &json!(user)).await?;
-        self.connection
+        redis::pipe()
+            .atomic()
+            .json_set(&key, "$", &json!(user))?
             .expire(&key, TTL::PendingUser as i64)
+            .set_ex(
+                format!("user_pending_id:{}", user.user_id),
+                nonce,
+                TTL::PendingUser as u64,
+            )
+            .query_async::<()>(&mut self.connection)
             .await?;
         Ok(())
     }
 
-    pub async fn confirm_user_pending(&mut self, nonce: &Nonce) -> Result<()> {
-        let user = self.get_user_pending(nonce).await?;
-        self.add_user(&user).await;
-        self.delete_user_pending(nonce).await;
-        info!("confirmed {:?}", user);
-        Ok(())
-    }
-
-    pub async fn get_user_pending(&mut self, nonce: &Nonce) -> Result<User> {
+    async fn get_user_pending(&mut self, nonce: &Nonce) -> Result<User> {
         info!("get pending user. nonce: {nonce}");
         let user_str: String = self
             .connection
@@ -129,62 +324,516 @@ impl RedisDatabaseService {
         Ok(serde_json::from_str(&user_str)?)
     }
 
-    pub async fn delete_user_pending(&mut self, nonce: &Nonce) {
-        let _: () = self
-            .connection
-            .json_del(format!("user_pending:{nonce}"), ".")
-            .await
-            .unwrap();
+    /// Deletes the id index of the pending user along with it.
+    async fn delete_user_pending(&mut self, nonce: &Nonce) -> Result<()> {
+        let key = format!("user_pending:{nonce}");
+        let user: Option<String> = self.connection.json_get(&key, ".").await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().json_del(&key, ".")?;
+        if let Some(user) = user {
+            let user: User = serde_json::from_str(&user)?;
+            pipe.del(format!("user_pending_id:{}", user.user_id));
+        }
+        pipe.query_async::<()>(&mut self.connection).await?;
+        Ok(())
     }
 
-    pub async fn get_user_by_id(&mut self, user_id: &UserID) -> User {
-        info!("get user by user_id {user_id}");
-        let user_str: String = self
+    async fn get_users_pending(&mut self) -> Result<Vec<User>> {
+        let keys = self.scan_keys("user_pending:*").await?;
+        let mut pending = Vec::with_capacity(keys.len());
+        for key in keys {
+            let user: Option<String> = self.connection.json_get(&key, ".").await?;
+            if let Some(user) = user {
+                pending.push(serde_json::from_str(&user)?);
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Pending users are indexed by id in `user_pending_id:{user_id}`, which
+    /// expires together with the pending user.
+    async fn get_nonce_of_user_pending(&mut self, user_id: &UserID) -> Result<Option<Nonce>> {
+        Ok(self
             .connection
-            .json_get(format!("user:{user_id}"), ".")
-            .await
-            .unwrap();
-        serde_json::from_str(&user_str).unwrap()
+            .get(format!("user_pending_id:{user_id}"))
+            .await?)
     }
+}
 
-    pub async fn get_user_by_email(&mut self, email: &str) -> Option<User> {
-        info!("get user by email {email}");
-        if let Some(result) = self
+/// The nonces of each user are indexed in `password_resets:{user_id}`, so that
+/// deleting the user deletes them.
+impl PersistPasswordReset for RedisDatabaseService {
+    async fn add_password_reset(&mut self, user_id: &UserID, nonce: &Nonce) -> Result<()> {
+        let index = format!("password_resets:{user_id}");
+        redis::pipe()
+            .atomic()
+            .set_ex(
+                format!("password_reset:{nonce}"),
+                user_id.to_string(),
+                TTL::PasswordReset as u64,
+            )
+            .sadd(&index, nonce)
+            .expire(&index, TTL::PasswordReset as i64)
+            .query_async::<()>(&mut self.connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn take_password_reset(&mut self, nonce: &Nonce) -> Result<Option<UserID>> {
+        let user_id: Option<String> = self
             .connection
-            .get(format!("user_email:{email}"))
-            .await
-            .unwrap()
-        {
-            info!("found: {:?}", result);
-            let user_id = String::from_redis_value(&result).unwrap();
-            return Some(self.get_user_by_id(&user_id.into()).await);
-        };
-        None
+            .get_del(format!("password_reset:{nonce}"))
+            .await?;
+        if let Some(user_id) = &user_id {
+            let _: () = self
+                .connection
+                .srem(format!("password_resets:{user_id}"), nonce)
+                .await?;
+        }
+        Ok(user_id.map(UserID::from))
     }
+}
+
+#[derive(Serialize, Deserialize)]
+struct EmailChange {
+    user_id: UserID,
+    email: String,
+}
 
-    pub(crate) async fn get_notification_settings(
+/// Indexed per user in `email_changes:{user_id}` like password resets.
+impl PersistEmailChange for RedisDatabaseService {
+    async fn add_email_change(
         &mut self,
         user_id: &UserID,
-    ) -> NotificationSettings {
-        self.connection
-            .json_get(format!("notification_settings:{user_id}"), ".")
-            .await
-            .unwrap_or_default()
+        email: &str,
+        nonce: &Nonce,
+    ) -> Result<()> {
+        let change = EmailChange {
+            user_id: user_id.clone(),
+            email: email.to_string(),
+        };
+        let index = format!("email_changes:{user_id}");
+        redis::pipe()
+            .atomic()
+            .set_ex(
+                format!("email_change:{nonce}"),
+                serde_json::to_string(&change)?,
+                TTL::EmailChange as u64,
+            )
+            .sadd(&index, nonce)
+            .expire(&index, TTL::EmailChange as i64)
+            .query_async::<()>(&mut self.connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn take_email_change(&mut self, nonce: &Nonce) -> Result<Option<(UserID, String)>> {
+        let change: Option<String> = self
+            .connection
+            .get_del(format!("email_change:{nonce}"))
+            .await?;
+        match change {
+            Some(change) => {
+                let change: EmailChange = serde_json::from_str(&change)?;
+                let _: () = self
+                    .connection
+                    .srem(format!("email_changes:{}", change.user_id), nonce)
+                    .await?;
+                Ok(Some((change.user_id, change.email)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Sessions are stored in the hash `sessions:{user_id}`, by session id.
+impl PersistSession for RedisDatabaseService {
+    /// The hash of the sessions expires with the newest session.
+    async fn add_session(&mut self, user_id: &UserID, session: &SessionInfo) -> Result<()> {
+        let key = format!("sessions:{user_id}");
+        redis::pipe()
+            .atomic()
+            .hset(&key, &session.session_id, serde_json::to_string(session)?)
+            .expire(&key, SESSION_MAX_AGE)
+            .query_async::<()>(&mut self.connection)
+            .await?;
+        Ok(())
+    }
+
+    /// Also deletes the expired sessions.
+    async fn get_sessions(&mut self, user_id: &UserID) -> Result<Vec<SessionInfo>> {
+        let key = format!("sessions:{user_id}");
+        let sessions: Vec<String> = self.connection.hvals(&key).await?;
+        let now = Utc::now();
+        let (expired, sessions): (Vec<SessionInfo>, Vec<SessionInfo>) = sessions
+            .iter()
+            .map(|session| serde_json::from_str(session))
+            .collect::<Result<Vec<SessionInfo>, _>>()?
+            .into_iter()
+            .partition(|session| session.is_expired(now));
+        if !expired.is_empty() {
+            let session_ids: Vec<&SessionID> =
+                expired.iter().map(|session| &session.session_id).collect();
+            let _: () = self.connection.hdel(&key, session_ids).await?;
+        }
+        Ok(sessions)
+    }
+
+    async fn has_session(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<bool> {
+        Ok(self
+            .connection
+            .hexists(format!("sessions:{user_id}"), session_id)
+            .await?)
+    }
+
+    async fn delete_session(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<bool> {
+        let deleted: u64 = self
+            .connection
+            .hdel(format!("sessions:{user_id}"), session_id)
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn delete_sessions(&mut self, user_id: &UserID, keep: Option<&SessionID>) -> Result<()> {
+        let key = format!("sessions:{user_id}");
+        let session_ids: Vec<String> = self.connection.hkeys(&key).await?;
+        let session_ids: Vec<&String> = session_ids
+            .iter()
+            .filter(|session_id| Some(*session_id) != keep)
+            .collect();
+        if !session_ids.is_empty() {
+            let _: () = self.connection.hdel(&key, session_ids).await?;
+        }
+        Ok(())
+    }
+}
+
+impl PersistRateLimit for RedisDatabaseService {
+    async fn count_attempt(&mut self, key: &str, window_seconds: i64) -> Result<u64> {
+        let key = format!("rate_limit:{key}");
+        let (attempts,): (u64,) = redis::pipe()
+            .atomic()
+            .incr(&key, 1)
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(window_seconds)
+            .arg("NX")
+            .ignore()
+            .query_async(&mut self.connection)
+            .await?;
+        Ok(attempts)
     }
 
-    pub(crate) async fn set_notification_settings(
+    async fn block(&mut self, key: &str, seconds: i64) -> Result<()> {
+        let _: () = self
+            .connection
+            .set_ex(format!("rate_limit_block:{key}"), 1, seconds as u64)
+            .await?;
+        Ok(())
+    }
+
+    async fn blocked_for(&mut self, key: &str) -> Result<Option<i64>> {
+        // -2 if the key doesn't exist
+        let seconds: i64 = self
+            .connection
+            .ttl(format!("rate_limit_block:{key}"))
+            .await?;
+        Ok((seconds > 0).then_some(seconds))
+    }
+
+    async fn reset_attempts(&mut self, key: &str) -> Result<()> {
+        let _: () = self
+            .connection
+            .del(&[
+                format!("rate_limit:{key}"),
+                format!("rate_limit_block:{key}"),
+            ])
+            .await?;
+        Ok(())
+    }
+}
+
+impl PersistNotificationSettings for RedisDatabaseService {
+    async fn get_notification_settings(&mut self, org_id: &OrgID) -> Result<NotificationSettings> {
+        let notification_settings: Option<NotificationSettings> = self
+            .connection
+            .json_get(format!("notification_settings:{org_id}"), ".")
+            .await?;
+        Ok(notification_settings.unwrap_or_default())
+    }
+
+    async fn set_notification_settings(
         &mut self,
-        user_id: &UserID,
+        org_id: &OrgID,
         notification_settings: NotificationSettings,
-    ) {
-        self.connection
+    ) -> Result<()> {
+        let _: () = self
+            .connection
             .json_set(
-                format!("notification_settings:{user_id}"),
+                format!("notification_settings:{org_id}"),
                 ".",
                 &notification_settings,
             )
-            .await
-            .unwrap()
+            .await?;
+        Ok(())
+    }
+}
+
+impl PersistIdempotencyKey for RedisDatabaseService {
+    async fn claim_idempotency_key(&mut self, org_id: &OrgID, key: &str) -> Result<bool> {
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(format!("idempotency:{org_id}:{key}"))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(TTL::IdempotencyKey as i64)
+            .query_async(&mut self.connection)
+            .await?;
+        Ok(claimed.is_some())
+    }
+
+    async fn release_idempotency_key(&mut self, org_id: &OrgID, key: &str) -> Result<()> {
+        let _: () = self
+            .connection
+            .del(format!("idempotency:{org_id}:{key}"))
+            .await?;
+        Ok(())
+    }
+}
+
+impl PersistTwoFactor for RedisDatabaseService {
+    async fn get_two_factor(&mut self, user_id: &UserID) -> Result<Option<TwoFactor>> {
+        let two_factor: Option<String> = self
+            .connection
+            .json_get(format!("two_factor:{user_id}"), ".")
+            .await?;
+        match two_factor {
+            Some(two_factor) => Ok(Some(serde_json::from_str(&two_factor)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_two_factor(&mut self, user_id: &UserID, two_factor: &TwoFactor) -> Result<()> {
+        let _: () = self
+            .connection
+            .json_set(format!("two_factor:{user_id}"), "$", &json!(two_factor))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_two_factor(&mut self, user_id: &UserID) -> Result<()> {
+        let _: () = self.connection.del(format!("two_factor:{user_id}")).await?;
+        Ok(())
+    }
+}
+
+impl PersistNotificationRules for RedisDatabaseService {
+    /// Only compiles the patterns of rules that changed since they were last loaded.
+    async fn get_notification_rules(&mut self, org_id: &OrgID) -> Result<Vec<NotificationRule>> {
+        let json: Option<String> = self
+            .connection
+            .json_get(format!("notification_rules:{org_id}"), ".")
+            .await?;
+        let mut cache = self.notification_rules.lock().unwrap();
+        let Some(json) = json else {
+            cache.remove(org_id);
+            return Ok(Vec::new());
+        };
+        if let Some((cached, rules)) = cache.get(org_id) {
+            if *cached == json {
+                return Ok(rules.clone());
+            }
+        }
+        let rules: Vec<NotificationRule> = serde_json::from_str(&json)?;
+        rules.iter().for_each(NotificationRule::compile);
+        cache.insert(org_id.clone(), (json, rules.clone()));
+        Ok(rules)
+    }
+
+    async fn set_notification_rules(
+        &mut self,
+        org_id: &OrgID,
+        rules: &[NotificationRule],
+    ) -> Result<()> {
+        let _: () = self
+            .connection
+            .json_set(format!("notification_rules:{org_id}"), "$", &json!(rules))
+            .await?;
+        Ok(())
+    }
+}
+
+impl PersistOrganization for RedisDatabaseService {
+    async fn add_organization(
+        &mut self,
+        organization: &Organization,
+        owner: &UserID,
+    ) -> Result<()> {
+        let org_id = &organization.org_id;
+        let _: () = redis::pipe()
+            .atomic()
+            .json_set(format!("org:{org_id}"), "$", &json!(organization))?
+            .hset(
+                format!("org_members:{org_id}"),
+                owner.to_string(),
+                Role::Owner.to_string(),
+            )
+            .hset(
+                format!("user_orgs:{owner}"),
+                org_id.to_string(),
+                Role::Owner.to_string(),
+            )
+            .query_async(&mut self.connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_organization(&mut self, org_id: &OrgID) -> Result<Option<Organization>> {
+        let organization: Option<String> = self
+            .connection
+            .json_get(format!("org:{org_id}"), ".")
+            .await?;
+        match organization {
+            Some(organization) => Ok(Some(serde_json::from_str(&organization)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_organization(&mut self, org_id: &OrgID) -> Result<()> {
+        let members: Vec<String> = self
+            .connection
+            .hkeys(format!("org_members:{org_id}"))
+            .await?;
+        let keys = self.organization_keys(org_id).await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for user_id in members {
+            pipe.hdel(format!("user_orgs:{user_id}"), org_id.to_string());
+        }
+        let _: () = pipe.del(&keys).query_async(&mut self.connection).await?;
+        info!("deleted organization {org_id}");
+        Ok(())
+    }
+
+    async fn set_member(&mut self, org_id: &OrgID, user_id: &UserID, role: Role) -> Result<()> {
+        let _: () = redis::pipe()
+            .atomic()
+            .hset(
+                format!("org_members:{org_id}"),
+                user_id.to_string(),
+                role.to_string(),
+            )
+            .hset(
+                format!("user_orgs:{user_id}"),
+                org_id.to_string(),
+                role.to_string(),
+            )
+            .query_async(&mut self.connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_member(&mut self, org_id: &OrgID, user_id: &UserID) -> Result<()> {
+        let _: () = redis::pipe()
+            .atomic()
+            .hdel(format!("org_members:{org_id}"), user_id.to_string())
+            .hdel(format!("user_orgs:{user_id}"), org_id.to_string())
+            .query_async(&mut self.connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_members(&mut self, org_id: &OrgID) -> Result<Vec<(UserID, Role)>> {
+        let members: HashMap<String, String> = self
+            .connection
+            .hgetall(format!("org_members:{org_id}"))
+            .await?;
+        let mut members = members
+            .into_iter()
+            .map(|(user_id, role)| Ok((UserID::from(user_id), parse_role(&role)?)))
+            .collect::<Result<Vec<_>>>()?;
+        members.sort();
+        Ok(members)
+    }
+
+    async fn get_role(&mut self, org_id: &OrgID, user_id: &UserID) -> Result<Option<Role>> {
+        let role: Option<String> = self
+            .connection
+            .hget(format!("org_members:{org_id}"), user_id.to_string())
+            .await?;
+        role.as_deref().map(parse_role).transpose()
+    }
+
+    async fn get_organizations_of_user(&mut self, user_id: &UserID) -> Result<Vec<(OrgID, Role)>> {
+        let organizations: HashMap<String, String> = self
+            .connection
+            .hgetall(format!("user_orgs:{user_id}"))
+            .await?;
+        let mut organizations = organizations
+            .into_iter()
+            .map(|(org_id, role)| Ok((OrgID::from(org_id), parse_role(&role)?)))
+            .collect::<Result<Vec<_>>>()?;
+        organizations.sort();
+        Ok(organizations)
+    }
+
+    async fn add_invitation(&mut self, nonce: &Nonce, invitation: &Invitation) -> Result<()> {
+        let _: () = self
+            .connection
+            .set_ex(
+                format!("org_invitation:{nonce}"),
+                serde_json::to_string(invitation)?,
+                TTL::Invitation as u64,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_invitation(&mut self, nonce: &Nonce) -> Result<Option<Invitation>> {
+        let invitation: Option<String> = self
+            .connection
+            .get(format!("org_invitation:{nonce}"))
+            .await?;
+        match invitation {
+            Some(invitation) => Ok(Some(serde_json::from_str(&invitation)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn take_invitation(&mut self, nonce: &Nonce) -> Result<Option<Invitation>> {
+        let invitation: Option<String> = self
+            .connection
+            .get_del(format!("org_invitation:{nonce}"))
+            .await?;
+        match invitation {
+            Some(invitation) => Ok(Some(serde_json::from_str(&invitation)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Personal organizations share the id of their user, so the messages,
+    /// tokens and settings stored per user already belong to them.
+    async fn migrate_personal_organizations(&mut self) -> Result<usize> {
+        let keys = self.scan_keys("user:*").await?;
+        let mut migrated = 0;
+        for key in keys {
+            let Some(user_id) = key
+                .strip_prefix("user:")
+                .map(|id| UserID::from(id.to_string()))
+            else {
+                continue;
+            };
+            let exists: bool = self
+                .connection
+                .exists(format!("org:{}", OrgID::personal(&user_id)))
+                .await?;
+            if !exists {
+                self.add_organization(&Organization::personal(&user_id), &user_id)
+                    .await?;
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
     }
 }
 
@@ -231,7 +880,7 @@ impl PersistMessage for RedisDatabaseService {
         let key = key.to_redis_key();
         let _: () = self.connection.rpush(&key, message).await?;
         // info!("storing in database: {:?}... finished", message);
-        self.connection.expire(&key, TTL::Message as i64).await?;
+        let _: () = self.connection.expire(&key, TTL::Message as i64).await?;
 
         Ok(())
     }
@@ -239,18 +888,18 @@ impl PersistMessage for RedisDatabaseService {
     async fn find_messages(&mut self, key: &MessageKey) -> Result<Vec<MessageBackend>> {
         let responses: Vec<String> = self
             .connection
-            .lrange(key.to_redis_key(), 0, MAX_MESSAGES)
+            .lrange(key.to_redis_key(), -(MAX_MESSAGES + 1), -1)
             .await?;
 
         let messages = responses
             .iter()
-            .map(|response| serde_json::from_str(response).unwrap())
-            .collect();
+            .map(|response| serde_json::from_str(response))
+            .collect::<Result<Vec<MessageBackend>, _>>()?;
         Ok(messages)
     }
 
-    async fn get_hostnames_of_user(&mut self, user_id: &UserID) -> Result<Vec<String>> {
-        let key = format!("messages:{user_id}:*");
+    async fn get_hostnames(&mut self, org_id: &OrgID) -> Result<Vec<String>> {
+        let key = format!("messages:{org_id}:*");
         let keys: Vec<String> = self.connection.keys(key).await?;
         let hostnames = keys
             .iter()
@@ -267,13 +916,17 @@ async fn test_add_delete_user() {
     test_user.email = "x.x@x.x".to_string();
     let mut db = RedisDatabaseService::new().await.unwrap();
 
-    db.add_user(&test_user).await;
-    let _x = db.get_user_by_id(&test_user.user_id).await;
-    let x = db.get_user_by_email(&test_user.email).await.unwrap();
+    db.add_user(&test_user).await.unwrap();
+    let _x = db.get_user_by_id(&test_user.user_id).await.unwrap();
+    let x = db
+        .get_user_by_email(&test_user.email)
+        .await
+        .unwrap()
+        .unwrap();
     assert_eq!(x.email, test_user.email);
     assert_eq!(x.user_id, test_user.user_id);
 
-    db.delete_user(&test_user.user_id).await;
+    db.delete_user(&test_user.user_id).await.unwrap();
     // Test this to improve error handling
     // assert_eq!(db.get_user_by_name(&test_user.email).await.ok(), None);
 }
@@ -285,21 +938,126 @@ async fn test_add_messages() {
     test_user.email = "x.x@x.x".to_string();
     let mut db = RedisDatabaseService::new().await.unwrap();
     let mut test_message = MessageBackend::default();
-    db.add_user(&test_user).await;
+    db.add_user(&test_user).await.unwrap();
 
     let n_hostnames = 3;
     for i in 0..n_hostnames {
         test_message.hostname = format!("testhostname-{}", i);
         let key = MessageKey {
-            user_id: test_user.user_id.clone(),
+            org_id: OrgID::personal(&test_user.user_id),
             hostname: test_message.hostname.clone(),
         };
         db.add_message(&key, &test_message).await.unwrap();
         assert_eq!(db.find_messages(&key).await.unwrap().len(), 1);
     }
 
-    let hostnames = db.get_hostnames_of_user(&test_user.user_id).await.unwrap();
+    let hostnames = db
+        .get_hostnames(&OrgID::personal(&test_user.user_id))
+        .await
+        .unwrap();
     assert_eq!(hostnames.len(), n_hostnames);
 
-    db.delete_user(&test_user.user_id).await;
+    db.delete_user(&test_user.user_id).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_delete_user_data() {
+    use crate::model::user::User;
+    let mut test_user = User::example();
+    test_user.email = format!("{}@x.x", test_user.user_id);
+    let user_id = &test_user.user_id;
+    let org_id = &OrgID::personal(user_id);
+    let mut db = RedisDatabaseService::new().await.unwrap();
+    db.add_user(&test_user).await.unwrap();
+    db.add_organization(&Organization::personal(user_id), user_id)
+        .await
+        .unwrap();
+    let team = Organization::new("team".to_string());
+    db.add_organization(&team, &UserID::new()).await.unwrap();
+    db.set_member(&team.org_id, user_id, Role::Viewer)
+        .await
+        .unwrap();
+    let token = db.create_token_for_org(org_id).await.unwrap();
+    let token = crate::service::token::hash_token(&token);
+    db.set_token_host_used(&token, "testhostname", chrono::Utc::now())
+        .await
+        .unwrap();
+    db.add_session(user_id, &SessionInfo::example("session"))
+        .await
+        .unwrap();
+    db.set_two_factor(user_id, &TwoFactor::example())
+        .await
+        .unwrap();
+    db.set_notification_settings(org_id, NotificationSettings::default())
+        .await
+        .unwrap();
+    db.set_notification_rules(org_id, &[]).await.unwrap();
+    db.claim_idempotency_key(org_id, "key").await.unwrap();
+    let key = MessageKey {
+        org_id: org_id.clone(),
+        hostname: "testhostname".to_string(),
+    };
+    db.add_message(&key, &MessageBackend::default())
+        .await
+        .unwrap();
+    let reset_nonce = format!("reset-{}", UserID::new());
+    db.add_password_reset(user_id, &reset_nonce).await.unwrap();
+    let new_email = format!("new-{}@y.y", UserID::new());
+    let change_nonce = format!("change-{}", UserID::new());
+    db.add_email_change(user_id, &new_email, &change_nonce)
+        .await
+        .unwrap();
+
+    db.delete_user(user_id).await.unwrap();
+    let remaining: Vec<String> = db.connection.keys(format!("*{user_id}*")).await.unwrap();
+    assert!(remaining.is_empty(), "{remaining:?}");
+    // keys named by nonce must not reference the user either
+    for key in db.scan_keys("*").await.unwrap() {
+        let kind: String = redis::cmd("TYPE")
+            .arg(&key)
+            .query_async(&mut db.connection)
+            .await
+            .unwrap();
+        let value: Vec<String> = match kind.as_str() {
+            "string" => {
+                let value: Option<String> = db.connection.get(&key).await.unwrap();
+                value.into_iter().collect()
+            }
+            "ReJSON-RL" => {
+                let value: Option<String> = db.connection.json_get(&key, ".").await.unwrap();
+                value.into_iter().collect()
+            }
+            "hash" => {
+                let hash: HashMap<String, String> = db.connection.hgetall(&key).await.unwrap();
+                hash.into_iter().flat_map(|(k, v)| [k, v]).collect()
+            }
+            "set" => db.connection.smembers(&key).await.unwrap(),
+            "list" => db.connection.lrange(&key, 0, -1).await.unwrap(),
+            _ => Vec::new(),
+        };
+        for reference in [
+            user_id.to_string(),
+            test_user.email.clone(),
+            new_email.clone(),
+        ] {
+            assert!(
+                !value.iter().any(|value| value.contains(&reference)),
+                "{key} references {reference}"
+            );
+        }
+    }
+    let token_keys: usize = db
+        .connection
+        .exists(&[
+            format!("token_to_user_id:{token}"),
+            format!("token_hosts:{token}"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(token_keys, 0);
+    assert_eq!(db.get_members(&team.org_id).await.unwrap().len(), 1);
+    db.delete_organization(&team.org_id).await.unwrap();
+
+    // deleting again is a no-op
+    db.delete_user(user_id).await.unwrap();
 }
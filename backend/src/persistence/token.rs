@@ -1,87 +1,306 @@
 use crate::model::message::MessageToken;
-use crate::model::user::UserID;
+use crate::model::organization::OrgID;
+use crate::model::token::{token_id, TokenHash, TokenInfo, TokenMetadata};
 
-use crate::errors::APIError;
-use crate::service::token::random_alphanumeric_string;
+use crate::persistence::redis::RedisDatabaseService;
+use crate::persistence::{Persistence, TTL};
+use crate::service::token::{hash_token, random_alphanumeric_string};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
 use log::{error, info};
-use redis::aio::MultiplexedConnection;
 use redis::AsyncCommands;
-use std::str::FromStr;
+use std::collections::HashMap;
 use tokio::sync::Mutex;
 
-const TOKEN_LENGTH: u32 = 32;
+pub(crate) const TOKEN_LENGTH: u32 = 32;
 
-pub struct TokenStore {
-    pub connection: MultiplexedConnection,
+/// Tokens are only stored as [`hash_token`] hashes, so apart from creating and
+/// rotating, which return the new token once, tokens are referred to by hash.
+pub trait TokenStore {
+    async fn create_token(
+        &mut self,
+        org_id: &OrgID,
+        metadata: &TokenMetadata,
+    ) -> Result<MessageToken>;
+    /// Creates a token with the default metadata.
+    async fn create_token_for_org(&mut self, org_id: &OrgID) -> Result<MessageToken> {
+        self.create_token(org_id, &TokenMetadata::default()).await
+    }
+    /// The hashes of the tokens of an organization.
+    async fn get_tokens_of_org(&mut self, org_id: &OrgID) -> Result<Vec<TokenHash>>;
+    /// The organization of a token that didn't expire, e.g. at the end of the
+    /// grace window after rotating it.
+    async fn get_org_of_token(&mut self, token: &MessageToken) -> Result<Option<OrgID>> {
+        let now = Utc::now();
+        Ok(self
+            .get_token_info(&hash_token(token))
+            .await?
+            .filter(|info| !info.is_expired(now))
+            .map(|info| info.org_id))
+    }
+    async fn get_token_info(&mut self, hash: &TokenHash) -> Result<Option<TokenInfo>>;
+    async fn set_token_last_used(
+        &mut self,
+        hash: &TokenHash,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<()>;
+    /// Records that messages of `hostname` were sent with the token.
+    async fn set_token_host_used(
+        &mut self,
+        hash: &TokenHash,
+        hostname: &str,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<()>;
+    /// Issues a successor with the same settings. The token stays valid for
+    /// `grace` after `now`, see [`TokenInfo::rotate`], and is removed afterwards.
+    async fn rotate_token(
+        &mut self,
+        hash: &TokenHash,
+        now: DateTime<Utc>,
+        grace: Duration,
+    ) -> Result<MessageToken>;
+    async fn delete_token(&mut self, hash: &TokenHash) -> Result<()>;
+    /// Replaces tokens stored in plaintext by older versions with their hashes
+    /// and returns how many were migrated.
+    async fn migrate_plaintext_tokens(&mut self) -> Result<usize>;
 }
 
-impl TokenStore {
-    pub async fn create_token_for_user_id(&mut self, user_id: &UserID) -> MessageToken {
-        info!("create token for user_id {}", user_id);
-        let token = random_alphanumeric_string(TOKEN_LENGTH);
-        let key_user_id_to_token = format!("user_id_to_token:{user_id}");
-        let _: u8 = self
+/// Tokens are stored in the hash `token_to_user_id:{hash}` with the fields
+/// `user_id`, `token_id`, `metadata`, `last_used_at` and `rotation`. Tokens
+/// from before metadata existed only have `user_id`. The hosts using a token are
+/// kept in the hash `token_hosts:{hash}` from hostname to the time of last use,
+/// which expires after [`TTL::TokenHosts`] without use. Rotated tokens and
+/// their hosts expire at the end of the grace window.
+/// `user_id_to_token:{org_id}` is the set of the hashes of an organization's
+/// tokens. The keys and the `user_id` field, which holds the organization, are
+/// named after the users owning tokens before organizations existed; their
+/// personal organizations share their ids.
+impl TokenStore for RedisDatabaseService {
+    async fn create_token(
+        &mut self,
+        org_id: &OrgID,
+        metadata: &TokenMetadata,
+    ) -> Result<MessageToken> {
+        info!("create token for organization {}", org_id);
+        let mut pipe = redis::pipe();
+        let token = add_token(pipe.atomic(), org_id, metadata)?;
+        pipe.query_async::<()>(&mut self.connection).await?;
+        Ok(token)
+    }
+
+    /// Also drops the hashes of expired rotated tokens from the set.
+    async fn get_tokens_of_org(&mut self, org_id: &OrgID) -> Result<Vec<TokenHash>> {
+        let key = format!("user_id_to_token:{org_id}");
+        let hashes: Vec<TokenHash> = self.connection.smembers(&key).await?;
+        if hashes.is_empty() {
+            return Ok(hashes);
+        }
+        let mut pipe = redis::pipe();
+        for hash in &hashes {
+            pipe.exists(format!("token_to_user_id:{hash}"));
+        }
+        let exists: Vec<bool> = pipe.query_async(&mut self.connection).await?;
+        let (hashes, expired): (Vec<_>, Vec<_>) = hashes
+            .into_iter()
+            .zip(exists)
+            .partition(|(_, exists)| *exists);
+        if !expired.is_empty() {
+            let expired: Vec<_> = expired.into_iter().map(|(hash, _)| hash).collect();
+            let _: () = self.connection.srem(&key, expired).await?;
+        }
+        Ok(hashes.into_iter().map(|(hash, _)| hash).collect())
+    }
+
+    async fn get_token_info(&mut self, hash: &TokenHash) -> Result<Option<TokenInfo>> {
+        let mut fields: HashMap<String, String> = self
             .connection
-            .sadd(&key_user_id_to_token, &token)
-            .await
-            .map_err(|e| error!("{}", e))
-            .unwrap();
+            .hgetall(format!("token_to_user_id:{hash}"))
+            .await?;
+        let (Some(org_id), Some(token_id)) = (fields.remove("user_id"), fields.remove("token_id"))
+        else {
+            return Ok(None);
+        };
+        let metadata = match fields.remove("metadata") {
+            Some(metadata) => serde_json::from_str(&metadata)?,
+            None => TokenMetadata::default(),
+        };
+        let last_used_at = match fields.remove("last_used_at") {
+            Some(last_used_at) => Some(last_used_at.parse()?),
+            None => None,
+        };
+        let mut info = TokenInfo::new(
+            hash.clone(),
+            token_id,
+            org_id.into(),
+            metadata,
+            last_used_at,
+        );
+        if let Some(rotation) = fields.remove("rotation") {
+            info.rotation = Some(serde_json::from_str(&rotation)?);
+        }
+        let hosts: HashMap<String, String> = self
+            .connection
+            .hgetall(format!("token_hosts:{hash}"))
+            .await?;
+        for (hostname, last_used_at) in hosts {
+            info.use_host(&hostname, last_used_at.parse()?);
+        }
+        Ok(Some(info))
+    }
 
-        let key_token_to_user_id: String = format!("token_to_user_id:{token}");
-        let _: u8 = self
+    async fn set_token_last_used(
+        &mut self,
+        hash: &TokenHash,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let _: () = self
             .connection
-            .hset(key_token_to_user_id, "user_id", user_id.to_string())
-            .await
-            .expect("failed adding token");
-        token
+            .hset(
+                format!("token_to_user_id:{hash}"),
+                "last_used_at",
+                last_used_at.to_rfc3339(),
+            )
+            .await?;
+        Ok(())
     }
 
-    pub async fn get_token_of_user_id(&mut self, user_id: &UserID) -> Option<Vec<MessageToken>> {
-        let key = format!("user_id_to_token:{user_id}");
-        self.connection.smembers(&key).await.ok()
+    async fn set_token_host_used(
+        &mut self,
+        hash: &TokenHash,
+        hostname: &str,
+        last_used_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let key = format!("token_hosts:{hash}");
+        redis::pipe()
+            .atomic()
+            .hset(&key, hostname, last_used_at.to_rfc3339())
+            .expire(&key, TTL::TokenHosts as i64)
+            .query_async::<()>(&mut self.connection)
+            .await?;
+        Ok(())
     }
 
-    pub async fn get_user_id_of_token(&mut self, token: &MessageToken) -> Option<UserID> {
-        let key_token_to_user_id = format!("token_to_user_id:{token}");
-        let result: String = self
-            .connection
-            .hget(key_token_to_user_id, "user_id")
-            .await
-            .inspect_err(|e| error!("{e}"))
-            .ok()?;
-        UserID::from_str(&result).ok()
+    async fn rotate_token(
+        &mut self,
+        hash: &TokenHash,
+        now: DateTime<Utc>,
+        grace: Duration,
+    ) -> Result<MessageToken> {
+        let mut info = self
+            .get_token_info(hash)
+            .await?
+            .ok_or(anyhow!("unknown token"))?;
+        let mut pipe = redis::pipe();
+        let successor = add_token(pipe.atomic(), &info.org_id, &info.metadata.successor(now))?;
+        info.rotate(&successor, now, grace);
+        let key = format!("token_to_user_id:{hash}");
+        let expires_at = info.metadata.expires_at.unwrap_or(now + grace).timestamp();
+        pipe.hset_multiple(
+            &key,
+            &[
+                ("metadata", serde_json::to_string(&info.metadata)?),
+                ("rotation", serde_json::to_string(&info.rotation)?),
+            ],
+        )
+        .expire_at(&key, expires_at)
+        .expire_at(format!("token_hosts:{hash}"), expires_at);
+        pipe.query_async::<()>(&mut self.connection).await?;
+        Ok(successor)
     }
 
-    pub async fn delete_token(&mut self, token: &MessageToken) -> Result<(), APIError> {
-        let user_id = self
-            .get_user_id_of_token(token)
-            .await
-            .ok_or(APIError::InternalServerError)?;
-        let key_token_to_user_id = format!("token_to_user_id:{token}");
-        let key_user_id_to_token = format!("user_id_to_token:{user_id}");
+    async fn delete_token(&mut self, hash: &TokenHash) -> Result<()> {
+        let key_token_to_user_id = format!("token_to_user_id:{hash}");
+        let org_id: String = self
+            .connection
+            .hget::<_, _, Option<String>>(&key_token_to_user_id, "user_id")
+            .await?
+            .ok_or(anyhow!("unknown token"))?;
+        let key_user_id_to_token = format!("user_id_to_token:{org_id}");
 
-        self.connection
-            .del(key_token_to_user_id)
-            .await
-            .inspect_err(|e| error!("{e}"))
-            .map_err(|_| APIError::InternalServerError)?;
-        self.connection
-            .srem(key_user_id_to_token, token)
-            .await
-            .inspect_err(|e| error!("{e}"))
-            .map_err(|_| APIError::InternalServerError)?;
+        let _: () = self
+            .connection
+            .del(&[key_token_to_user_id, format!("token_hosts:{hash}")])
+            .await?;
+        let _: () = self.connection.srem(key_user_id_to_token, hash).await?;
         Ok(())
     }
+
+    /// Stored tokens without `token_id` are plaintext. Each is renamed to its
+    /// hash in one transaction. A token failing to migrate is rejected until
+    /// the next attempt.
+    async fn migrate_plaintext_tokens(&mut self) -> Result<usize> {
+        let keys = self.scan_keys("token_to_user_id:*").await?;
+        let mut migrated = 0;
+        for key in keys {
+            let hashed: bool = self.connection.hexists(&key, "token_id").await?;
+            let Some(token) = key.strip_prefix("token_to_user_id:").filter(|_| !hashed) else {
+                continue;
+            };
+            let Some(org_id): Option<String> = self.connection.hget(&key, "user_id").await? else {
+                continue;
+            };
+            let hash = hash_token(token);
+            let key_user_id_to_token = format!("user_id_to_token:{org_id}");
+            let has_hosts: bool = self
+                .connection
+                .exists(format!("token_hosts:{token}"))
+                .await?;
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .rename(&key, format!("token_to_user_id:{hash}"))
+                .hset(
+                    format!("token_to_user_id:{hash}"),
+                    "token_id",
+                    token_id(token),
+                )
+                .srem(&key_user_id_to_token, token)
+                .sadd(&key_user_id_to_token, &hash);
+            if has_hosts {
+                pipe.rename(
+                    format!("token_hosts:{token}"),
+                    format!("token_hosts:{hash}"),
+                );
+            }
+            match pipe.query_async::<()>(&mut self.connection).await {
+                Ok(()) => migrated += 1,
+                Err(e) => error!(
+                    "failed to migrate token {} of {org_id}: {e}",
+                    token_id(token)
+                ),
+            }
+        }
+        Ok(migrated)
+    }
+}
+
+/// Queues the commands storing a new token and returns the token.
+fn add_token(
+    pipe: &mut redis::Pipeline,
+    org_id: &OrgID,
+    metadata: &TokenMetadata,
+) -> Result<MessageToken> {
+    let token = random_alphanumeric_string(TOKEN_LENGTH);
+    let hash = hash_token(&token);
+    pipe.sadd(format!("user_id_to_token:{org_id}"), &hash)
+        .hset_multiple(
+            format!("token_to_user_id:{hash}"),
+            &[
+                ("user_id", org_id.to_string()),
+                ("token_id", token_id(&token)),
+                ("metadata", serde_json::to_string(metadata)?),
+            ],
+        );
+    Ok(token)
 }
 
 pub struct TokenState {
-    pub token: Mutex<TokenStore>,
+    pub token: Mutex<Persistence>,
 }
 
 impl TokenState {
-    pub fn new(connection: MultiplexedConnection) -> TokenState {
+    pub fn new(persistence: Persistence) -> TokenState {
         Self {
-            token: Mutex::new(TokenStore { connection }),
+            token: Mutex::new(persistence),
         }
     }
 }
@@ -89,30 +308,113 @@ impl TokenState {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::RedisDatabaseService;
 
     #[tokio::test]
     async fn test_token_store() {
-        let db = RedisDatabaseService::new().await.unwrap();
-        let mut store = TokenStore {
-            connection: db.connection,
-        };
-        let user_id = UserID::new();
-        let _ = store.create_token_for_user_id(&user_id).await;
-        let _ = store.create_token_for_user_id(&user_id).await;
-        let token_list = store.get_token_of_user_id(&user_id).await.unwrap();
+        let mut store = RedisDatabaseService::new().await.unwrap();
+        let org_id = OrgID::new();
+        let _ = store.create_token_for_org(&org_id).await.unwrap();
+        let _ = store.create_token_for_org(&org_id).await.unwrap();
+        let token_list = store.get_tokens_of_org(&org_id).await.unwrap();
         assert_eq!(token_list.len(), 2);
     }
 
     #[tokio::test]
-    async fn test_user_id_of_token() {
-        let db = RedisDatabaseService::new().await.unwrap();
-        let mut store = TokenStore {
-            connection: db.connection,
-        };
+    async fn test_org_of_token() {
+        let mut store = RedisDatabaseService::new().await.unwrap();
+
+        let org_id = OrgID::new();
+        let token = store.create_token_for_org(&org_id).await.unwrap();
+        assert_eq!(
+            org_id,
+            store.get_org_of_token(&token).await.unwrap().unwrap()
+        );
+        let stored: bool = store
+            .connection
+            .exists(format!("token_to_user_id:{token}"))
+            .await
+            .unwrap();
+        assert!(!stored);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_token() {
+        let mut store = RedisDatabaseService::new().await.unwrap();
+        let org_id = OrgID::new();
+        let token = store.create_token_for_org(&org_id).await.unwrap();
+        let hash = hash_token(&token);
+        let now = Utc::now();
+        store.set_token_host_used(&hash, "host", now).await.unwrap();
+
+        let successor = store
+            .rotate_token(&hash, now, Duration::hours(1))
+            .await
+            .unwrap();
+        let info = store.get_token_info(&hash).await.unwrap().unwrap();
+        assert_eq!(info.rotation.unwrap().successor, token_id(&successor));
+        assert_eq!(info.hosts[0].hostname, "host");
+        assert_eq!(
+            store.get_org_of_token(&token).await.unwrap(),
+            Some(org_id.clone())
+        );
+        assert_eq!(
+            store.get_org_of_token(&successor).await.unwrap(),
+            Some(org_id)
+        );
+        for key in [
+            format!("token_to_user_id:{hash}"),
+            format!("token_hosts:{hash}"),
+        ] {
+            let ttl: i64 = store.connection.ttl(key).await.unwrap();
+            assert!(0 < ttl && ttl <= 3600);
+        }
 
-        let user_id = UserID::new();
-        let token = store.create_token_for_user_id(&user_id).await;
-        assert_eq!(user_id, store.get_user_id_of_token(&token).await.unwrap());
+        store.delete_token(&hash).await.unwrap();
+        let hosts: bool = store
+            .connection
+            .exists(format!("token_hosts:{hash}"))
+            .await
+            .unwrap();
+        assert!(!hosts);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_plaintext_tokens() {
+        let mut store = RedisDatabaseService::new().await.unwrap();
+        let org_id = OrgID::new();
+        let token = random_alphanumeric_string(TOKEN_LENGTH);
+        let _: () = store
+            .connection
+            .sadd(format!("user_id_to_token:{org_id}"), &token)
+            .await
+            .unwrap();
+        let _: () = store
+            .connection
+            .hset(
+                format!("token_to_user_id:{token}"),
+                "user_id",
+                org_id.to_string(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(store.get_org_of_token(&token).await.unwrap(), None);
+
+        assert!(store.migrate_plaintext_tokens().await.unwrap() >= 1);
+        assert_eq!(
+            store.get_org_of_token(&token).await.unwrap(),
+            Some(org_id.clone())
+        );
+        assert_eq!(
+            store.get_tokens_of_org(&org_id).await.unwrap(),
+            [hash_token(&token)]
+        );
+        let info = store
+            .get_token_info(&hash_token(&token))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.token_id, token_id(&token));
+        assert_eq!(info.metadata, TokenMetadata::default());
+        store.delete_token(&info.hash).await.unwrap();
     }
 }
@@ -34,6 +34,12 @@ pub fn valid_hash(hash: &str, password: &str) -> bool {
     false
 }
 
+/// Compares secrets without revealing the position of the first difference
+/// through timing.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 #[test]
 pub fn test_hasher_valid() {
     let password = "password";
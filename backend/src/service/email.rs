@@ -13,7 +13,8 @@ use std::env;
 use tera;
 use tera::{Context, Tera};
 
-pub struct RegistrationMessage {
+pub struct MailMessage {
+    subject: &'static str,
     payload: String,
 }
 
@@ -30,25 +31,81 @@ lazy_static! {
         let raw_template = include_str!("templates/registration.html");
         tera.add_raw_template("registration.html", raw_template)
             .expect("failed adding template");
+        let raw_template = include_str!("templates/password_reset.html");
+        tera.add_raw_template("password_reset.html", raw_template)
+            .expect("failed adding template");
+        let raw_template = include_str!("templates/email_change.html");
+        tera.add_raw_template("email_change.html", raw_template)
+            .expect("failed adding template");
+        let raw_template = include_str!("templates/account_locked.html");
+        tera.add_raw_template("account_locked.html", raw_template)
+            .expect("failed adding template");
+        let raw_template = include_str!("templates/invitation.html");
+        tera.add_raw_template("invitation.html", raw_template)
+            .expect("failed adding template");
         tera.autoescape_on(vec!["*.html"]);
         tera
     };
 }
 
-pub fn generate_registration_mail(email: &str, activation_link: &Url) -> RegistrationMessage {
+pub fn generate_registration_mail(email: &str, activation_link: &Url) -> MailMessage {
     let mut context = Context::new();
     context.insert("email", email);
     context.insert("activation_link", &activation_link.to_string());
 
-    RegistrationMessage {
+    MailMessage {
+        subject: "Snitch User Registration",
         payload: TEMPLATES.render("registration.html", &context).unwrap(),
     }
 }
 
-pub async fn send_registration_mail(
-    message: RegistrationMessage,
-    receiver: Mailbox,
-) -> Result<Response, Error> {
+pub fn generate_password_reset_mail(reset_link: &Url) -> MailMessage {
+    let mut context = Context::new();
+    context.insert("reset_link", &reset_link.to_string());
+
+    MailMessage {
+        subject: "Snitch Password Reset",
+        payload: TEMPLATES.render("password_reset.html", &context).unwrap(),
+    }
+}
+
+pub fn generate_email_change_mail(confirmation_link: &Url) -> MailMessage {
+    let mut context = Context::new();
+    context.insert("confirmation_link", &confirmation_link.to_string());
+
+    MailMessage {
+        subject: "Snitch Email Change",
+        payload: TEMPLATES.render("email_change.html", &context).unwrap(),
+    }
+}
+
+pub fn generate_account_locked_mail(lockout_minutes: i64) -> MailMessage {
+    let mut context = Context::new();
+    context.insert("lockout_minutes", &lockout_minutes);
+
+    MailMessage {
+        subject: "Snitch Account Locked",
+        payload: TEMPLATES.render("account_locked.html", &context).unwrap(),
+    }
+}
+
+pub fn generate_invitation_mail(
+    organization: &str,
+    role: &str,
+    invitation_link: &Url,
+) -> MailMessage {
+    let mut context = Context::new();
+    context.insert("organization", organization);
+    context.insert("role", role);
+    context.insert("invitation_link", &invitation_link.to_string());
+
+    MailMessage {
+        subject: "Snitch Invitation",
+        payload: TEMPLATES.render("invitation.html", &context).unwrap(),
+    }
+}
+
+pub async fn send_mail(message: MailMessage, receiver: Mailbox) -> Result<Response, Error> {
     let smtp_user = env::var("SNITCH_SMTP_USER").expect("SNITCH_SMTP_USER not defined");
     let smtp_password = env::var("SNITCH_SMTP_PASSWORD").expect("SNITCH_SMTP_PASSWORD not defined");
     let smtp_server = env::var("SNITCH_SMTP_URL").expect("SNITCH_SMTP_URL not defined");
@@ -58,7 +115,7 @@ pub async fn send_registration_mail(
         .from("noreply@snitch.cool".parse().unwrap())
         .reply_to("noreply@snitch.cool".parse().unwrap())
         .to(receiver)
-        .subject("Snitch User Registration")
+        .subject(message.subject)
         .body(message.payload)
         .unwrap();
 
@@ -79,11 +136,9 @@ async fn test_email_client() {
         "Bob",
         &Url::parse("https://snitch.cool/register/isdjfolisjdflijs").unwrap(),
     );
-    assert!(
-        send_registration_mail(test_message, test_recipient.parse().unwrap())
-            .await
-            .is_ok()
-    );
+    assert!(send_mail(test_message, test_recipient.parse().unwrap())
+        .await
+        .is_ok());
 }
 
 #[test]
@@ -92,4 +147,24 @@ fn test_render_email() {
         "liajsdfljasdlifj.sdlfijsdlfijsdlfijsldfjdfjdf@gmail.com",
         &Url::parse("https://snitch.cool/register/isdjfolisjdflijs").unwrap(),
     );
+    let mail = generate_password_reset_mail(
+        &Url::parse("https://snitch.cool/password/reset/isdjfolisjdflijs").unwrap(),
+    );
+    assert!(mail
+        .payload
+        .contains("https://snitch.cool/password/reset/isdjfolisjdflijs"));
+    let mail = generate_email_change_mail(
+        &Url::parse("https://snitch.cool/user/email/isdjfolisjdflijs").unwrap(),
+    );
+    assert!(mail
+        .payload
+        .contains("https://snitch.cool/user/email/isdjfolisjdflijs"));
+    let mail = generate_invitation_mail(
+        "team",
+        "viewer",
+        &Url::parse("https://snitch.cool/invitations/isdjfolisjdflijs").unwrap(),
+    );
+    assert!(mail
+        .payload
+        .contains("https://snitch.cool/invitations/isdjfolisjdflijs"));
 }
@@ -0,0 +1,469 @@
+use crate::api::AppState;
+use crate::model::message::{MessageBackend, MessageEvent};
+use crate::model::organization::OrgID;
+use crate::persistence::{
+    MessageKey, PersistIdempotencyKey, PersistMessage, PersistNotificationRules,
+    PersistNotificationSettings,
+};
+use crate::service::kafka::{delivered, KafkaManager, KafkaPersistClient};
+use crate::service::kafka_config::KafkaConfig;
+use crate::service::notification_dispatcher::{NotificationActor, Notify};
+use crate::service::notification_rules::evaluate;
+use actix::Addr;
+use actix_web::web::Data;
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use std::env;
+use thiserror::Error;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+
+const DIRECT_QUEUE_CAPACITY: usize = 10_000;
+/// Suggested delay in seconds before clients retry a rejected message.
+pub(crate) const RETRY_AFTER: u64 = 1;
+
+#[derive(Debug, Error)]
+pub(crate) enum IngestError {
+    #[error("ingestion queue is full")]
+    QueueFull,
+    #[error("message delivery failed: {0}")]
+    Delivery(String),
+}
+
+/// Hands validated messages of an organization over to persistence and notifications.
+///
+/// With `wait_for_ack` the call returns once the messages are stored (or
+/// acknowledged by the broker), otherwise once they are queued.
+pub(crate) trait IngestMessages {
+    async fn ingest(
+        &self,
+        org_id: OrgID,
+        messages: Vec<MessageBackend>,
+        wait_for_ack: bool,
+    ) -> Result<(), IngestError>;
+}
+
+/// Produces messages to Kafka. The consumer started alongside stores them.
+pub(crate) struct KafkaIngestion {
+    producer: KafkaManager,
+    _consumer: KafkaPersistClient,
+}
+
+impl KafkaIngestion {
+    pub(crate) fn new(
+        config: KafkaConfig,
+        state: Data<AppState>,
+        notification_addr: Data<Addr<NotificationActor>>,
+    ) -> Self {
+        let producer = KafkaManager::new(&config);
+        let consumer = KafkaPersistClient::new(state, notification_addr, config);
+        Self {
+            producer,
+            _consumer: consumer,
+        }
+    }
+}
+
+impl IngestMessages for KafkaIngestion {
+    async fn ingest(
+        &self,
+        org_id: OrgID,
+        messages: Vec<MessageBackend>,
+        wait_for_ack: bool,
+    ) -> Result<(), IngestError> {
+        if !self.producer.has_room(messages.len()) {
+            return Err(IngestError::QueueFull);
+        }
+        let deliveries = messages
+            .into_iter()
+            .map(|message| self.producer.enqueue(&org_id, &message.into()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if wait_for_ack {
+            for delivery in deliveries {
+                delivered(delivery).await?;
+            }
+        } else {
+            tokio::task::spawn(async move {
+                for delivery in deliveries {
+                    if let Err(e) = delivered(delivery).await {
+                        warn!("message of {org_id} lost: {e}");
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+type Ack = oneshot::Sender<Result<(), IngestError>>;
+
+/// Stores messages in process through a channel, for deployments without Kafka.
+/// Messages still queued are lost on shutdown.
+pub(crate) struct DirectIngestion {
+    sender: mpsc::Sender<(OrgID, MessageBackend, Option<Ack>)>,
+}
+
+impl DirectIngestion {
+    pub(crate) fn new(
+        state: Data<AppState>,
+        notification_addr: Data<Addr<NotificationActor>>,
+    ) -> Self {
+        Self::with_capacity(state, notification_addr, DIRECT_QUEUE_CAPACITY)
+    }
+
+    fn with_capacity(
+        state: Data<AppState>,
+        notification_addr: Data<Addr<NotificationActor>>,
+        capacity: usize,
+    ) -> Self {
+        let (sender, mut receiver) =
+            mpsc::channel::<(OrgID, MessageBackend, Option<Ack>)>(capacity);
+        tokio::task::spawn(async move {
+            while let Some((org_id, message, ack)) = receiver.recv().await {
+                let result = store_and_notify(&state, &notification_addr, org_id, message)
+                    .await
+                    .map_err(|e| IngestError::Delivery(e.to_string()));
+                if let Err(e) = &result {
+                    error!("failed storing message: {e}");
+                }
+                if let Some(ack) = ack {
+                    let _ = ack.send(result);
+                }
+            }
+        });
+        Self { sender }
+    }
+}
+
+impl IngestMessages for DirectIngestion {
+    async fn ingest(
+        &self,
+        org_id: OrgID,
+        messages: Vec<MessageBackend>,
+        wait_for_ack: bool,
+    ) -> Result<(), IngestError> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        // reserves room for the whole batch, so a rejected batch queues nothing
+        // and its retry doesn't duplicate messages
+        let permits = self
+            .sender
+            .try_reserve_many(messages.len())
+            .map_err(|e| match e {
+                TrySendError::Full(_) => IngestError::QueueFull,
+                TrySendError::Closed(_) => {
+                    IngestError::Delivery("ingestion worker stopped".to_string())
+                }
+            })?;
+        let mut acks = Vec::new();
+        for (permit, message) in permits.zip(messages) {
+            let ack = if wait_for_ack {
+                let (ack, acked) = oneshot::channel();
+                acks.push(acked);
+                Some(ack)
+            } else {
+                None
+            };
+            permit.send((org_id.clone(), message, ack));
+        }
+        for acked in acks {
+            acked
+                .await
+                .map_err(|_| IngestError::Delivery("ingestion worker stopped".to_string()))??;
+        }
+        Ok(())
+    }
+}
+
+/// The ingestion pipeline selected at startup via `SNITCH_INGESTION`.
+pub(crate) enum Ingestion {
+    Kafka(KafkaIngestion),
+    Direct(DirectIngestion),
+}
+
+impl Ingestion {
+    pub(crate) fn new(
+        state: Data<AppState>,
+        notification_addr: Data<Addr<NotificationActor>>,
+    ) -> Result<Self> {
+        let mode = env::var("SNITCH_INGESTION").unwrap_or("kafka".to_string());
+        info!("using {mode} ingestion");
+        match mode.as_str() {
+            "kafka" => Ok(Ingestion::Kafka(KafkaIngestion::new(
+                KafkaConfig::from_env()?,
+                state,
+                notification_addr,
+            ))),
+            "direct" => Ok(Ingestion::Direct(DirectIngestion::new(
+                state,
+                notification_addr,
+            ))),
+            _ => bail!("unknown SNITCH_INGESTION: {mode}"),
+        }
+    }
+}
+
+impl IngestMessages for Ingestion {
+    async fn ingest(
+        &self,
+        org_id: OrgID,
+        messages: Vec<MessageBackend>,
+        wait_for_ack: bool,
+    ) -> Result<(), IngestError> {
+        match self {
+            Ingestion::Kafka(ingestion) => ingestion.ingest(org_id, messages, wait_for_ack).await,
+            Ingestion::Direct(ingestion) => ingestion.ingest(org_id, messages, wait_for_ack).await,
+        }
+    }
+}
+
+/// Persists a message, publishes it to open message streams and notifies the organization.
+/// Messages repeating an idempotency key of the organization are skipped. The key
+/// is released if storing fails, so that the message can be retried.
+pub(crate) async fn store_and_notify(
+    state: &Data<AppState>,
+    notification_addr: &Addr<NotificationActor>,
+    org_id: OrgID,
+    message: MessageBackend,
+) -> Result<()> {
+    let message_key = MessageKey {
+        org_id,
+        hostname: message.hostname.clone(),
+    };
+    {
+        let mut persist = state.persist.lock().await;
+        if let Some(idempotency_key) = &message.idempotency_key {
+            if !persist
+                .claim_idempotency_key(&message_key.org_id, idempotency_key)
+                .await?
+            {
+                info!("skipping duplicate message {idempotency_key}");
+                return Ok(());
+            }
+        }
+        if let Err(e) = persist.add_message(&message_key, &message).await {
+            if let Some(idempotency_key) = &message.idempotency_key {
+                persist
+                    .release_idempotency_key(&message_key.org_id, idempotency_key)
+                    .await?;
+            }
+            return Err(e);
+        }
+    }
+    let _ = state.message_events.send(MessageEvent {
+        org_id: message_key.org_id.clone(),
+        message: message.clone(),
+    });
+    notify_org(state, notification_addr, &message_key.org_id, message).await;
+    Ok(())
+}
+
+/// Forwards a persisted message to the channels selected by the organization's notification
+/// rules, rate limited by the [`NotificationFilter`](crate::service::notification_filter::NotificationFilter).
+async fn notify_org(
+    state: &Data<AppState>,
+    notification_addr: &Addr<NotificationActor>,
+    org_id: &OrgID,
+    message: MessageBackend,
+) {
+    let notification_settings = match state
+        .persist
+        .lock()
+        .await
+        .get_notification_settings(org_id)
+        .await
+    {
+        Ok(notification_settings) => notification_settings,
+        Err(e) => {
+            warn!("failed loading notification settings of {org_id}: {e}");
+            return;
+        }
+    };
+    let rules = match state
+        .persist
+        .lock()
+        .await
+        .get_notification_rules(org_id)
+        .await
+    {
+        Ok(rules) => rules,
+        Err(e) => {
+            warn!("failed loading notification rules of {org_id}: {e}");
+            return;
+        }
+    };
+    let Some(notification_settings) = evaluate(&rules, &message).apply(notification_settings)
+    else {
+        info!("skipping notification of {org_id}: muted by rule");
+        return;
+    };
+    if !notification_settings.has_channels() {
+        return;
+    }
+
+    if !state
+        .notification_filter
+        .lock()
+        .await
+        .notify_org(org_id)
+        .await
+    {
+        info!("skipping notification of {org_id}: rate limited");
+        return;
+    }
+
+    notification_addr.do_send(Notify(notification_settings, message));
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::service::notification_dispatcher::NotificationManager;
+    use actix::Actor;
+    use needs_env_var::needs_env_var;
+    use std::time::Duration;
+
+    pub(crate) fn notification_addr() -> Data<Addr<NotificationActor>> {
+        Data::new(
+            NotificationActor {
+                notification_manager: NotificationManager::new(),
+            }
+            .start(),
+        )
+    }
+
+    fn message(title: &str) -> MessageBackend {
+        MessageBackend {
+            hostname: "host".to_string(),
+            title: title.to_string(),
+            idempotency_key: Some(title.to_string()),
+            ..Default::default()
+        }
+        .validated()
+        .unwrap()
+    }
+
+    /// Shared by all pipelines: ingested messages end up in persistence and on
+    /// the message stream, repeated idempotency keys are stored once.
+    async fn ingest_and_store(ingestion: &Ingestion, state: &Data<AppState>) {
+        let mut events = state.message_events.subscribe();
+        let org_id = OrgID::new();
+        let messages = (0..3).map(|i| message(&format!("title-{i}"))).collect();
+        ingestion
+            .ingest(org_id.clone(), messages, false)
+            .await
+            .unwrap();
+        ingestion
+            .ingest(
+                org_id.clone(),
+                vec![message("title-0"), message("title-3")],
+                true,
+            )
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            let event = tokio::time::timeout(Duration::from_secs(30), events.recv())
+                .await
+                .expect("no message received")
+                .unwrap();
+            if event.org_id == org_id {
+                received.push(event.message.title);
+            }
+        }
+        assert_eq!(received, vec!["title-0", "title-1", "title-2", "title-3"]);
+        let key = MessageKey {
+            org_id,
+            hostname: "host".to_string(),
+        };
+        let stored = state
+            .persist
+            .lock()
+            .await
+            .find_messages(&key)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 4);
+    }
+
+    #[actix_web::test]
+    async fn test_direct_ingestion() {
+        let state = Data::new(AppState::in_memory());
+        let ingestion = Ingestion::Direct(DirectIngestion::new(state.clone(), notification_addr()));
+        ingest_and_store(&ingestion, &state).await;
+    }
+
+    #[actix_web::test]
+    async fn test_direct_ingestion_queue_full() {
+        let state = Data::new(AppState::in_memory());
+        let ingestion = DirectIngestion::with_capacity(state.clone(), notification_addr(), 1);
+        // blocks the worker
+        let _persist = state.persist.lock().await;
+
+        let mut results = Vec::new();
+        for i in 0..3 {
+            results.push(
+                ingestion
+                    .ingest(OrgID::new(), vec![message(&format!("title-{i}"))], false)
+                    .await,
+            );
+        }
+        assert!(matches!(results.last(), Some(Err(IngestError::QueueFull))));
+    }
+
+    #[actix_web::test]
+    async fn test_direct_ingestion_batch_all_or_nothing() {
+        let state = Data::new(AppState::in_memory());
+        let ingestion = DirectIngestion::with_capacity(state.clone(), notification_addr(), 2);
+        let org_id = OrgID::new();
+        let persist = state.persist.lock().await;
+
+        let messages = (0..3).map(|i| message(&format!("title-{i}"))).collect();
+        let result = ingestion.ingest(org_id.clone(), messages, false).await;
+        assert!(matches!(result, Err(IngestError::QueueFull)));
+        let messages = (3..5).map(|i| message(&format!("title-{i}"))).collect();
+        ingestion
+            .ingest(org_id.clone(), messages, false)
+            .await
+            .unwrap();
+        drop(persist);
+
+        let key = MessageKey {
+            org_id,
+            hostname: "host".to_string(),
+        };
+        let mut stored = Vec::new();
+        for _ in 0..100 {
+            stored = state
+                .persist
+                .lock()
+                .await
+                .find_messages(&key)
+                .await
+                .unwrap();
+            if stored.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let titles: Vec<_> = stored.into_iter().map(|m| m.title).collect();
+        assert_eq!(titles, vec!["title-3", "title-4"]);
+    }
+
+    #[needs_env_var(SNITCH_KAFKA_BROKERS)]
+    #[actix_web::test]
+    async fn test_kafka_ingestion() {
+        let state = Data::new(AppState::in_memory());
+        let mut config = KafkaConfig::from_env().unwrap();
+        config.group_id = format!("snitch-test-{}", OrgID::new());
+        config.consumer_auto_offset_reset = Some("earliest".to_string());
+        let ingestion = Ingestion::Kafka(KafkaIngestion::new(
+            config,
+            state.clone(),
+            notification_addr(),
+        ));
+        ingest_and_store(&ingestion, &state).await;
+    }
+}
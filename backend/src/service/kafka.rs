@@ -1,14 +1,16 @@
 use crate::persistence::redis::NotificationSettings;
-use actix::ActorFutureExt;
-use actix::{Actor, Context, Handler, Message as ActixMessage, ResponseActFuture, WrapFuture};
+use crate::service::notification_dispatcher::NotificationActor;
+use actix::Addr;
 use chatterbox::message::Dispatcher;
 use rdkafka::Message;
-use std::io::Cursor;
 
 use crate::api::AppState;
-use crate::model::message::{serialize_message, MessageBackend, MessageToken, ProtoMessageBackend};
-use crate::model::user::UserID;
-use crate::persistence::{MessageKey, PersistMessage};
+use crate::model::message::{
+    serialize_message, InvalidMessage, MessageBackend, MessageToken, ProtoMessageBackend,
+};
+use crate::model::organization::OrgID;
+use crate::service::ingestion::{store_and_notify, IngestError};
+use crate::service::kafka_config::{KafkaConfig, PRODUCER_QUEUE_CAPACITY};
 use actix_web::web::Data;
 use log::{info, warn};
 use prost::Message as _;
@@ -16,13 +18,15 @@ use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use rdkafka::consumer::{
     BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer,
 };
-use rdkafka::error::KafkaResult;
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
 use rdkafka::message::{Header, Headers, OwnedHeaders, ToBytes};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord, Producer};
 use rdkafka::util::get_rdkafka_version;
 use rdkafka::{ClientContext, TopicPartitionList};
 use serde_json::ser::State;
+use std::string::FromUtf8Error;
 use std::time::Duration;
+use thiserror::Error;
 use tokio::task::JoinHandle;
 
 async fn produce(brokers: &str, topic_name: &str) {
@@ -44,76 +48,84 @@ async fn produce(brokers: &str, topic_name: &str) {
 #[derive(Clone)]
 pub(crate) struct KafkaManager {
     producer: FutureProducer,
+    topic: String,
+    dead_letter_topic: String,
 }
 
 impl KafkaManager {
-    pub(crate) fn new() -> KafkaManager {
-        let producer: FutureProducer = ClientConfig::new()
-            // .set("sasl.mechanism", "SCRAM-SHA-256")
-            // .set("sasl.username", "tool")
-            // .set("sasl.password", "token")
-            .set("group.id", "snitch-backend")
-            .set("bootstrap.servers", "localhost:9092")
-            .set("queue.buffering.max.ms", "1000")
-            .set("message.timeout.ms", "5000")
+    pub(crate) fn new(config: &KafkaConfig) -> KafkaManager {
+        let producer: FutureProducer = config
+            .producer_config()
             .create()
             .expect("Producer creation error");
 
-        Self { producer }
+        Self {
+            producer,
+            topic: config.topic.clone(),
+            dead_letter_topic: config.dead_letter_topic.clone(),
+        }
     }
 }
 
 impl KafkaManager {
-    pub(crate) async fn try_notify(&self, message: TryNotify) -> bool {
-        let payload = serialize_message(&message.1);
-        let delivery_status = self
-            .producer
-            .send(
-                FutureRecord::to("messages-backend")
-                    .payload(&payload)
-                    .key(&message.0)
-                    .headers(OwnedHeaders::new().insert(Header {
-                        key: "header_key",
-                        value: Some("header_value"),
-                    })),
-                Duration::from_secs(0),
-            )
-            .await;
-        delivery_status.is_ok()
+    /// Whether the local queue of the producer has room for `count` more
+    /// messages, checked before enqueueing a batch so that it isn't enqueued
+    /// partly.
+    pub(crate) fn has_room(&self, count: usize) -> bool {
+        self.producer.in_flight_count() as usize + count <= PRODUCER_QUEUE_CAPACITY
     }
-}
-
-#[derive(ActixMessage, Clone)]
-#[rtype(result = "Result<bool, ()>")]
-pub(crate) struct TryNotify(pub UserID, pub ProtoMessageBackend);
-
-pub(crate) struct KafkaActor {
-    pub(crate) producer: KafkaManager,
-}
 
-impl KafkaActor {
-    pub(crate) fn new(producer: KafkaManager) -> Self {
-        Self { producer }
+    /// Enqueues a message in the producer without waiting for the broker. Fails
+    /// right away if the local queue is full.
+    pub(crate) fn enqueue(
+        &self,
+        org_id: &OrgID,
+        message: &ProtoMessageBackend,
+    ) -> Result<DeliveryFuture, IngestError> {
+        let payload = serialize_message(message);
+        self.producer
+            .send_result(FutureRecord::to(&self.topic).payload(&payload).key(org_id))
+            .map_err(|(e, _)| match e {
+                KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull) => {
+                    IngestError::QueueFull
+                }
+                e => IngestError::Delivery(e.to_string()),
+            })
     }
-}
 
-impl Actor for KafkaActor {
-    type Context = Context<Self>;
+    /// Forwards a record that could not be processed, with the reason in the
+    /// `error` header.
+    pub(crate) async fn send_to_dead_letter(
+        &self,
+        key: Option<&[u8]>,
+        payload: Option<&[u8]>,
+        error: &RecordError,
+    ) {
+        let reason = error.to_string();
+        let mut record = FutureRecord::<[u8], [u8]>::to(&self.dead_letter_topic).headers(
+            OwnedHeaders::new().insert(Header {
+                key: "error",
+                value: Some(reason.as_str()),
+            }),
+        );
+        if let Some(key) = key {
+            record = record.key(key);
+        }
+        if let Some(payload) = payload {
+            record = record.payload(payload);
+        }
+        if let Err((e, _)) = self.producer.send(record, Duration::from_secs(0)).await {
+            warn!("failed producing to {}: {e}", self.dead_letter_topic);
+        }
+    }
 }
 
-impl Handler<TryNotify> for KafkaActor {
-    type Result = ResponseActFuture<Self, Result<bool, ()>>;
-
-    fn handle(&mut self, msg: TryNotify, _: &mut Context<Self>) -> Self::Result {
-        println!("trying to notify: {:?}", msg.0);
-        let p = self.producer.clone();
-        Box::pin(
-            async move {
-                p.try_notify(msg).await;
-                Ok(true)
-            }
-            .into_actor(self),
-        )
+/// Waits for the broker to acknowledge an enqueued message.
+pub(crate) async fn delivered(delivery: DeliveryFuture) -> Result<(), IngestError> {
+    match delivery.await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err((e, _))) => Err(IngestError::Delivery(e.to_string())),
+        Err(_) => Err(IngestError::Delivery("producer dropped".to_string())),
     }
 }
 
@@ -122,16 +134,14 @@ pub(crate) struct KafkaPersistClient {
 }
 
 impl KafkaPersistClient {
-    pub(crate) fn new(state: Data<AppState>) -> Self {
+    pub(crate) fn new(
+        state: Data<AppState>,
+        notification_addr: Data<Addr<NotificationActor>>,
+        config: KafkaConfig,
+    ) -> Self {
+        let dead_letter = KafkaManager::new(&config);
         let handle = tokio::task::spawn(async move {
-            consume_and_store(
-                "localhost:9092",
-                "snitch-backend",
-                &["messages-backend"],
-                None,
-                state,
-            )
-            .await;
+            consume_and_store(&config, state, notification_addr, dead_letter).await;
         });
         KafkaPersistClient { handle }
     }
@@ -159,64 +169,30 @@ impl ConsumerContext for CustomContext {
 type LoggingConsumer = StreamConsumer<CustomContext>;
 
 async fn consume_and_store(
-    brokers: &str,
-    group_id: &str,
-    topics: &[&str],
-    assignor: Option<&String>,
+    config: &KafkaConfig,
     state: Data<AppState>,
+    notification_addr: Data<Addr<NotificationActor>>,
+    dead_letter: KafkaManager,
 ) {
     let context = CustomContext;
 
-    let mut config = ClientConfig::new();
-
-    config
-        .set("group.id", group_id)
-        .set("bootstrap.servers", brokers)
-        .set("enable.partition.eof", "false")
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "true")
-        //.set("statistics.interval.ms", "30000")
-        //.set("auto.offset.reset", "smallest")
-        .set_log_level(RDKafkaLogLevel::Debug);
-
-    if let Some(assignor) = assignor {
-        config
-            .set("group.remote.assignor", assignor)
-            .set("group.protocol", "consumer")
-            .remove("session.timeout.ms");
-    }
+    let mut consumer_config = config.consumer_config();
+    consumer_config.set_log_level(RDKafkaLogLevel::Debug);
 
-    let consumer: LoggingConsumer = config
+    let consumer: LoggingConsumer = consumer_config
         .create_with_context(context)
         .expect("Consumer creation failed");
 
     consumer
-        .subscribe(topics)
+        .subscribe(&[&config.topic])
         .expect("Can't subscribe to specified topics");
 
     loop {
         match consumer.recv().await {
             Err(e) => warn!("Kafka error: {}", e),
             Ok(m) => {
-                let payload = match m.payload_view::<[u8]>() {
-                    None => None,
-                    Some(Ok(s)) => {
-                        let message = ProtoMessageBackend::decode(&mut Cursor::new(s)).unwrap();
-                        Some(message)
-                    }
-                    Some(Err(e)) => {
-                        warn!("Error while deserializing message payload: {:?}", e);
-                        None
-                    }
-                };
-                let message = payload.unwrap();
-
-                let user_id = m.key().unwrap();
-                let user_id = UserID(String::from_utf8(user_id.to_vec()).unwrap());
                 info!(
-                    "key: '{:?}', message: '{:?}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
-                    user_id,
-                    message,
+                    "topic: {}, partition: {}, offset: {}, timestamp: {:?}",
                     m.topic(),
                     m.partition(),
                     m.offset(),
@@ -227,20 +203,131 @@ async fn consume_and_store(
                         info!("  Header {:#?}: {:?}", header.key, header.value);
                     }
                 }
-                consumer.commit_message(&m, CommitMode::Async).unwrap();
-                let message_key = MessageKey {
-                    user_id,
-                    hostname: message.hostname.clone(),
+                let result = match decode_record(m.key(), m.payload()) {
+                    Ok((org_id, message)) => {
+                        store_and_notify(&state, &notification_addr, org_id, message)
+                            .await
+                            .map_err(RecordError::Persistence)
+                    }
+                    Err(e) => Err(e),
                 };
-                let message = MessageBackend::from(message);
-                state
-                    .persist
-                    .lock()
-                    .await
-                    .add_message(&message_key, &message)
-                    .await
-                    .unwrap();
+                if let Err(e) = result {
+                    warn!(
+                        "moving record {}/{}/{} to {}: {e}",
+                        m.topic(),
+                        m.partition(),
+                        m.offset(),
+                        config.dead_letter_topic
+                    );
+                    dead_letter
+                        .send_to_dead_letter(m.key(), m.payload(), &e)
+                        .await;
+                }
+                if let Err(e) = consumer.commit_message(&m, CommitMode::Async) {
+                    warn!("failed committing offset {}: {e}", m.offset());
+                }
             }
         };
     }
 }
+
+/// Reasons for moving a consumed record to the dead-letter topic.
+#[derive(Debug, Error)]
+pub(crate) enum RecordError {
+    #[error("record without payload")]
+    MissingPayload,
+    #[error("undecodable payload: {0}")]
+    InvalidPayload(#[from] prost::DecodeError),
+    #[error("record without key")]
+    MissingKey,
+    #[error("key is not a valid organization id: {0}")]
+    InvalidKey(#[from] FromUtf8Error),
+    #[error("invalid message: {0}")]
+    InvalidMessage(#[from] InvalidMessage),
+    #[error("failed persisting message: {0}")]
+    Persistence(anyhow::Error),
+}
+
+/// Decodes a record produced by [`KafkaManager`]: the organization id as key and a
+/// [`ProtoMessageBackend`] as payload.
+fn decode_record(
+    key: Option<&[u8]>,
+    payload: Option<&[u8]>,
+) -> Result<(OrgID, MessageBackend), RecordError> {
+    let payload = payload.ok_or(RecordError::MissingPayload)?;
+    let message = ProtoMessageBackend::decode(payload)?;
+    let key = key
+        .filter(|key| !key.is_empty())
+        .ok_or(RecordError::MissingKey)?;
+    let org_id = OrgID(String::from_utf8(key.to_vec())?);
+    let message = MessageBackend::from(message).validated()?;
+    Ok((org_id, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(message: MessageBackend) -> Vec<u8> {
+        serialize_message(&message.into())
+    }
+
+    #[test]
+    fn test_decode_record() {
+        let message = MessageBackend {
+            hostname: "host".to_string(),
+            title: "title".to_string(),
+            ..Default::default()
+        };
+        let (org_id, decoded) =
+            decode_record(Some(b"user".as_slice()), Some(&payload(message))).unwrap();
+        assert_eq!(org_id, OrgID("user".to_string()));
+        assert_eq!(decoded.title, "title");
+        assert!(decoded.timestamp.is_some());
+    }
+
+    #[test]
+    fn test_decode_record_without_timestamp() {
+        let proto = ProtoMessageBackend {
+            hostname: "host".to_string(),
+            ..Default::default()
+        };
+        let (_, decoded) =
+            decode_record(Some(b"user".as_slice()), Some(&serialize_message(&proto))).unwrap();
+        assert!(decoded.timestamp.is_some());
+    }
+
+    #[test]
+    fn test_decode_record_failures() {
+        let valid = payload(MessageBackend {
+            hostname: "host".to_string(),
+            ..Default::default()
+        });
+        let key = Some(b"user".as_slice());
+
+        assert!(matches!(
+            decode_record(key, None),
+            Err(RecordError::MissingPayload)
+        ));
+        assert!(matches!(
+            decode_record(key, Some(&[0xff, 0xff, 0xff])),
+            Err(RecordError::InvalidPayload(_))
+        ));
+        assert!(matches!(
+            decode_record(None, Some(&valid)),
+            Err(RecordError::MissingKey)
+        ));
+        assert!(matches!(
+            decode_record(Some(b"".as_slice()), Some(&valid)),
+            Err(RecordError::MissingKey)
+        ));
+        assert!(matches!(
+            decode_record(Some(&[0xc3, 0x28]), Some(&valid)),
+            Err(RecordError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            decode_record(key, Some(&payload(MessageBackend::default()))),
+            Err(RecordError::InvalidMessage(InvalidMessage::EmptyHostname))
+        ));
+    }
+}
@@ -0,0 +1,434 @@
+use rdkafka::config::ClientConfig;
+use std::env;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+const DEFAULT_BROKERS: &str = "localhost:9092";
+const DEFAULT_TOPIC: &str = "messages-backend";
+const DEFAULT_DEAD_LETTER_TOPIC: &str = "messages-backend-dlq";
+const DEFAULT_GROUP_ID: &str = "snitch-backend";
+/// Messages the producer buffers before enqueueing fails with a full queue.
+pub(crate) const PRODUCER_QUEUE_CAPACITY: usize = 100_000;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum KafkaConfigError {
+    #[error("{0} must not be empty")]
+    Empty(&'static str),
+    #[error("{key}={value} is invalid: {reason}")]
+    Invalid {
+        key: &'static str,
+        value: String,
+        reason: String,
+    },
+    #[error("{0} is required for security protocol {1}")]
+    Missing(&'static str, SecurityProtocol),
+    #[error("{0} is only used with security protocol sasl_plaintext or sasl_ssl")]
+    Unused(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, derive_more::Display)]
+pub(crate) enum SecurityProtocol {
+    #[default]
+    #[display(fmt = "plaintext")]
+    Plaintext,
+    #[display(fmt = "ssl")]
+    Ssl,
+    #[display(fmt = "sasl_plaintext")]
+    SaslPlaintext,
+    #[display(fmt = "sasl_ssl")]
+    SaslSsl,
+}
+
+impl FromStr for SecurityProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plaintext" => Ok(SecurityProtocol::Plaintext),
+            "ssl" => Ok(SecurityProtocol::Ssl),
+            "sasl_plaintext" => Ok(SecurityProtocol::SaslPlaintext),
+            "sasl_ssl" => Ok(SecurityProtocol::SaslSsl),
+            _ => Err("expected plaintext, ssl, sasl_plaintext or sasl_ssl".to_string()),
+        }
+    }
+}
+
+impl SecurityProtocol {
+    fn uses_sasl(&self) -> bool {
+        matches!(
+            self,
+            SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub(crate) enum SaslMechanism {
+    #[display(fmt = "PLAIN")]
+    Plain,
+    #[display(fmt = "SCRAM-SHA-256")]
+    ScramSha256,
+    #[display(fmt = "SCRAM-SHA-512")]
+    ScramSha512,
+}
+
+impl FromStr for SaslMechanism {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "PLAIN" => Ok(SaslMechanism::Plain),
+            "SCRAM-SHA-256" => Ok(SaslMechanism::ScramSha256),
+            "SCRAM-SHA-512" => Ok(SaslMechanism::ScramSha512),
+            _ => Err("expected PLAIN, SCRAM-SHA-256 or SCRAM-SHA-512".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct SaslConfig {
+    pub mechanism: SaslMechanism,
+    pub username: String,
+    pub password: String,
+}
+
+/// Redacts the password, e.g. when the config is logged.
+impl fmt::Debug for SaslConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SaslConfig")
+            .field("mechanism", &self.mechanism)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Kafka settings, read from `SNITCH_KAFKA_*` environment variables. See the
+/// README for the full list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KafkaConfig {
+    pub brokers: String,
+    pub topic: String,
+    pub dead_letter_topic: String,
+    pub group_id: String,
+    pub group_remote_assignor: Option<String>,
+    pub security_protocol: SecurityProtocol,
+    pub sasl: Option<SaslConfig>,
+    pub ssl_ca_location: Option<String>,
+    pub ssl_certificate_location: Option<String>,
+    pub ssl_key_location: Option<String>,
+    pub producer_linger_ms: u32,
+    pub producer_message_timeout_ms: u32,
+    pub consumer_session_timeout_ms: u32,
+    pub consumer_auto_offset_reset: Option<String>,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        Self {
+            brokers: DEFAULT_BROKERS.to_string(),
+            topic: DEFAULT_TOPIC.to_string(),
+            dead_letter_topic: DEFAULT_DEAD_LETTER_TOPIC.to_string(),
+            group_id: DEFAULT_GROUP_ID.to_string(),
+            group_remote_assignor: None,
+            security_protocol: SecurityProtocol::default(),
+            sasl: None,
+            ssl_ca_location: None,
+            ssl_certificate_location: None,
+            ssl_key_location: None,
+            producer_linger_ms: 1000,
+            producer_message_timeout_ms: 5000,
+            consumer_session_timeout_ms: 6000,
+            consumer_auto_offset_reset: None,
+        }
+    }
+}
+
+impl KafkaConfig {
+    pub(crate) fn from_env() -> Result<Self, KafkaConfigError> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    /// Builds the config from `lookup`, falling back to the defaults for unset
+    /// variables, and validates it.
+    pub(crate) fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, KafkaConfigError> {
+        let defaults = KafkaConfig::default();
+        let string = |key: &'static str, default: String| -> Result<String, KafkaConfigError> {
+            match lookup(key) {
+                None => Ok(default),
+                Some(value) if value.trim().is_empty() => Err(KafkaConfigError::Empty(key)),
+                Some(value) => Ok(value.trim().to_string()),
+            }
+        };
+        let optional = |key: &'static str| lookup(key).filter(|value| !value.trim().is_empty());
+        let parse = |key: &'static str, default: u32| -> Result<u32, KafkaConfigError> {
+            optional(key).map_or(Ok(default), |value| {
+                value.trim().parse().map_err(|e: std::num::ParseIntError| {
+                    KafkaConfigError::Invalid {
+                        key,
+                        value,
+                        reason: e.to_string(),
+                    }
+                })
+            })
+        };
+
+        let security_protocol = match optional("SNITCH_KAFKA_SECURITY_PROTOCOL") {
+            None => defaults.security_protocol,
+            Some(value) => value
+                .trim()
+                .parse()
+                .map_err(|reason| KafkaConfigError::Invalid {
+                    key: "SNITCH_KAFKA_SECURITY_PROTOCOL",
+                    value,
+                    reason,
+                })?,
+        };
+
+        let sasl_mechanism = optional("SNITCH_KAFKA_SASL_MECHANISM");
+        let sasl_username = optional("SNITCH_KAFKA_SASL_USERNAME");
+        let sasl_password = optional("SNITCH_KAFKA_SASL_PASSWORD");
+        let sasl = if security_protocol.uses_sasl() {
+            let mechanism = sasl_mechanism.ok_or(KafkaConfigError::Missing(
+                "SNITCH_KAFKA_SASL_MECHANISM",
+                security_protocol,
+            ))?;
+            let mechanism =
+                mechanism
+                    .trim()
+                    .parse()
+                    .map_err(|reason| KafkaConfigError::Invalid {
+                        key: "SNITCH_KAFKA_SASL_MECHANISM",
+                        value: mechanism.clone(),
+                        reason,
+                    })?;
+            Some(SaslConfig {
+                mechanism,
+                username: sasl_username.ok_or(KafkaConfigError::Missing(
+                    "SNITCH_KAFKA_SASL_USERNAME",
+                    security_protocol,
+                ))?,
+                password: sasl_password.ok_or(KafkaConfigError::Missing(
+                    "SNITCH_KAFKA_SASL_PASSWORD",
+                    security_protocol,
+                ))?,
+            })
+        } else {
+            for (key, value) in [
+                ("SNITCH_KAFKA_SASL_MECHANISM", &sasl_mechanism),
+                ("SNITCH_KAFKA_SASL_USERNAME", &sasl_username),
+                ("SNITCH_KAFKA_SASL_PASSWORD", &sasl_password),
+            ] {
+                if value.is_some() {
+                    return Err(KafkaConfigError::Unused(key));
+                }
+            }
+            None
+        };
+
+        let existing_file = |key: &'static str| -> Result<Option<String>, KafkaConfigError> {
+            match optional(key) {
+                Some(path) if !Path::new(&path).is_file() => Err(KafkaConfigError::Invalid {
+                    key,
+                    value: path,
+                    reason: "no such file".to_string(),
+                }),
+                path => Ok(path),
+            }
+        };
+
+        let consumer_auto_offset_reset = match optional("SNITCH_KAFKA_AUTO_OFFSET_RESET") {
+            Some(value) if !matches!(value.as_str(), "earliest" | "latest") => {
+                return Err(KafkaConfigError::Invalid {
+                    key: "SNITCH_KAFKA_AUTO_OFFSET_RESET",
+                    value,
+                    reason: "expected earliest or latest".to_string(),
+                })
+            }
+            value => value,
+        };
+
+        Ok(Self {
+            brokers: string("SNITCH_KAFKA_BROKERS", defaults.brokers)?,
+            topic: string("SNITCH_KAFKA_TOPIC", defaults.topic)?,
+            dead_letter_topic: string(
+                "SNITCH_KAFKA_DEAD_LETTER_TOPIC",
+                defaults.dead_letter_topic,
+            )?,
+            group_id: string("SNITCH_KAFKA_GROUP_ID", defaults.group_id)?,
+            group_remote_assignor: optional("SNITCH_KAFKA_GROUP_REMOTE_ASSIGNOR"),
+            security_protocol,
+            sasl,
+            ssl_ca_location: existing_file("SNITCH_KAFKA_SSL_CA_LOCATION")?,
+            ssl_certificate_location: existing_file("SNITCH_KAFKA_SSL_CERTIFICATE_LOCATION")?,
+            ssl_key_location: existing_file("SNITCH_KAFKA_SSL_KEY_LOCATION")?,
+            producer_linger_ms: parse(
+                "SNITCH_KAFKA_PRODUCER_LINGER_MS",
+                defaults.producer_linger_ms,
+            )?,
+            producer_message_timeout_ms: parse(
+                "SNITCH_KAFKA_PRODUCER_MESSAGE_TIMEOUT_MS",
+                defaults.producer_message_timeout_ms,
+            )?,
+            consumer_session_timeout_ms: parse(
+                "SNITCH_KAFKA_CONSUMER_SESSION_TIMEOUT_MS",
+                defaults.consumer_session_timeout_ms,
+            )?,
+            consumer_auto_offset_reset,
+        })
+    }
+
+    /// Connection and security settings shared by producer and consumer.
+    fn client_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &self.brokers)
+            .set("security.protocol", self.security_protocol.to_string());
+        if let Some(sasl) = &self.sasl {
+            config
+                .set("sasl.mechanism", sasl.mechanism.to_string())
+                .set("sasl.username", &sasl.username)
+                .set("sasl.password", &sasl.password);
+        }
+        for (key, value) in [
+            ("ssl.ca.location", &self.ssl_ca_location),
+            ("ssl.certificate.location", &self.ssl_certificate_location),
+            ("ssl.key.location", &self.ssl_key_location),
+        ] {
+            if let Some(value) = value {
+                config.set(key, value);
+            }
+        }
+        config
+    }
+
+    pub(crate) fn producer_config(&self) -> ClientConfig {
+        let mut config = self.client_config();
+        config
+            .set(
+                "queue.buffering.max.ms",
+                self.producer_linger_ms.to_string(),
+            )
+            .set(
+                "message.timeout.ms",
+                self.producer_message_timeout_ms.to_string(),
+            )
+            .set(
+                "queue.buffering.max.messages",
+                PRODUCER_QUEUE_CAPACITY.to_string(),
+            );
+        config
+    }
+
+    pub(crate) fn consumer_config(&self) -> ClientConfig {
+        let mut config = self.client_config();
+        config
+            .set("group.id", &self.group_id)
+            .set("enable.partition.eof", "false")
+            .set(
+                "session.timeout.ms",
+                self.consumer_session_timeout_ms.to_string(),
+            )
+            .set("enable.auto.commit", "true");
+        if let Some(auto_offset_reset) = &self.consumer_auto_offset_reset {
+            config.set("auto.offset.reset", auto_offset_reset);
+        }
+        if let Some(assignor) = &self.group_remote_assignor {
+            config
+                .set("group.remote.assignor", assignor)
+                .set("group.protocol", "consumer")
+                .remove("session.timeout.ms");
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> Result<KafkaConfig, KafkaConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        KafkaConfig::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = config(&[]).unwrap();
+        assert_eq!(config, KafkaConfig::default());
+        assert_eq!(
+            config.producer_config().get("bootstrap.servers"),
+            Some(DEFAULT_BROKERS)
+        );
+        assert_eq!(
+            config.consumer_config().get("group.id"),
+            Some(DEFAULT_GROUP_ID)
+        );
+    }
+
+    #[test]
+    fn test_sasl_ssl() {
+        let config = config(&[
+            ("SNITCH_KAFKA_BROKERS", "kafka-1:9093,kafka-2:9093"),
+            ("SNITCH_KAFKA_SECURITY_PROTOCOL", "SASL_SSL"),
+            ("SNITCH_KAFKA_SASL_MECHANISM", "scram-sha-256"),
+            ("SNITCH_KAFKA_SASL_USERNAME", "snitch"),
+            ("SNITCH_KAFKA_SASL_PASSWORD", "secret"),
+            ("SNITCH_KAFKA_PRODUCER_LINGER_MS", "50"),
+        ])
+        .unwrap();
+        let producer = config.producer_config();
+        assert_eq!(producer.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(producer.get("sasl.mechanism"), Some("SCRAM-SHA-256"));
+        assert_eq!(producer.get("sasl.password"), Some("secret"));
+        assert_eq!(producer.get("queue.buffering.max.ms"), Some("50"));
+        assert_eq!(producer.get("queue.buffering.max.messages"), Some("100000"));
+
+        let debug = format!("{config:?}");
+        assert!(debug.contains("snitch"));
+        assert!(!debug.contains("secret"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            config(&[
+                ("SNITCH_KAFKA_SECURITY_PROTOCOL", "sasl_ssl"),
+                ("SNITCH_KAFKA_SASL_MECHANISM", "PLAIN"),
+                ("SNITCH_KAFKA_SASL_USERNAME", "snitch"),
+            ]),
+            Err(KafkaConfigError::Missing(
+                "SNITCH_KAFKA_SASL_PASSWORD",
+                SecurityProtocol::SaslSsl
+            ))
+        );
+        assert_eq!(
+            config(&[("SNITCH_KAFKA_SASL_USERNAME", "snitch")]),
+            Err(KafkaConfigError::Unused("SNITCH_KAFKA_SASL_USERNAME"))
+        );
+        assert_eq!(
+            config(&[("SNITCH_KAFKA_BROKERS", " ")]),
+            Err(KafkaConfigError::Empty("SNITCH_KAFKA_BROKERS"))
+        );
+        assert!(matches!(
+            config(&[("SNITCH_KAFKA_SECURITY_PROTOCOL", "tls")]),
+            Err(KafkaConfigError::Invalid { .. })
+        ));
+        assert!(matches!(
+            config(&[("SNITCH_KAFKA_PRODUCER_LINGER_MS", "soon")]),
+            Err(KafkaConfigError::Invalid { .. })
+        ));
+        assert!(matches!(
+            config(&[("SNITCH_KAFKA_SSL_CA_LOCATION", "/nonexistent/ca.pem")]),
+            Err(KafkaConfigError::Invalid { .. })
+        ));
+    }
+}
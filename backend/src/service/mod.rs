@@ -1,6 +1,11 @@
 pub mod authentication;
 pub mod email;
+pub(crate) mod ingestion;
 pub(crate) mod kafka;
+pub(crate) mod kafka_config;
 pub(crate) mod notification_dispatcher;
 pub(crate) mod notification_filter;
+pub(crate) mod notification_rules;
+pub(crate) mod session;
 pub mod token;
+pub(crate) mod totp;
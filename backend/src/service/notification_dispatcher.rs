@@ -1,6 +1,8 @@
+use crate::model::message::MessageBackend;
 use crate::persistence::redis::NotificationSettings;
 use actix::{Actor, Context, Handler, Message};
 use chatterbox::message::Dispatcher;
+use log::error;
 
 pub(crate) struct NotificationManager {}
 
@@ -15,12 +17,28 @@ impl NotificationManager {
         let dispatcher = Dispatcher::new(notification_settings.into());
         dispatcher.send_test_message().is_ok()
     }
+
+    pub(crate) fn notify(
+        &self,
+        notification_settings: NotificationSettings,
+        message: &MessageBackend,
+    ) -> bool {
+        let dispatcher = Dispatcher::new(notification_settings.into());
+        dispatcher
+            .dispatch(message)
+            .inspect_err(|e| error!("failed dispatching notification: {e}"))
+            .is_ok()
+    }
 }
 
 #[derive(Message, Clone)]
 #[rtype(result = "bool")]
 pub(crate) struct TryNotify(pub NotificationSettings);
 
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub(crate) struct Notify(pub NotificationSettings, pub MessageBackend);
+
 pub(crate) struct NotificationActor {
     pub(crate) notification_manager: NotificationManager,
 }
@@ -36,3 +54,11 @@ impl Handler<TryNotify> for NotificationActor {
         self.notification_manager.try_notify(msg.0)
     }
 }
+
+impl Handler<Notify> for NotificationActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Notify, _: &mut Context<Self>) -> Self::Result {
+        self.notification_manager.notify(msg.0, &msg.1)
+    }
+}
@@ -1,4 +1,4 @@
-use crate::model::user::UserID;
+use crate::model::organization::OrgID;
 use chrono::Utc;
 use std::collections::HashMap;
 
@@ -6,7 +6,7 @@ const DEAD_TIME: i64 = 30;
 
 #[derive(Debug)]
 pub(crate) struct NotificationFilter {
-    pub(crate) last_notifications: HashMap<UserID, i64>,
+    pub(crate) last_notifications: HashMap<OrgID, i64>,
 }
 
 impl NotificationFilter {
@@ -16,7 +16,7 @@ impl NotificationFilter {
         }
     }
 
-    pub(crate) async fn notify_user(&mut self, key: &UserID) -> bool {
+    pub(crate) async fn notify_org(&mut self, key: &OrgID) -> bool {
         self.cleanup().await;
         if self.last_notifications.contains_key(key) {
             return false;
@@ -42,4 +42,13 @@ mod tests {
         let mut handler = NotificationFilter::new();
         handler.cleanup().await;
     }
+
+    #[tokio::test]
+    async fn test_notify_org_rate_limited() {
+        let mut handler = NotificationFilter::new();
+        let org_id = OrgID::new();
+        assert!(handler.notify_org(&org_id).await);
+        assert!(!handler.notify_org(&org_id).await);
+        assert!(handler.notify_org(&OrgID::new()).await);
+    }
 }
@@ -0,0 +1,161 @@
+use crate::model::message::MessageBackend;
+use crate::model::notification_rule::{Channel, NotificationRule, RuleAction};
+use crate::persistence::redis::NotificationSettings;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// Outcome of matching a message against a user's notification rules.
+///
+/// A matching mute rule wins over any routing rule. Without any matching rule
+/// all configured channels are notified.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct RuleEvaluation {
+    pub matched_rules: Vec<NotificationRule>,
+    pub muted: bool,
+    /// `None` if no routing rule matched and all channels are used.
+    pub channels: Option<BTreeSet<Channel>>,
+}
+
+pub(crate) fn evaluate(rules: &[NotificationRule], message: &MessageBackend) -> RuleEvaluation {
+    let mut evaluation = RuleEvaluation::default();
+    for rule in rules.iter().filter(|rule| rule.matches(message)) {
+        match &rule.action {
+            RuleAction::Mute => evaluation.muted = true,
+            RuleAction::Route { channels } => evaluation
+                .channels
+                .get_or_insert_with(BTreeSet::new)
+                .extend(channels),
+        }
+        evaluation.matched_rules.push(rule.clone());
+    }
+    evaluation
+}
+
+impl RuleEvaluation {
+    /// Returns the settings to notify with, or `None` if the message is muted.
+    pub(crate) fn apply(
+        &self,
+        notification_settings: NotificationSettings,
+    ) -> Option<NotificationSettings> {
+        if self.muted {
+            return None;
+        }
+        match &self.channels {
+            None => Some(notification_settings),
+            Some(channels) => Some(notification_settings.restrict_to(channels)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::message::Severity;
+    use crate::model::notification_rule::RuleConditions;
+    use std::collections::BTreeMap;
+
+    fn rule(name: &str, conditions: RuleConditions, action: RuleAction) -> NotificationRule {
+        NotificationRule {
+            rule_id: NotificationRule::new_rule_id(),
+            name: name.to_string(),
+            conditions,
+            action,
+        }
+    }
+
+    fn message(hostname: &str, title: &str, severity: Option<Severity>) -> MessageBackend {
+        MessageBackend {
+            hostname: hostname.to_string(),
+            title: title.to_string(),
+            severity,
+            labels: BTreeMap::from([("env".to_string(), "prod".to_string())]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_conditions() {
+        let conditions = RuleConditions {
+            hostname: Some("web-*".to_string()),
+            title: Some("(?i)^disk".to_string()),
+            severity: Some(Severity::Warning),
+            labels: BTreeMap::from([("env".to_string(), "prod".to_string())]),
+            ..Default::default()
+        };
+        let rule = rule("disk", conditions, RuleAction::Mute);
+        rule.validate().unwrap();
+
+        assert!(rule.matches(&message("web-1", "Disk full", Some(Severity::Critical))));
+        assert!(!rule.matches(&message("db-1", "Disk full", Some(Severity::Critical))));
+        assert!(!rule.matches(&message("web-1", "cpu", Some(Severity::Critical))));
+        assert!(!rule.matches(&message("web-1", "disk", Some(Severity::Info))));
+        assert!(!rule.matches(&message("web-1", "disk", None)));
+
+        let mut staging = message("web-1", "disk", Some(Severity::Critical));
+        staging
+            .labels
+            .insert("env".to_string(), "staging".to_string());
+        assert!(!rule.matches(&staging));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        let conditions = RuleConditions {
+            title: Some("(".to_string()),
+            ..Default::default()
+        };
+        let invalid = rule("invalid", conditions, RuleAction::Mute);
+        assert!(invalid.validate().is_err());
+        assert!(!invalid.matches(&message("web-1", "(", None)));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let rules = vec![
+            rule(
+                "critical to telegram",
+                RuleConditions {
+                    severity: Some(Severity::Critical),
+                    ..Default::default()
+                },
+                RuleAction::Route {
+                    channels: vec![Channel::Telegram],
+                },
+            ),
+            rule(
+                "web to slack",
+                RuleConditions {
+                    hostname: Some("web-*".to_string()),
+                    ..Default::default()
+                },
+                RuleAction::Route {
+                    channels: vec![Channel::Slack],
+                },
+            ),
+            rule(
+                "mute backups",
+                RuleConditions {
+                    title: Some("backup".to_string()),
+                    ..Default::default()
+                },
+                RuleAction::Mute,
+            ),
+        ];
+
+        let evaluation = evaluate(&rules, &message("db-1", "cpu", None));
+        assert!(evaluation.matched_rules.is_empty());
+        assert_eq!(evaluation.channels, None);
+
+        let evaluation = evaluate(&rules, &message("web-1", "cpu", Some(Severity::Critical)));
+        assert_eq!(evaluation.matched_rules.len(), 2);
+        assert_eq!(
+            evaluation.channels,
+            Some(BTreeSet::from([Channel::Telegram, Channel::Slack]))
+        );
+        assert!(!evaluation.muted);
+
+        let evaluation = evaluate(&rules, &message("web-1", "backup done", None));
+        assert!(evaluation.muted);
+        assert!(evaluation.apply(NotificationSettings::default()).is_none());
+    }
+}
@@ -0,0 +1,127 @@
+use crate::persistence::redis::redis_url;
+use actix_session::storage::{
+    CookieSessionStore, LoadError, RedisSessionStore, SaveError, SessionKey, SessionStore,
+    UpdateError,
+};
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::Key;
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::env;
+
+/// Where session state is kept, selected at startup via `SNITCH_SESSION_STORE`.
+/// `cookie` keeps it in the signed cookie, `redis` only stores the session key in
+/// the cookie so that sessions are shared between replicas.
+#[derive(Clone)]
+pub(crate) enum SnitchSessionStore {
+    Cookie,
+    Redis(Box<RedisSessionStore>),
+}
+
+impl SnitchSessionStore {
+    pub(crate) async fn from_env() -> Result<Self> {
+        let store = env::var("SNITCH_SESSION_STORE").unwrap_or("cookie".to_string());
+        info!("using {store} session store");
+        match store.as_str() {
+            "cookie" => Ok(SnitchSessionStore::Cookie),
+            "redis" => Ok(SnitchSessionStore::Redis(Box::new(
+                RedisSessionStore::new(redis_url()).await?,
+            ))),
+            _ => bail!("unknown SNITCH_SESSION_STORE: {store}"),
+        }
+    }
+}
+
+impl SessionStore for SnitchSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        match self {
+            SnitchSessionStore::Cookie => CookieSessionStore::default().load(session_key).await,
+            SnitchSessionStore::Redis(store) => store.load(session_key).await,
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            SnitchSessionStore::Cookie => {
+                CookieSessionStore::default().save(session_state, ttl).await
+            }
+            SnitchSessionStore::Redis(store) => store.save(session_state, ttl).await,
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            SnitchSessionStore::Cookie => {
+                CookieSessionStore::default()
+                    .update(session_key, session_state, ttl)
+                    .await
+            }
+            SnitchSessionStore::Redis(store) => store.update(session_key, session_state, ttl).await,
+        }
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        match self {
+            SnitchSessionStore::Cookie => {
+                CookieSessionStore::default()
+                    .update_ttl(session_key, ttl)
+                    .await
+            }
+            SnitchSessionStore::Redis(store) => store.update_ttl(session_key, ttl).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        match self {
+            SnitchSessionStore::Cookie => CookieSessionStore::default().delete(session_key).await,
+            SnitchSessionStore::Redis(store) => store.delete(session_key).await,
+        }
+    }
+}
+
+/// The key signing the session cookie, from `SNITCH_SESSION_KEY`.
+pub(crate) fn session_key() -> Result<Key> {
+    session_key_from(env::var("SNITCH_SESSION_KEY").ok())
+}
+
+/// Without a configured key, a random one is used and sessions end on restart.
+fn session_key_from(value: Option<String>) -> Result<Key> {
+    match value {
+        Some(value) => Key::try_from(value.as_bytes())
+            .map_err(|e| anyhow!("invalid SNITCH_SESSION_KEY, at least 64 bytes needed: {e}")),
+        None => {
+            warn!("SNITCH_SESSION_KEY not defined, sessions end on restart");
+            Ok(Key::generate())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_key() {
+        let value = "k".repeat(64);
+        let key = session_key_from(Some(value.clone())).unwrap();
+        assert_eq!(
+            key.master(),
+            session_key_from(Some(value)).unwrap().master()
+        );
+        assert!(session_key_from(Some("short".to_string())).is_err());
+        assert!(session_key_from(None).is_ok());
+    }
+}
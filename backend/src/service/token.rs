@@ -1,5 +1,51 @@
+use crate::model::message::MessageToken;
+use crate::model::organization::OrgID;
+use crate::model::token::{TokenHash, TokenInfo, TokenScope};
+use crate::persistence::token::TokenStore;
+use crate::persistence::Persistence;
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use log::{error, info};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::env;
+use thiserror::Error;
+
+lazy_static! {
+    /// Key of the token hashes. Changing it invalidates all tokens.
+    static ref TOKEN_PEPPER: String =
+        env::var("SNITCH_TOKEN_PEPPER").expect("SNITCH_TOKEN_PEPPER not defined");
+}
+
+/// Fails unless `SNITCH_TOKEN_PEPPER` is set, checked at startup so that a
+/// missing pepper doesn't panic on the first request with a token.
+pub(crate) fn check_token_pepper() -> anyhow::Result<()> {
+    match env::var("SNITCH_TOKEN_PEPPER") {
+        Ok(pepper) if !pepper.is_empty() => Ok(()),
+        _ => anyhow::bail!("SNITCH_TOKEN_PEPPER not defined"),
+    }
+}
+
+/// The last use of a token is only recorded again after this many seconds,
+/// not for every request.
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60;
+
+#[derive(Debug, Error)]
+pub(crate) enum TokenError {
+    #[error("unknown token")]
+    Unknown,
+    #[error("expired token")]
+    Expired,
+    #[error("token lacks the {0} scope")]
+    MissingScope(TokenScope),
+    #[error("token not allowed for host {0}")]
+    Hostname(String),
+    #[error(transparent)]
+    Persistence(#[from] anyhow::Error),
+}
 
 pub fn random_alphanumeric_string(length: u32) -> String {
     let mut rng = rand::thread_rng();
@@ -8,3 +54,99 @@ pub fn random_alphanumeric_string(length: u32) -> String {
         .collect();
     token
 }
+
+/// HMAC-SHA256 of the token with [`TOKEN_PEPPER`], hex encoded. Without the
+/// pepper the stored hashes can't be used to find or forge tokens.
+pub(crate) fn hash_token(token: &str) -> TokenHash {
+    let mut mac = Hmac::<Sha256>::new_from_slice(TOKEN_PEPPER.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(token.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Looks up a token for a request that needs `scope` and records its use.
+pub(crate) async fn authorize_token(
+    store: &mut Persistence,
+    token: &MessageToken,
+    scope: TokenScope,
+) -> Result<TokenInfo, TokenError> {
+    let info = store
+        .get_token_info(&hash_token(token))
+        .await?
+        .ok_or(TokenError::Unknown)?;
+    let now = Utc::now();
+    if info.is_expired(now) {
+        info!("expired token {} of {}", info.token_id, info.org_id);
+        return Err(TokenError::Expired);
+    }
+    if !info.has_scope(scope) {
+        return Err(TokenError::MissingScope(scope));
+    }
+    let recently_used = info.last_used_at.is_some_and(|last_used_at| {
+        now - last_used_at < Duration::seconds(LAST_USED_RESOLUTION_SECONDS)
+    });
+    // the token was valid, failing to record its use doesn't fail the request
+    if !recently_used {
+        if let Err(e) = store.set_token_last_used(&info.hash, now).await {
+            error!("{e}");
+        }
+    }
+    Ok(info)
+}
+
+/// Fails if the token may not be used for messages of `hostname`.
+pub(crate) fn check_hostname(info: &TokenInfo, hostname: &str) -> Result<(), TokenError> {
+    if info.allows_hostname(hostname) {
+        Ok(())
+    } else {
+        Err(TokenError::Hostname(hostname.to_string()))
+    }
+}
+
+/// Records the hosts messages were sent for with a token, to tell which hosts
+/// still use it after rotating it. Failing to do so doesn't fail the request.
+pub(crate) async fn record_token_hosts<'a>(
+    store: &mut Persistence,
+    token: &TokenInfo,
+    hostnames: impl IntoIterator<Item = &'a str>,
+) {
+    let now = Utc::now();
+    for hostname in hostnames.into_iter().collect::<BTreeSet<_>>() {
+        if let Err(e) = store.set_token_host_used(&token.hash, hostname, now).await {
+            error!("{e}");
+        }
+    }
+}
+
+/// All tokens of an organization with their metadata.
+pub(crate) async fn tokens_of_org(
+    store: &mut Persistence,
+    org_id: &OrgID,
+) -> anyhow::Result<Vec<TokenInfo>> {
+    let mut tokens = Vec::new();
+    for hash in store.get_tokens_of_org(org_id).await? {
+        if let Some(info) = store.get_token_info(&hash).await? {
+            tokens.push(info);
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_token() {
+        let token = random_alphanumeric_string(32);
+        let hash = hash_token(&token);
+        assert_eq!(hash.len(), 64);
+        assert!(!hash.contains(&token));
+        assert_eq!(hash, hash_token(&token));
+        assert_ne!(hash, hash_token(&random_alphanumeric_string(32)));
+    }
+}
@@ -0,0 +1,172 @@
+//! Time-based one-time passwords (RFC 6238) as generated by authenticator apps.
+
+use anyhow::Result;
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use reqwest::Url;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::service::authentication::constant_time_eq;
+use crate::service::token::random_alphanumeric_string;
+
+const ALPHABET: Alphabet = Alphabet::Rfc4648 { padding: false };
+const ISSUER: &str = "Snitch";
+const SECRET_LENGTH: usize = 20;
+const TIME_STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// Codes of the neighbouring time steps are accepted as well, for clock drift.
+const ALLOWED_DRIFT: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LENGTH: u32 = 12;
+
+/// A random base32 encoded secret of 160 bits, as recommended by RFC 4226.
+pub(crate) fn generate_secret() -> String {
+    let secret: [u8; SECRET_LENGTH] = rand::random();
+    base32::encode(ALPHABET, &secret)
+}
+
+pub(crate) fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| random_alphanumeric_string(RECOVERY_CODE_LENGTH))
+        .collect()
+}
+
+/// SHA-256 of a recovery code, hex encoded. Recovery codes are random with
+/// enough entropy that a fast hash can't be brute forced, unlike passwords.
+pub(crate) fn hash_recovery_code(code: &str) -> String {
+    Sha256::digest(code.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The index of the hash of `code` in `hashes`, if any.
+pub(crate) fn find_recovery_code(hashes: &[String], code: &str) -> Option<usize> {
+    let hash = hash_recovery_code(code);
+    hashes
+        .iter()
+        .position(|candidate| constant_time_eq(candidate.as_bytes(), hash.as_bytes()))
+}
+
+/// The `otpauth://` URI that authenticator apps import, usually scanned as QR code.
+pub(crate) fn provisioning_uri(secret: &str, account: &str) -> String {
+    let mut uri = Url::parse("otpauth://totp").expect("valid base uri");
+    uri.set_path(&format!("/{ISSUER}:{account}"));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", ISSUER)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &TIME_STEP.to_string());
+    uri.to_string()
+}
+
+/// Renders the provisioning URI as SVG image.
+pub(crate) fn qr_code(uri: &str) -> Result<String> {
+    Ok(QrCode::new(uri)?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+
+fn hotp(key: &[u8], counter: u64, digits: u32) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        code % 10u32.pow(digits),
+        width = digits as usize
+    )
+}
+
+#[cfg(test)]
+fn totp(key: &[u8], unix_time: u64, digits: u32) -> String {
+    hotp(key, unix_time / TIME_STEP, digits)
+}
+
+/// Returns the time step of `code` if it is valid at `unix_time` and newer than
+/// `last_time_step`, so that a code can't be replayed.
+pub(crate) fn verify(
+    secret: &str,
+    code: &str,
+    unix_time: u64,
+    last_time_step: Option<u64>,
+) -> Option<u64> {
+    let key = base32::decode(ALPHABET, secret)?;
+    let current = unix_time / TIME_STEP;
+    (current.saturating_sub(ALLOWED_DRIFT)..=current + ALLOWED_DRIFT)
+        .filter(|step| last_time_step.is_none_or(|last| *step > last))
+        .find(|step| constant_time_eq(hotp(&key, *step, DIGITS).as_bytes(), code.as_bytes()))
+}
+
+/// The code an authenticator app shows at `unix_time`.
+#[cfg(test)]
+pub(crate) fn code_at(secret: &str, unix_time: u64) -> String {
+    totp(
+        &base32::decode(ALPHABET, secret).unwrap(),
+        unix_time,
+        DIGITS,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let key = b"12345678901234567890";
+        for (time, code) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ] {
+            assert_eq!(totp(key, time, 8), code, "at {time}");
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let secret = generate_secret();
+        let now = 1111111111;
+        let code = code_at(&secret, now);
+        let step = verify(&secret, &code, now, None).unwrap();
+        assert_eq!(step, now / TIME_STEP);
+        assert_eq!(verify(&secret, &code, now + TIME_STEP, None), Some(step));
+        assert_eq!(verify(&secret, &code, now + 3 * TIME_STEP, None), None);
+        assert_eq!(verify(&secret, &code, now, Some(step)), None);
+        assert_eq!(verify(&secret, "000000x", now, None), None);
+    }
+
+    #[test]
+    fn test_find_recovery_code() {
+        let codes = generate_recovery_codes();
+        let hashes: Vec<_> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+        assert_eq!(find_recovery_code(&hashes, &codes[3]), Some(3));
+        assert_eq!(find_recovery_code(&hashes, "wrong"), None);
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        let uri = provisioning_uri("SECRET", "x.x@x.x");
+        assert_eq!(
+            uri,
+            "otpauth://totp/Snitch:x.x@x.x?secret=SECRET&issuer=Snitch&algorithm=SHA1&digits=6&period=30"
+        );
+        assert!(qr_code(&uri).unwrap().starts_with("<?xml"));
+    }
+}